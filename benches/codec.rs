@@ -0,0 +1,117 @@
+//! Encode/decode throughput of `SerialCodec`.
+//!
+//! Run with `cargo bench --bench codec`. Each size is measured with a plain payload and with a
+//! payload made entirely of sentinel bytes, which doubles its size on the wire.
+
+use bytes::{BufMut, BytesMut};
+use crc::Crc;
+use criterion::{
+    black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput,
+};
+use stn_updater::codec::{RequestFrame, SerialCodec};
+use tokio_util::codec::{Decoder, Encoder};
+
+const ENCODE_SIZES: [usize; 3] = [16, 256, 1024];
+
+// Responses carry a single length byte, so they top out at 255 bytes of data.
+const DECODE_SIZES: [usize; 3] = [16, 128, 255];
+
+fn payload(size: usize, stuffed: bool) -> Vec<u8> {
+    if stuffed {
+        vec![SerialCodec::STX; size]
+    } else {
+        (0..size).map(|idx| 0x10 + (idx % 0x40) as u8).collect()
+    }
+}
+
+fn response_bytes(data: &[u8]) -> Vec<u8> {
+    let crc = Crc::<u16>::new(&crc::CRC_16_XMODEM);
+
+    let mut payload = vec![0x71, data.len() as u8];
+    payload.extend_from_slice(data);
+    payload.extend_from_slice(&crc.checksum(&payload).to_be_bytes());
+
+    let mut bytes = vec![SerialCodec::STX, SerialCodec::STX];
+    for byte in payload {
+        if let SerialCodec::STX | SerialCodec::ETX | SerialCodec::DLE = byte {
+            bytes.push(SerialCodec::DLE);
+        }
+        bytes.push(byte);
+    }
+    bytes.push(SerialCodec::ETX);
+    bytes
+}
+
+fn encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode");
+    let mut codec = SerialCodec::new();
+
+    for size in ENCODE_SIZES {
+        for (name, stuffed) in [("plain", false), ("stuffed", true)] {
+            let data = payload(size, stuffed);
+            group.throughput(Throughput::Bytes(size as u64));
+            group.bench_with_input(BenchmarkId::new(name, size), &data, |b, data| {
+                b.iter_batched(
+                    || RequestFrame::new(0x31, data.clone()),
+                    |frame| {
+                        let mut dst = BytesMut::with_capacity(2 * size + 16);
+                        codec.encode(frame, &mut dst).unwrap();
+                        dst
+                    },
+                    BatchSize::SmallInput,
+                )
+            });
+        }
+    }
+
+    group.finish();
+}
+
+fn decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode");
+    let mut codec = SerialCodec::new();
+
+    for size in DECODE_SIZES {
+        for (name, stuffed) in [("plain", false), ("stuffed", true)] {
+            let bytes = response_bytes(&payload(size, stuffed));
+            group.throughput(Throughput::Bytes(size as u64));
+            group.bench_with_input(BenchmarkId::new(name, size), &bytes, |b, bytes| {
+                b.iter(|| {
+                    let mut src = BytesMut::from(&bytes[..]);
+                    codec.decode(&mut src).unwrap().unwrap()
+                })
+            });
+        }
+    }
+
+    group.finish();
+}
+
+fn decode_incremental(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode_incremental");
+    let mut codec = SerialCodec::new();
+
+    for size in DECODE_SIZES {
+        for (name, stuffed) in [("plain", false), ("stuffed", true)] {
+            let bytes = response_bytes(&payload(size, stuffed));
+            group.throughput(Throughput::Bytes(size as u64));
+            group.bench_with_input(BenchmarkId::new(name, size), &bytes, |b, bytes| {
+                b.iter(|| {
+                    let mut src = BytesMut::with_capacity(bytes.len());
+                    for byte in bytes {
+                        src.put_u8(*byte);
+                        if let Some(frame) = codec.decode(&mut src).unwrap() {
+                            return black_box(frame);
+                        }
+                    }
+                    unreachable!()
+                })
+            });
+        }
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, encode, decode, decode_incremental);
+criterion_main!(benches);
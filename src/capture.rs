@@ -0,0 +1,305 @@
+use crate::codec::{RequestFrame, ResponseFrame};
+use crate::error::Error;
+use crate::protocol::opcode_name;
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Write};
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Sees every frame an `Updater` sends and receives, in order.
+pub trait Recorder: Send {
+    fn on_tx(&mut self, frame: &RequestFrame);
+    fn on_rx(&mut self, frame: &ResponseFrame);
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CaptureEntry {
+    Tx(RequestFrame),
+    Rx(ResponseFrame),
+}
+
+/// Records a session to a file that `load_capture` reads back.
+///
+/// Recording must never get in the way of flashing, so after the first write error nothing more
+/// is recorded and the error is kept for `error`.
+pub struct FileRecorder {
+    writer: BufWriter<File>,
+    error: Option<std::io::Error>,
+}
+
+impl FileRecorder {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<FileRecorder, Error> {
+        Ok(FileRecorder {
+            writer: BufWriter::new(File::create(path)?),
+            error: None,
+        })
+    }
+
+    /// The write error that stopped recording, if any.
+    pub fn error(&self) -> Option<&std::io::Error> {
+        self.error.as_ref()
+    }
+
+    fn record(&mut self, entry: &CaptureEntry) {
+        if self.error.is_some() {
+            return;
+        }
+        // Flushed per frame so a capture survives the process dying mid-session
+        let result = bincode::serialize_into(&mut self.writer, entry)
+            .map_err(|err| match *err {
+                bincode::ErrorKind::Io(err) => err,
+                err => std::io::Error::other(err),
+            })
+            .and_then(|()| self.writer.flush());
+        if let Err(err) = result {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(error = %err, "Capture stopped");
+            self.error = Some(err);
+        }
+    }
+}
+
+impl Recorder for FileRecorder {
+    fn on_tx(&mut self, frame: &RequestFrame) {
+        self.record(&CaptureEntry::Tx(frame.clone()));
+    }
+
+    fn on_rx(&mut self, frame: &ResponseFrame) {
+        self.record(&CaptureEntry::Rx(frame.clone()));
+    }
+}
+
+/// Reads a capture written by `FileRecorder`.
+pub fn load_capture<P: AsRef<Path>>(path: P) -> Result<Vec<CaptureEntry>, Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut entries = vec![];
+    loop {
+        match bincode::deserialize_from(&mut reader) {
+            Ok(entry) => entries.push(entry),
+            Err(err) => match *err {
+                bincode::ErrorKind::Io(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                    return Ok(entries)
+                }
+                err => return Err(Box::new(err).into()),
+            },
+        }
+    }
+}
+
+/// A frame as a line of a `JsonRecorder` trace.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TraceEntry {
+    /// Time since the recorder was created.
+    pub elapsed_us: u64,
+    pub direction: Direction,
+    pub command: u8,
+    /// From `opcode_name`, `None` for opcodes it doesn't know.
+    pub name: Option<String>,
+    /// Only set for responses.
+    pub ack: Option<bool>,
+    /// Lowercase hex, two digits per byte.
+    pub data: String,
+}
+
+/// Records a session as newline delimited JSON, one `TraceEntry` per frame, for reading and
+/// diffing rather than replaying. Write errors stop recording as they do for `FileRecorder`.
+pub struct JsonRecorder<W: Write + Send> {
+    writer: W,
+    started: Instant,
+    error: Option<std::io::Error>,
+}
+
+impl JsonRecorder<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Ok(JsonRecorder::new(BufWriter::new(File::create(path)?)))
+    }
+}
+
+impl<W: Write + Send> JsonRecorder<W> {
+    pub fn new(writer: W) -> JsonRecorder<W> {
+        JsonRecorder {
+            writer,
+            started: Instant::now(),
+            error: None,
+        }
+    }
+
+    /// The write error that stopped recording, if any.
+    pub fn error(&self) -> Option<&std::io::Error> {
+        self.error.as_ref()
+    }
+
+    fn record(&mut self, direction: Direction, command: u8, ack: Option<bool>, data: &[u8]) {
+        if self.error.is_some() {
+            return;
+        }
+        let entry = TraceEntry {
+            elapsed_us: self.started.elapsed().as_micros() as u64,
+            direction,
+            command,
+            name: opcode_name(command).map(str::to_string),
+            ack,
+            data: data.iter().map(|byte| format!("{:02x}", byte)).collect(),
+        };
+        let result = serde_json::to_writer(&mut self.writer, &entry)
+            .map_err(std::io::Error::from)
+            .and_then(|()| self.writer.write_all(b"\n"))
+            .and_then(|()| self.writer.flush());
+        if let Err(err) = result {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(error = %err, "Trace stopped");
+            self.error = Some(err);
+        }
+    }
+}
+
+impl<W: Write + Send> Recorder for JsonRecorder<W> {
+    fn on_tx(&mut self, frame: &RequestFrame) {
+        self.record(Direction::Tx, frame.command, None, &frame.data);
+    }
+
+    fn on_rx(&mut self, frame: &ResponseFrame) {
+        self.record(Direction::Rx, frame.command, Some(frame.ack), &frame.data);
+    }
+}
+
+/// Reads a trace written by `JsonRecorder`.
+pub fn load_trace<P: AsRef<Path>>(path: P) -> Result<Vec<TraceEntry>, Error> {
+    let mut entries = vec![];
+    for line in BufReader::new(File::open(path)?).lines() {
+        entries.push(serde_json::from_str(&line?).map_err(std::io::Error::from)?);
+    }
+    Ok(entries)
+}
+
+/// Plays the device side of a capture back, for driving a mock transport.
+pub struct Replay {
+    entries: VecDeque<CaptureEntry>,
+}
+
+impl Replay {
+    pub fn new(entries: Vec<CaptureEntry>) -> Replay {
+        Replay {
+            entries: entries.into(),
+        }
+    }
+
+    /// The responses recorded after `request`, which has to be the next frame sent in the
+    /// capture.
+    pub fn respond(&mut self, request: &RequestFrame) -> Result<Vec<ResponseFrame>, Error> {
+        match self.entries.pop_front() {
+            Some(CaptureEntry::Tx(expected)) if expected == *request => {}
+            Some(entry) => {
+                return Err(
+                    anyhow!("Replay diverged, expected {:?}, got {:?}", entry, request).into(),
+                )
+            }
+            None => return Err(anyhow!("Replay exhausted, got {:?}", request).into()),
+        }
+
+        let mut responses = vec![];
+        while let Some(CaptureEntry::Rx(response)) = self.entries.front() {
+            responses.push(response.clone());
+            self.entries.pop_front();
+        }
+        Ok(responses)
+    }
+
+    /// Whether every recorded frame was played back.
+    pub fn is_finished(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Which way bytes seen by a `WireTap`, or a frame in a trace, went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Tx,
+    Rx,
+}
+
+/// Wraps a transport, handing every byte written to and read from it to `tap` exactly as it goes
+/// over the wire, stuffing included. Below the frames a `Recorder` sees, for lining a session up
+/// with a logic analyzer capture.
+pub struct WireTap<T, F> {
+    inner: T,
+    tap: F,
+}
+
+impl<T, F> WireTap<T, F>
+where
+    F: FnMut(Direction, &[u8]),
+{
+    pub fn new(inner: T, tap: F) -> WireTap<T, F> {
+        WireTap { inner, tap }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T, F> AsyncRead for WireTap<T, F>
+where
+    T: AsyncRead + Unpin,
+    F: FnMut(Direction, &[u8]) + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if buf.filled().len() > filled {
+            (this.tap)(Direction::Rx, &buf.filled()[filled..]);
+        }
+        poll
+    }
+}
+
+impl<T, F> AsyncWrite for WireTap<T, F>
+where
+    T: AsyncWrite + Unpin,
+    F: FnMut(Direction, &[u8]) + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            if written > 0 {
+                (this.tap)(Direction::Tx, &buf[..written]);
+            }
+        }
+        poll
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
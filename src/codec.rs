@@ -2,7 +2,10 @@ use crate::error::Error;
 
 use bytes::{Buf, BufMut, BytesMut};
 use crc::Crc;
-use tokio_util::codec::{Decoder, Encoder};
+use futures::Stream;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncRead;
+use tokio_util::codec::{Decoder, Encoder, FramedRead};
 
 // #[derive(Debug)]
 // pub enum Error {
@@ -39,7 +42,7 @@ use tokio_util::codec::{Decoder, Encoder};
 //     }
 // }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct RequestFrame {
     pub command: u8,
     pub data: Vec<u8>,
@@ -51,21 +54,79 @@ impl RequestFrame {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+/// The command byte of a response is laid out as:
+///
+/// | Bit | Meaning                                      |
+/// |-----|----------------------------------------------|
+/// | 7   | `extended`, unused by the current bootloader |
+/// | 6   | `ack`                                        |
+/// | 5-0 | `command`, the opcode of the request         |
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ResponseFrame {
     pub ack: bool,
+    pub extended: bool,
     pub command: u8,
     pub data: Vec<u8>,
 }
 
 impl ResponseFrame {
+    pub const ACK: u8 = 0x40;
+    pub const EXTENDED: u8 = 0x80;
+    pub const COMMAND_MASK: u8 = 0x3F;
+
     pub const fn new(ack: bool, command: u8, data: Vec<u8>) -> ResponseFrame {
-        ResponseFrame { ack, command, data }
+        ResponseFrame {
+            ack,
+            extended: false,
+            command,
+            data,
+        }
+    }
+
+    /// Splits a command byte as received into its fields.
+    pub const fn from_command_byte(command_byte: u8, data: Vec<u8>) -> ResponseFrame {
+        ResponseFrame {
+            ack: command_byte & ResponseFrame::ACK != 0,
+            extended: command_byte & ResponseFrame::EXTENDED != 0,
+            command: command_byte & ResponseFrame::COMMAND_MASK,
+            data,
+        }
     }
+
+    /// The command byte as sent on the wire.
+    pub const fn command_byte(&self) -> u8 {
+        let mut command_byte = self.command & ResponseFrame::COMMAND_MASK;
+        if self.ack {
+            command_byte |= ResponseFrame::ACK;
+        }
+        if self.extended {
+            command_byte |= ResponseFrame::EXTENDED;
+        }
+        command_byte
+    }
+}
+
+/// Progress through the frame at the front of the buffer, kept across `decode` calls so every
+/// byte is only un-stuffed and checksummed once.
+#[derive(Default)]
+struct PartialFrame {
+    /// Bytes of the buffer already scanned, including the leading STX pair.
+    scanned: usize,
+    data: Vec<u8>,
+    crc: u16,
+    skip: bool,
 }
 
 pub struct SerialCodec {
     crc: Crc<u16>,
+    partial: PartialFrame,
+    stx: u8,
+    etx: u8,
+    dle: u8,
+    runt_frame_errors: bool,
+    crc_errors: usize,
+    resyncs: usize,
+    discarded_bytes: usize,
 }
 
 impl SerialCodec {
@@ -74,120 +135,329 @@ impl SerialCodec {
     pub const DLE: u8 = 0x05;
 
     pub const fn new() -> SerialCodec {
+        SerialCodec::with_sentinels(SerialCodec::STX, SerialCodec::ETX, SerialCodec::DLE)
+    }
+
+    pub const fn with_sentinels(stx: u8, etx: u8, dle: u8) -> SerialCodec {
         SerialCodec {
             crc: Crc::<u16>::new(&crc::CRC_16_XMODEM),
+            partial: PartialFrame {
+                scanned: 0,
+                data: vec![],
+                crc: 0,
+                skip: false,
+            },
+            stx,
+            etx,
+            dle,
+            runt_frame_errors: false,
+            crc_errors: 0,
+            resyncs: 0,
+            discarded_bytes: 0,
         }
     }
 
-    fn byte_stuff(data: u8, dst: &mut BytesMut) {
-        if let SerialCodec::STX | SerialCodec::ETX | SerialCodec::DLE = data {
-            dst.put_u8(SerialCodec::DLE);
+    /// Fail with `Error::BadFrame` on an ETX too early for a command, length and CRC, instead of
+    /// resyncing past it as line noise.
+    pub const fn with_runt_frame_errors(mut self) -> SerialCodec {
+        self.runt_frame_errors = true;
+        self
+    }
+
+    /// Frames dropped because their CRC didn't match, over the life of the codec.
+    pub const fn crc_errors(&self) -> usize {
+        self.crc_errors
+    }
+
+    /// Times the decoder had to realign on the next frame start.
+    pub const fn resyncs(&self) -> usize {
+        self.resyncs
+    }
+
+    /// Bytes thrown away while resyncing, garbage between frames and truncated frames.
+    pub const fn discarded_bytes(&self) -> usize {
+        self.discarded_bytes
+    }
+
+    fn discard(&mut self, count: usize, src: &mut BytesMut) {
+        self.resyncs += 1;
+        self.discarded_bytes += count;
+        src.advance(count);
+    }
+
+    fn byte_stuff(&self, data: u8, dst: &mut BytesMut) {
+        if data == self.stx || data == self.etx || data == self.dle {
+            dst.put_u8(self.dle);
         }
         dst.put_u8(data);
     }
 }
 
+/// Decodes every frame read from `io`, without pairing them with requests. Intended for sniffing
+/// and logging a session driven by something else.
+pub fn frame_stream<T, U>(io: T, codec: U) -> impl Stream<Item = Result<ResponseFrame, Error>>
+where
+    T: AsyncRead,
+    U: Decoder<Item = ResponseFrame, Error = Error>,
+{
+    FramedRead::new(io, codec)
+}
+
+impl Default for SerialCodec {
+    fn default() -> Self {
+        SerialCodec::new()
+    }
+}
+
 impl Encoder<RequestFrame> for SerialCodec {
     type Error = Error;
 
     fn encode(&mut self, item: RequestFrame, dst: &mut BytesMut) -> Result<(), Self::Error> {
         let mut digest = self.crc.digest();
 
-        dst.put_u8(SerialCodec::STX);
-        dst.put_u8(SerialCodec::STX);
+        dst.put_u8(self.stx);
+        dst.put_u8(self.stx);
 
-        SerialCodec::byte_stuff(item.command, dst);
+        self.byte_stuff(item.command, dst);
         digest.update(&[item.command]);
 
         let length = (item.data.len() as u16).to_be_bytes();
-        SerialCodec::byte_stuff(length[0], dst);
-        SerialCodec::byte_stuff(length[1], dst);
+        self.byte_stuff(length[0], dst);
+        self.byte_stuff(length[1], dst);
         digest.update(&length);
 
         for data in &item.data {
-            SerialCodec::byte_stuff(*data, dst);
+            self.byte_stuff(*data, dst);
         }
         digest.update(&item.data);
 
         let crc = digest.finalize().to_be_bytes();
-        SerialCodec::byte_stuff(crc[0], dst);
-        SerialCodec::byte_stuff(crc[1], dst);
+        self.byte_stuff(crc[0], dst);
+        self.byte_stuff(crc[1], dst);
 
-        dst.put_u8(SerialCodec::ETX);
+        dst.put_u8(self.etx);
 
         Ok(())
     }
 }
 
-impl Decoder for SerialCodec {
-    type Item = ResponseFrame;
+/// Device side of the protocol, mostly useful for mocks and test fixtures.
+impl Encoder<ResponseFrame> for SerialCodec {
     type Error = Error;
 
-    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if src.len() < 2 {
-            return Ok(None);
-        }
+    fn encode(&mut self, item: ResponseFrame, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let length = u8::try_from(item.data.len()).map_err(|_| {
+            Error::IOError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Response too long: {} bytes", item.data.len()),
+            ))
+        })?;
+        let command = item.command_byte();
+
+        let mut digest = self.crc.digest();
+
+        dst.put_u8(self.stx);
+        dst.put_u8(self.stx);
+
+        self.byte_stuff(command, dst);
+        self.byte_stuff(length, dst);
+        digest.update(&[command, length]);
 
-        if src[..2] != [SerialCodec::STX, SerialCodec::STX] {
-            return Err(Error::IOError(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("STX: {:?}", &src[..2]),
-            )));
+        for data in &item.data {
+            self.byte_stuff(*data, dst);
         }
+        digest.update(&item.data);
 
-        let mut digest = self.crc.digest();
-        let mut skip = false;
+        let crc = digest.finalize().to_be_bytes();
+        self.byte_stuff(crc[0], dst);
+        self.byte_stuff(crc[1], dst);
+
+        dst.put_u8(self.etx);
+
+        Ok(())
+    }
+}
+
+/// Where `SerialCodec::scan` got to in the frame at the front of a buffer.
+enum Scan {
+    /// Ran out of bytes, the progress so far is in the `PartialFrame`.
+    Incomplete,
+    /// A frame, and the bytes it took up.
+    Frame(ResponseFrame, usize),
+    /// An invalid frame, and the bytes to drop to get past it.
+    Invalid(Error, usize),
+    /// An ETX too early to end a frame, and the bytes up to and including it.
+    Runt(usize),
+}
 
-        let mut data = vec![];
+impl SerialCodec {
+    /// Offset of the next frame start in `src`, or of a trailing STX that may be the first half
+    /// of one.
+    fn frame_start(&self, src: &[u8]) -> usize {
+        match src.windows(2).position(|w| w == [self.stx, self.stx]) {
+            Some(start) => start,
+            None if src.last() == Some(&self.stx) => src.len() - 1,
+            None => src.len(),
+        }
+    }
 
-        for idx in 2..src.len() {
-            if skip {
-                skip = false;
-                data.push(src[idx]);
+    /// Scans the frame `src` starts with, from where `partial` left off.
+    fn scan(&self, src: &[u8], partial: &mut PartialFrame) -> Scan {
+        // Resuming from the running value works as XMODEM has no reflection or final XOR
+        let mut digest = self.crc.digest_with_initial(partial.crc);
+
+        for idx in partial.scanned..src.len() {
+            if partial.skip {
+                partial.skip = false;
+                partial.data.push(src[idx]);
+                digest.update(&src[idx..idx + 1]);
             } else {
                 match src[idx] {
-                    SerialCodec::STX => {
-                        return Err(Error::IOError(std::io::Error::new(
-                            std::io::ErrorKind::InvalidData,
-                            format!("Unexpected STX: {:?}", &src[..idx + 1]),
-                        )));
+                    byte if byte == self.stx => {
+                        // The frame was cut short, resume from the new frame start
+                        return Scan::Invalid(Error::UnexpectedStx(src[..idx + 1].to_vec()), idx);
                     }
-                    SerialCodec::ETX => {
+                    byte if byte == self.etx => {
+                        let mut data = std::mem::take(&mut partial.data);
+                        if data.len() < 4 && !self.runt_frame_errors {
+                            return Scan::Runt(idx + 1);
+                        }
                         if data.len() < 4 || (data[1] as usize) != (data.len() - 4) {
-                            return Err(Error::IOError(std::io::Error::new(
-                                std::io::ErrorKind::InvalidData,
-                                format!("Bad frame: {:?}", &src[..idx + 1]),
-                            )));
+                            return Scan::Invalid(
+                                Error::BadFrame(src[..idx + 1].to_vec()),
+                                idx + 1,
+                            );
                         }
 
-                        digest.update(&data);
                         if digest.finalize() != 0 {
-                            return Err(Error::IOError(std::io::Error::new(
-                                std::io::ErrorKind::InvalidData,
-                                format!("Bad CRC: {:?}", &src[..idx + 1]),
-                            )));
+                            return Scan::Invalid(Error::BadCrc(src[..idx + 1].to_vec()), idx + 1);
                         }
 
-                        let ack = (data[0] & 0x40) == 0x40;
-                        let command = data.remove(0) & 0x3F;
+                        let command_byte = data.remove(0);
                         let length = data.remove(0) as usize;
 
                         data.truncate(length);
 
-                        let response = ResponseFrame { ack, command, data };
-
-                        src.advance(idx + 1);
-
-                        return Ok(Some(response));
+                        return Scan::Frame(
+                            ResponseFrame::from_command_byte(command_byte, data),
+                            idx + 1,
+                        );
                     }
-                    SerialCodec::DLE => skip = true,
+                    byte if byte == self.dle => partial.skip = true,
                     _ => {
-                        data.push(src[idx]);
+                        partial.data.push(src[idx]);
+                        digest.update(&src[idx..idx + 1]);
+                    }
+                }
+            }
+        }
+
+        partial.scanned = src.len();
+        partial.crc = digest.finalize();
+        Scan::Incomplete
+    }
+
+    /// Decodes the first frame in `src` without a `BytesMut`, for callers driving the codec
+    /// themselves. Returns it with the number of bytes to drop from the front of `src`, including
+    /// any garbage ahead of it, or `None` if `src` doesn't hold a whole frame yet.
+    ///
+    /// Unlike `decode` nothing is carried over between calls, so each call scans `src` from the
+    /// start. After an error, drop at least one byte and the next call skips ahead to the
+    /// following frame start.
+    pub fn decode_one(&mut self, src: &[u8]) -> Result<Option<(ResponseFrame, usize)>, Error> {
+        let mut skipped = 0;
+        loop {
+            let start = skipped + self.frame_start(&src[skipped..]);
+            if src.len() - start < 2 {
+                return Ok(None);
+            }
+
+            let mut partial = PartialFrame {
+                scanned: 2,
+                ..PartialFrame::default()
+            };
+            match self.scan(&src[start..], &mut partial) {
+                Scan::Incomplete => return Ok(None),
+                Scan::Frame(frame, consumed) => return Ok(Some((frame, start + consumed))),
+                Scan::Runt(consumed) => skipped = start + consumed,
+                Scan::Invalid(err, _) => {
+                    if let Error::BadCrc(_) = err {
+                        self.crc_errors += 1;
                     }
+                    return Err(err);
                 }
             }
         }
+    }
+
+    /// Whether `frame`, a single whole response frame from STX to ETX as it went over the wire,
+    /// has a good CRC. For checking frames from elsewhere, e.g. logs, nothing is counted.
+    /// Anything but one complete, well formed frame fails with `BadFrame` or `UnexpectedStx`.
+    pub fn verify_frame(&self, frame: &[u8]) -> Result<bool, Error> {
+        if !frame.starts_with(&[self.stx, self.stx]) {
+            return Err(Error::BadFrame(frame.to_vec()));
+        }
 
-        Ok(None)
+        let mut partial = PartialFrame {
+            scanned: 2,
+            ..PartialFrame::default()
+        };
+        match self.scan(frame, &mut partial) {
+            Scan::Frame(_, consumed) if consumed == frame.len() => Ok(true),
+            Scan::Invalid(Error::BadCrc(_), consumed) if consumed == frame.len() => Ok(false),
+            Scan::Invalid(err @ Error::UnexpectedStx(_), _) => Err(err),
+            _ => Err(Error::BadFrame(frame.to_vec())),
+        }
+    }
+}
+
+impl Decoder for SerialCodec {
+    type Item = ResponseFrame;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        // The buffer was cleared behind our back, e.g. by `Updater` after a timeout
+        if src.len() < self.partial.scanned {
+            self.partial = PartialFrame::default();
+        }
+
+        loop {
+            if self.partial.scanned == 0 {
+                // Skip anything ahead of the next frame start
+                let start = self.frame_start(src);
+                if start > 0 {
+                    self.discard(start, src);
+                }
+
+                if src.len() < 2 {
+                    return Ok(None);
+                }
+                self.partial.scanned = 2;
+            }
+
+            // Left empty by every outcome but `Incomplete`, they all move past this frame
+            let mut partial = std::mem::take(&mut self.partial);
+            match self.scan(src, &mut partial) {
+                Scan::Incomplete => {
+                    self.partial = partial;
+                    return Ok(None);
+                }
+                Scan::Frame(frame, consumed) => {
+                    src.advance(consumed);
+                    return Ok(Some(frame));
+                }
+                // Line noise, carry on with whatever follows
+                Scan::Runt(consumed) => self.discard(consumed, src),
+                Scan::Invalid(err @ Error::BadCrc(_), consumed) => {
+                    self.crc_errors += 1;
+                    src.advance(consumed);
+                    return Err(err);
+                }
+                Scan::Invalid(err, consumed) => {
+                    self.discard(consumed, src);
+                    return Err(err);
+                }
+            }
+        }
     }
 }
@@ -1,4 +1,5 @@
 use crate::codec::ResponseFrame;
+use crate::protocol::UploadMode;
 
 use thiserror::Error;
 
@@ -13,12 +14,117 @@ pub enum Error {
     #[error("InvalidResponse")]
     InvalidResponse(ResponseFrame),
 
+    #[error("BadCrc")]
+    BadCrc(Vec<u8>),
+
+    #[error("BadFrame")]
+    BadFrame(Vec<u8>),
+
+    #[error("UnexpectedStx")]
+    UnexpectedStx(Vec<u8>),
+
     #[error("BinCode")]
     BinCode(#[from] Box<bincode::ErrorKind>),
 
     #[error("Timeout")]
     Timeout,
 
+    #[error("DeadlineExceeded")]
+    DeadlineExceeded,
+
+    #[error("DeviceReset")]
+    DeviceReset,
+
+    #[error("Disconnected")]
+    Disconnected,
+
+    #[error("Cancelled")]
+    Cancelled,
+
+    #[error("DeviceMismatch")]
+    DeviceMismatch { device_id: u16, expected: Vec<u16> },
+
+    #[error("DeviceNotAllowed")]
+    DeviceNotAllowed { device_id: u16 },
+
+    #[error("UnsupportedHardware")]
+    UnsupportedHardware { found: (u8, u8) },
+
+    #[error("DeviceIdMismatch")]
+    DeviceIdMismatch { expected: u16, actual: u16 },
+
+    #[error("ChunkDesync")]
+    ChunkDesync { expected: usize, actual: u16 },
+
+    #[error("InvalidNegotiation")]
+    InvalidNegotiation { max_chunk_size: u16 },
+
+    #[error("InvalidChunkSize")]
+    InvalidChunkSize { chunk_size: usize },
+
+    #[error("ChunkSizeTooSmall")]
+    ChunkSizeTooSmall { offered: u16, floor: usize },
+
+    #[error("ImageTooLarge")]
+    ImageTooLarge {
+        image_idx: usize,
+        offset: u32,
+        size: u32,
+        available: u32,
+    },
+
+    #[error("UnsupportedImageType")]
+    UnsupportedImageType { image_idx: usize, image_type: u8 },
+
+    #[error("UnsupportedMode")]
+    UnsupportedMode { image_idx: usize, mode: UploadMode },
+
+    #[error("VerificationFailed")]
+    VerificationFailed {
+        image_idx: usize,
+        expected: u32,
+        actual: u32,
+    },
+
+    #[error("ReadbackMismatch")]
+    ReadbackMismatch { image_idx: usize, offset: u32 },
+
+    #[error("TooManyDeviceIds")]
+    TooManyDeviceIds { count: usize, max: usize },
+
+    #[error("TooManyDescriptors")]
+    TooManyDescriptors { count: usize, max: usize },
+
+    #[error("NoDeviceIds")]
+    NoDeviceIds,
+
+    #[error("NoImages")]
+    NoImages,
+
+    #[error("ImageOutOfBounds")]
+    ImageOutOfBounds { image_idx: usize },
+
+    #[error("InvalidImageIndex")]
+    InvalidImageIndex { image_idx: usize, target: u8 },
+
+    #[error("AmbiguousRoot")]
+    AmbiguousRoot { roots: Vec<usize> },
+
+    #[error("ImageCycle")]
+    ImageCycle { image_idx: usize },
+
+    #[error("EmptyImage")]
+    EmptyImage { image_idx: usize },
+
+    #[error("OverlappingImages")]
+    OverlappingImages { a: usize, b: usize },
+
+    #[error("NoMatchingImage")]
+    NoMatchingImage { device_id: u16 },
+
+    #[error("AmbiguousImage")]
+    AmbiguousImage { device_id: u16, matches: Vec<usize> },
+
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 
@@ -1,66 +1,180 @@
-use std::collections::HashSet;
+use std::collections::BTreeSet;
 use std::fs;
 use std::io;
 use std::path::Path;
 
 use bytes::Buf;
+use crc::Crc;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::error::Error;
+use crate::protocol::UploadMode;
+
+/// Signature and version, "STNFWv05".
+const HEADER_LEN: usize = 8;
+
+/// Byte of the signature telling plain containers (`v`) from compressed ones (`z`). Everything
+/// after the header of a compressed container is a zlib stream holding the rest of the plain
+/// container, image offsets included, so it's only inflated and the header made plain again.
+const COMPRESSION_FLAG_OFFSET: usize = 5;
+const PLAIN: u8 = b'v';
+const ZLIB: u8 = b'z';
+
+/// Largest payload a compressed container is allowed to inflate to, far beyond any real flash.
+#[cfg(feature = "compression")]
+const MAX_INFLATED_LEN: u64 = 16 * 1024 * 1024;
 
 pub struct FirmwareImageDescriptor {
     pub image_type: u8,
+    /// Unused by the bootloader, zero in every container seen so far.
+    pub reserved: u8,
     pub next_idx: u8,
     pub error_idx: u8,
     pub image_offset: u32,
     pub image_size: u32,
 }
 
+impl FirmwareImageDescriptor {
+    fn range(&self) -> std::ops::Range<u64> {
+        let start = self.image_offset as u64;
+        start..start + self.image_size as u64
+    }
+
+    /// Images the upload may continue with after this one, 0xFF ends it.
+    fn successors(&self) -> impl Iterator<Item = u8> {
+        let error_idx = match self.image_type {
+            // Normal, Tolerate Errors
+            0x01 => Some(self.error_idx),
+            _ => None,
+        };
+        std::iter::once(self.next_idx)
+            .chain(error_idx)
+            .filter(|&idx| idx != 0xFF)
+    }
+}
+
+/// What a bootloader can do with a container.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    pub image_types: BTreeSet<u8>,
+    pub upload_modes: BTreeSet<UploadMode>,
+}
+
+impl Default for Capabilities {
+    /// Everything this crate knows how to upload.
+    fn default() -> Self {
+        Capabilities {
+            image_types: BTreeSet::from([0x00, 0x01, 0x10]),
+            upload_modes: BTreeSet::from([
+                UploadMode::Program,
+                UploadMode::ProgramVerify,
+                UploadMode::Verify,
+            ]),
+        }
+    }
+}
+
+/// Upper bounds on the tables of a container, checked as they're parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseLimits {
+    pub max_descriptors: usize,
+    pub max_device_ids: usize,
+}
+
+impl Default for ParseLimits {
+    /// 256 of each, more than the one byte counts of a v05 container can hold.
+    fn default() -> Self {
+        ParseLimits {
+            max_descriptors: 256,
+            max_device_ids: 256,
+        }
+    }
+}
+
 pub struct FirmwareImage {
-    pub device_ids: HashSet<u16>,
+    pub device_ids: BTreeSet<u16>,
     pub descriptors: Vec<FirmwareImageDescriptor>,
     pub data: Vec<u8>,
 }
 
 impl FirmwareImage {
     pub fn open<P: AsRef<Path>>(path: P) -> io::Result<FirmwareImage> {
-        let firmware_file = fs::read(path)?;
-        let mut buf: &[u8] = &firmware_file;
+        FirmwareImage::open_with_limits(path, ParseLimits::default())
+    }
 
-        if &buf[..6] != b"STNFWv" {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Invalid file signature",
-            ));
-        }
-        buf.advance(6);
+    /// Like `open`, rejecting containers with more descriptors or device IDs than `limits`.
+    pub fn open_with_limits<P: AsRef<Path>>(
+        path: P,
+        limits: ParseLimits,
+    ) -> io::Result<FirmwareImage> {
+        FirmwareImage::from_bytes_with_limits(fs::read(path)?, limits).map_err(|err| match err {
+            Error::IOError(err) => err,
+            err => io::Error::new(io::ErrorKind::InvalidData, err),
+        })
+    }
 
-        if &buf[..2] != b"05" {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Invalid file version",
-            ));
-        }
-        buf.advance(2);
+    /// Parses and validates a container, as `open` does for files.
+    pub fn from_bytes(firmware_file: Vec<u8>) -> Result<FirmwareImage, Error> {
+        FirmwareImage::from_bytes_with_limits(firmware_file, ParseLimits::default())
+    }
+
+    /// Like `from_bytes`, rejecting containers with more descriptors or device IDs than `limits`.
+    pub fn from_bytes_with_limits(
+        firmware_file: Vec<u8>,
+        limits: ParseLimits,
+    ) -> Result<FirmwareImage, Error> {
+        FirmwareImage::check_header(&firmware_file)?;
+        let firmware_file = match firmware_file[COMPRESSION_FLAG_OFFSET] {
+            ZLIB => FirmwareImage::inflate(&firmware_file)?,
+            _ => firmware_file,
+        };
+        let mut buf: &[u8] = &firmware_file[HEADER_LEN..];
 
+        let truncated = || io::Error::new(io::ErrorKind::InvalidData, "Truncated descriptors");
+
+        if buf.remaining() < 1 {
+            return Err(truncated().into());
+        }
         let device_ids_count = buf.get_u8();
+        if device_ids_count as usize > limits.max_device_ids {
+            return Err(Error::TooManyDeviceIds {
+                count: device_ids_count as usize,
+                max: limits.max_device_ids,
+            });
+        }
 
+        if buf.remaining() < device_ids_count as usize * 2 + 1 {
+            return Err(truncated().into());
+        }
         let device_ids = (0..device_ids_count)
             .map(|_| buf.get_u16())
-            .collect::<HashSet<u16>>();
+            .collect::<BTreeSet<u16>>();
 
         let descriptor_count = buf.get_u8();
+        if descriptor_count as usize > limits.max_descriptors {
+            return Err(Error::TooManyDescriptors {
+                count: descriptor_count as usize,
+                max: limits.max_descriptors,
+            });
+        }
 
         let descriptors = if descriptor_count == 0 {
             vec![FirmwareImageDescriptor {
                 image_type: 0x00,
+                reserved: 0x00,
                 next_idx: 0xFF,
                 error_idx: 0x00,
                 image_offset: 12,
-                image_size: (firmware_file.len() - 12) as u32,
+                image_size: firmware_file.len().saturating_sub(12) as u32,
             }]
         } else {
+            if buf.remaining() < descriptor_count as usize * 12 {
+                return Err(truncated().into());
+            }
             (0..descriptor_count)
                 .map(|_| {
                     let image_type = buf.get_u8();
-                    let _ = buf.get_u8();
+                    let reserved = buf.get_u8();
                     let next_idx = buf.get_u8();
                     let error_idx = buf.get_u8();
                     let image_offset = buf.get_u32();
@@ -68,6 +182,7 @@ impl FirmwareImage {
 
                     FirmwareImageDescriptor {
                         image_type,
+                        reserved,
                         next_idx,
                         error_idx,
                         image_offset,
@@ -77,10 +192,274 @@ impl FirmwareImage {
                 .collect()
         };
 
-        Ok(FirmwareImage {
+        let firmware = FirmwareImage {
             device_ids,
             descriptors,
             data: firmware_file,
-        })
+        };
+        let validation = firmware.validate();
+        #[cfg(feature = "tracing")]
+        if let Err(err) = &validation {
+            tracing::warn!(error = ?err, "Invalid firmware container");
+        }
+        validation?;
+
+        #[cfg(feature = "tracing")]
+        tracing::info!(
+            version = "05",
+            device_ids = ?firmware.device_ids,
+            images = firmware.descriptors.len(),
+            size = firmware.data.len(),
+            "Loaded firmware container"
+        );
+
+        Ok(firmware)
+    }
+
+    /// Parses a container as it's read from `reader`, e.g. a download in progress. A bad header
+    /// fails before the payload is read.
+    pub async fn from_async_reader<R: AsyncRead + Unpin>(
+        mut reader: R,
+    ) -> Result<FirmwareImage, Error> {
+        let mut firmware_file = vec![0u8; HEADER_LEN];
+        reader.read_exact(&mut firmware_file).await?;
+        FirmwareImage::check_header(&firmware_file)?;
+
+        reader.read_to_end(&mut firmware_file).await?;
+        FirmwareImage::from_bytes(firmware_file)
+    }
+
+    #[cfg(feature = "compression")]
+    fn inflate(firmware_file: &[u8]) -> io::Result<Vec<u8>> {
+        use std::io::Read;
+
+        let mut inflated = firmware_file[..HEADER_LEN].to_vec();
+        inflated[COMPRESSION_FLAG_OFFSET] = PLAIN;
+        flate2::read::ZlibDecoder::new(&firmware_file[HEADER_LEN..])
+            .take(MAX_INFLATED_LEN + 1)
+            .read_to_end(&mut inflated)?;
+        if (inflated.len() - HEADER_LEN) as u64 > MAX_INFLATED_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Compressed payload too large",
+            ));
+        }
+        Ok(inflated)
+    }
+
+    #[cfg(not(feature = "compression"))]
+    fn inflate(_firmware_file: &[u8]) -> io::Result<Vec<u8>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Compressed container, requires the compression feature",
+        ))
+    }
+
+    /// Checks the signature and version ahead of the descriptors.
+    fn check_header(buf: &[u8]) -> io::Result<()> {
+        if !buf.starts_with(b"STNFW")
+            || !matches!(buf.get(COMPRESSION_FLAG_OFFSET), Some(&PLAIN | &ZLIB))
+        {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(offset = 0, "Invalid firmware file signature");
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid file signature",
+            ));
+        }
+
+        let version = &buf[6..buf.len().min(HEADER_LEN)];
+        if version != b"05" {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(
+                offset = 6,
+                version = %String::from_utf8_lossy(version),
+                "Invalid firmware file version"
+            );
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid file version",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Images no other image continues with, see `root_idx`. Expects every index to be in range.
+    fn roots(&self) -> Vec<usize> {
+        let mut referenced = vec![false; self.descriptors.len()];
+        for descriptor in &self.descriptors {
+            for target in descriptor.successors() {
+                referenced[target as usize] = true;
+            }
+        }
+        (0..self.descriptors.len())
+            .filter(|&idx| !referenced[idx])
+            .collect()
+    }
+
+    /// The image the upload starts with, the one no other image's `next_idx` or `error_idx`
+    /// points at. `validate` checks there's exactly one, image 0 is assumed if there isn't.
+    pub fn root_idx(&self) -> usize {
+        match self.roots()[..] {
+            [root] => root,
+            _ => 0,
+        }
+    }
+
+    /// First image the upload could come back to, following `next_idx` and `error_idx` from
+    /// `start`. Expects every index to be in range.
+    fn find_cycle(&self, start: usize) -> Option<usize> {
+        const UNVISITED: u8 = 0;
+        const ON_PATH: u8 = 1;
+        const DONE: u8 = 2;
+
+        let mut state = vec![UNVISITED; self.descriptors.len()];
+        let mut path = vec![(start, self.descriptors[start].successors())];
+        state[start] = ON_PATH;
+
+        while let Some((idx, successors)) = path.last_mut() {
+            match successors.next().map(usize::from) {
+                Some(next) if state[next] == ON_PATH => return Some(next),
+                Some(next) if state[next] == UNVISITED => {
+                    state[next] = ON_PATH;
+                    path.push((next, self.descriptors[next].successors()));
+                }
+                Some(_) => {}
+                None => {
+                    state[*idx] = DONE;
+                    path.pop();
+                }
+            }
+        }
+
+        None
+    }
+
+    /// An image's data, located purely by its descriptor. Offsets count from the start of the
+    /// file, so padding or anything else between the descriptor table and the payload is skipped.
+    pub fn image_data(&self, image_idx: usize) -> &[u8] {
+        let range = self.descriptors[image_idx].range();
+        &self.data[range.start as usize..range.end as usize]
+    }
+
+    /// CRC-32 of an image's data, as `Updater::device_checksum` reports it once uploaded.
+    pub fn crc32(&self, image_idx: usize) -> u32 {
+        Crc::<u32>::new(&crc::CRC_32_ISO_HDLC).checksum(self.image_data(image_idx))
+    }
+
+    /// Images uploaded when every image succeeds, i.e. the length of the `next_idx` chain.
+    pub fn num_images(&self) -> usize {
+        let mut count = 0;
+        let mut idx = self.root_idx() as u8;
+        while idx != 0xFF && count < self.descriptors.len() {
+            count += 1;
+            idx = self.descriptors[idx as usize].next_idx;
+        }
+        count
+    }
+
+    /// Picks the one image in `images` built for `device_id`.
+    pub fn select_for_device(
+        images: &[FirmwareImage],
+        device_id: u16,
+    ) -> Result<&FirmwareImage, Error> {
+        let matches = images
+            .iter()
+            .enumerate()
+            .filter(|(_, image)| image.device_ids.contains(&device_id))
+            .map(|(idx, _)| idx)
+            .collect::<Vec<_>>();
+
+        match matches[..] {
+            [] => Err(Error::NoMatchingImage { device_id }),
+            [idx] => Ok(&images[idx]),
+            _ => Err(Error::AmbiguousImage { device_id, matches }),
+        }
+    }
+
+    /// Checks every descriptor's image type, and the mode it's uploaded with, is supported by a
+    /// device with `capabilities`. Every image is assumed to be sent with `UploadMode::Program`,
+    /// as `Updater` does by default.
+    pub fn check_compatibility(&self, capabilities: &Capabilities) -> Result<(), Error> {
+        for (image_idx, descriptor) in self.descriptors.iter().enumerate() {
+            if !capabilities.image_types.contains(&descriptor.image_type) {
+                return Err(Error::UnsupportedImageType {
+                    image_idx,
+                    image_type: descriptor.image_type,
+                });
+            }
+
+            let mode = UploadMode::Program;
+            if !capabilities.upload_modes.contains(&mode) {
+                return Err(Error::UnsupportedMode { image_idx, mode });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks the container for authoring mistakes before anything is sent to a device, returning
+    /// the first one found. `open` already rejects containers failing it.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.device_ids.is_empty() {
+            return Err(Error::NoDeviceIds);
+        }
+        if self.descriptors.is_empty() {
+            return Err(Error::NoImages);
+        }
+
+        for (image_idx, descriptor) in self.descriptors.iter().enumerate() {
+            if !matches!(descriptor.image_type, 0x00 | 0x01 | 0x10) {
+                return Err(Error::UnsupportedImageType {
+                    image_idx,
+                    image_type: descriptor.image_type,
+                });
+            }
+
+            if descriptor.range().end > self.data.len() as u64 {
+                return Err(Error::ImageOutOfBounds { image_idx });
+            }
+
+            // There's nothing to verify in an empty validation image, but an empty image to
+            // program is a broken container
+            if descriptor.image_size == 0 && descriptor.image_type != 0x10 {
+                return Err(Error::EmptyImage { image_idx });
+            }
+
+            if let Some(target) = descriptor
+                .successors()
+                .find(|&target| target as usize >= self.descriptors.len())
+            {
+                return Err(Error::InvalidImageIndex { image_idx, target });
+            }
+        }
+
+        // Every image being pointed at means the chain loops somewhere
+        let roots = self.roots();
+        let cycle = match roots[..] {
+            [] => (0..self.descriptors.len()).find_map(|start| self.find_cycle(start)),
+            [root] => self.find_cycle(root),
+            _ => return Err(Error::AmbiguousRoot { roots }),
+        };
+        if let Some(image_idx) = cycle {
+            return Err(Error::ImageCycle { image_idx });
+        }
+
+        for (a, first) in self.descriptors.iter().enumerate() {
+            for (b, second) in self.descriptors.iter().enumerate().skip(a + 1) {
+                // Validation images are expected to cover data that was already programmed
+                if first.image_type == 0x10 || second.image_type == 0x10 {
+                    continue;
+                }
+
+                let (first, second) = (first.range(), second.range());
+                if first.start < second.end && second.start < first.end {
+                    return Err(Error::OverlappingImages { a, b });
+                }
+            }
+        }
+
+        Ok(())
     }
 }
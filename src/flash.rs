@@ -0,0 +1,31 @@
+use std::path::Path;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::codec::{RequestFrame, ResponseFrame};
+use crate::error::Error;
+use crate::firmware::FirmwareImage;
+use crate::updater::{ProgressControl, Resetter, Updater, UploadReport};
+
+/// Flashes the container at `path` to the device on `transport` with the default `Updater`
+/// settings, resetting it into the bootloader with `D` if needed. `progress` is called the same
+/// way as by `Updater::upload_firmware`.
+pub async fn flash_file<D, U, P, C>(
+    transport: D::Device,
+    codec: U,
+    path: P,
+    progress: impl Fn(usize, usize, usize, usize) -> C + Send,
+) -> Result<UploadReport, Error>
+where
+    D: Resetter,
+    D::Device: AsyncRead + AsyncWrite + Unpin,
+    U: Encoder<RequestFrame, Error = Error> + Decoder<Item = ResponseFrame, Error = Error>,
+    P: AsRef<Path>,
+    C: ProgressControl,
+{
+    let firmware = FirmwareImage::open(path)?;
+    Updater::new(transport, codec)
+        .upload_firmware::<D, C>(&firmware, progress)
+        .await
+}
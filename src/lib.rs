@@ -1,5 +1,10 @@
+pub mod capture;
 pub mod codec;
 pub mod error;
 pub mod firmware;
+mod flash;
 pub mod protocol;
+pub mod transport;
 pub mod updater;
+
+pub use flash::flash_file;
@@ -1,12 +1,16 @@
 use std::marker::PhantomData;
 
-use crate::codec::{RequestFrame, ResponseFrame};
+use crate::codec::{RequestFrame, ResponseFrame, SerialCodec};
 use crate::error::Error;
 
 use bincode::Options;
+use bytes::BytesMut;
+use crc::Crc;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio_util::codec::Encoder;
 
 pub trait IntoBytes {
+    #[allow(clippy::wrong_self_convention)]
     fn into_bytes(&self) -> Vec<u8>;
 }
 
@@ -21,16 +25,108 @@ pub trait FromBytes: Sized {
     fn from_bytes(bytes: &[u8]) -> Result<Self, Self::Error>;
 }
 
-impl<T: DeserializeOwned> FromBytes for T {
+impl<T: DeserializeOwned + Response> FromBytes for T {
     type Error = Error;
 
     fn from_bytes(bytes: &[u8]) -> Result<Self, Self::Error> {
-        let result = bincode::DefaultOptions::new()
-            .with_fixint_encoding()
-            .allow_trailing_bytes()
-            .with_big_endian()
-            .deserialize_from(bytes)?;
-        Ok(result)
+        Ok(T::decode_options().deserialize(bytes)?)
+    }
+}
+
+/// Bincode settings of a response payload.
+///
+/// Defaults to fixed-width big-endian integers with trailing bytes allowed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeOptions {
+    little_endian: bool,
+    varint: bool,
+    reject_trailing: bool,
+}
+
+impl DecodeOptions {
+    pub const fn new() -> DecodeOptions {
+        DecodeOptions {
+            little_endian: false,
+            varint: false,
+            reject_trailing: false,
+        }
+    }
+
+    pub const fn with_little_endian(mut self) -> DecodeOptions {
+        self.little_endian = true;
+        self
+    }
+
+    pub const fn with_varint_encoding(mut self) -> DecodeOptions {
+        self.varint = true;
+        self
+    }
+
+    pub const fn reject_trailing_bytes(mut self) -> DecodeOptions {
+        self.reject_trailing = true;
+        self
+    }
+
+    pub fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> bincode::Result<T> {
+        struct Decode<'a, T>(&'a [u8], PhantomData<T>);
+        impl<T: DeserializeOwned> WithOptions for Decode<'_, T> {
+            type Output = bincode::Result<T>;
+            fn with<O: Options>(self, options: O) -> Self::Output {
+                options.deserialize(self.0)
+            }
+        }
+
+        self.apply(Decode(bytes, PhantomData))
+    }
+
+    pub fn serialize<T: Serialize>(&self, value: &T) -> bincode::Result<Vec<u8>> {
+        struct Encode<'a, T>(&'a T);
+        impl<T: Serialize> WithOptions for Encode<'_, T> {
+            type Output = bincode::Result<Vec<u8>>;
+            fn with<O: Options>(self, options: O) -> Self::Output {
+                options.serialize(self.0)
+            }
+        }
+
+        self.apply(Encode(value))
+    }
+
+    fn apply<W: WithOptions>(&self, with: W) -> W::Output {
+        let options = bincode::DefaultOptions::new();
+        if self.varint {
+            self.with_endian(options.with_varint_encoding(), with)
+        } else {
+            self.with_endian(options.with_fixint_encoding(), with)
+        }
+    }
+
+    fn with_endian<O: Options, W: WithOptions>(&self, options: O, with: W) -> W::Output {
+        if self.little_endian {
+            self.with_trailing(options.with_little_endian(), with)
+        } else {
+            self.with_trailing(options.with_big_endian(), with)
+        }
+    }
+
+    fn with_trailing<O: Options, W: WithOptions>(&self, options: O, with: W) -> W::Output {
+        if self.reject_trailing {
+            with.with(options.reject_trailing_bytes())
+        } else {
+            with.with(options.allow_trailing_bytes())
+        }
+    }
+}
+
+// Each bincode option combinator returns a different type, so the finished `Options` are handed to
+// whatever needs them rather than returned.
+trait WithOptions {
+    type Output;
+    fn with<O: Options>(self, options: O) -> Self::Output;
+}
+
+impl Default for DecodeOptions {
+    fn default() -> Self {
+        DecodeOptions::new()
     }
 }
 
@@ -43,9 +139,41 @@ pub trait Request: IntoBytes {
     }
 }
 
+/// Wire bytes of `request`, and of `response` framed the way the device would send it, for
+/// declaring replay fixtures with typed values instead of hand-assembled bytes.
+pub fn encode_exchange<T>(request: &T, response: &T::Response) -> Result<(Vec<u8>, Vec<u8>), Error>
+where
+    T: Request,
+    T::Response: Serialize,
+{
+    let mut codec = SerialCodec::new();
+
+    let mut request_bytes = BytesMut::new();
+    codec.encode(request.frame(), &mut request_bytes)?;
+
+    let data = T::Response::decode_options().serialize(response)?;
+    let mut response_bytes = BytesMut::new();
+    codec.encode(
+        ResponseFrame::new(true, T::COMMAND, data),
+        &mut response_bytes,
+    )?;
+
+    Ok((request_bytes.to_vec(), response_bytes.to_vec()))
+}
+
 pub trait Response: FromBytes<Error = Error> {
+    /// Wire format of the payload, override for responses that aren't big-endian fixint.
+    fn decode_options() -> DecodeOptions {
+        DecodeOptions::new()
+    }
+
+    /// A connect response to anything but a `ConnectRequest` fails with `Error::DeviceReset`, the
+    /// bootloader only sends one unprompted when it restarted, e.g. after a brownout, and the
+    /// session state is gone.
     fn from_frame<T: Request>(frame: ResponseFrame) -> Result<Self, Error> {
-        if frame.command != T::COMMAND {
+        if frame.command == ConnectRequest::COMMAND && T::COMMAND != ConnectRequest::COMMAND {
+            Err(Error::DeviceReset)
+        } else if frame.command != T::COMMAND {
             Err(Error::InvalidCommand(frame))
         } else if !frame.ack {
             Err(Error::InvalidResponse(frame))
@@ -62,7 +190,7 @@ impl Request for ConnectRequest {
     type Response = ConnectResponse;
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct ConnectResponse;
 impl Response for ConnectResponse {}
 
@@ -73,7 +201,7 @@ impl Request for ResetRequest {
     type Response = ResetResponse;
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct ResetResponse;
 impl Response for ResetResponse {}
 
@@ -88,11 +216,17 @@ impl<T> ResendLastRequest<T> {
         }
     }
 }
+impl<T> Default for ResendLastRequest<T> {
+    fn default() -> Self {
+        ResendLastRequest::new()
+    }
+}
 impl<T: Response> Request for ResendLastRequest<T> {
     const COMMAND: u8 = 0x01;
     type Response = T;
 }
 
+/// Version of the bootloader protocol, not of the application firmware.
 #[derive(Serialize)]
 pub struct GetVersionRequest;
 impl Request for GetVersionRequest {
@@ -100,7 +234,7 @@ impl Request for GetVersionRequest {
     type Response = GetVersionResponse;
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct GetVersionResponse {
     pub major: u8,
     pub minor: u8,
@@ -114,7 +248,7 @@ impl Request for GetDevIDRequest {
     type Response = GetDevIDResponse;
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct GetDevIDResponse(pub u16);
 impl Response for GetDevIDResponse {}
 
@@ -125,13 +259,34 @@ impl Request for GetHWRevRequest {
     type Response = GetHWRevResponse;
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct GetHWRevResponse {
     pub major: u8,
     pub minor: u8,
 }
 impl Response for GetHWRevResponse {}
 
+/// Size and CRC-32 (ISO-HDLC) of the image last programmed, as recorded by the bootloader once it
+/// was written. Not part of the bootloader spec, the opcode 0x09 and the response layout are
+/// unverified guesses.
+#[cfg(feature = "experimental")]
+#[derive(Serialize)]
+pub struct GetImageCrcRequest;
+#[cfg(feature = "experimental")]
+impl Request for GetImageCrcRequest {
+    const COMMAND: u8 = 0x09;
+    type Response = GetImageCrcResponse;
+}
+
+#[cfg(feature = "experimental")]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GetImageCrcResponse {
+    pub size: u32,
+    pub crc: u32,
+}
+#[cfg(feature = "experimental")]
+impl Response for GetImageCrcResponse {}
+
 #[derive(Serialize)]
 pub struct GetSerialNumberRequest;
 impl Request for GetSerialNumberRequest {
@@ -139,7 +294,7 @@ impl Request for GetSerialNumberRequest {
     type Response = GetSerialNumberResponse;
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct GetSerialNumberResponse {
     pub serial: [u8; 8],
 }
@@ -152,12 +307,54 @@ impl Request for GetDeviceNameRequest {
     type Response = GetDeviceNameResponse;
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Debug)]
 pub struct GetDeviceNameResponse {
-    pub name: [char; 32],
+    pub name: String,
+}
+impl GetDeviceNameResponse {
+    pub const MAX_LENGTH: usize = 32;
+}
+impl FromBytes for GetDeviceNameResponse {
+    type Error = Error;
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, Self::Error> {
+        if bytes.len() > GetDeviceNameResponse::MAX_LENGTH {
+            return Err(Error::IOError(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Device name too long: {} bytes", bytes.len()),
+            )));
+        }
+
+        // Names shorter than the field are NUL-terminated
+        let length = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        Ok(GetDeviceNameResponse {
+            name: String::from_utf8_lossy(&bytes[..length]).to_string(),
+        })
+    }
 }
 impl Response for GetDeviceNameResponse {}
 
+/// Not part of the bootloader spec, the opcode 0x0C and the response layout are unverified
+/// guesses.
+#[cfg(feature = "experimental")]
+#[derive(Serialize)]
+pub struct GetFlashInfoRequest;
+#[cfg(feature = "experimental")]
+impl Request for GetFlashInfoRequest {
+    const COMMAND: u8 = 0x0C;
+    type Response = GetFlashInfoResponse;
+}
+
+#[cfg(feature = "experimental")]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetFlashInfoResponse {
+    pub start: u32,
+    pub size: u32,
+    pub page_size: u16,
+}
+#[cfg(feature = "experimental")]
+impl Response for GetFlashInfoResponse {}
+
 #[derive(Serialize)]
 pub struct GetFWStatusRequest;
 impl Request for GetFWStatusRequest {
@@ -165,18 +362,135 @@ impl Request for GetFWStatusRequest {
     type Response = GetFWStatusResponse;
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct GetFWStatusResponse(pub u8);
+impl GetFWStatusResponse {
+    pub fn status(&self) -> FirmwareStatus {
+        FirmwareStatus::from(self.0)
+    }
+}
 impl Response for GetFWStatusResponse {}
 
+/// Whether the application image passed the bootloader's check at boot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FirmwareStatus {
+    /// `0x00`
+    Valid,
+    /// `0x01`, missing or corrupt, the device stays in the bootloader
+    Invalid,
+    Unknown(u8),
+}
+
+impl From<u8> for FirmwareStatus {
+    fn from(status: u8) -> Self {
+        match status {
+            0x00 => FirmwareStatus::Valid,
+            0x01 => FirmwareStatus::Invalid,
+            status => FirmwareStatus::Unknown(status),
+        }
+    }
+}
+
+/// Why the device last entered the bootloader. Not part of the bootloader spec, the opcode 0x10
+/// and the reason codes are unverified guesses.
+#[cfg(feature = "experimental")]
+#[derive(Serialize)]
+pub struct GetResetReasonRequest;
+#[cfg(feature = "experimental")]
+impl Request for GetResetReasonRequest {
+    const COMMAND: u8 = 0x10;
+    type Response = GetResetReasonResponse;
+}
+
+#[cfg(feature = "experimental")]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetResetReasonResponse(pub u8);
+#[cfg(feature = "experimental")]
+impl GetResetReasonResponse {
+    pub fn reason(&self) -> ResetReason {
+        ResetReason::from(self.0)
+    }
+}
+#[cfg(feature = "experimental")]
+impl Response for GetResetReasonResponse {}
+
+#[cfg(feature = "experimental")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetReason {
+    /// `0x00`, a `ResetRequest` or the application jumping to the bootloader
+    Commanded,
+    /// `0x01`
+    Watchdog,
+    /// `0x02`
+    Brownout,
+    Unknown(u8),
+}
+
+#[cfg(feature = "experimental")]
+impl From<u8> for ResetReason {
+    fn from(reason: u8) -> Self {
+        match reason {
+            0x00 => ResetReason::Commanded,
+            0x01 => ResetReason::Watchdog,
+            0x02 => ResetReason::Brownout,
+            reason => ResetReason::Unknown(reason),
+        }
+    }
+}
+
+/// What the bootloader does with the chunks that follow a `StartUploadRequest`.
+///
+/// | Mode | Byte | Bootloader behavior |
+/// |------|------|---------------------|
+/// | `Program` | `0x01` | Erase the image region, then write each chunk |
+/// | `ProgramVerify` | `0x02` | As `Program`, reading back and comparing each chunk after writing |
+/// | `Verify` | `0x03` | Nothing is erased or written, each chunk is compared against flash |
+///
+/// Only `Program` is known to be understood by STN bootloaders, it's what every upload is sent
+/// with unless configured otherwise. The other two bytes are unverified against the bootloader
+/// documentation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum UploadMode {
+    Program = 0x01,
+    ProgramVerify = 0x02,
+    Verify = 0x03,
+}
+
 pub struct StartUploadRequest {
     pub image_size: u32,
-    pub mode: u8,
+    pub mode: UploadMode,
+    /// Every `SendChunkRequest` of this upload carries a CRC, see `SendChunkRequest::with_crc`.
+    pub chunk_crc: bool,
+    /// Where in flash the image goes, for bootloaders programming several regions in one
+    /// session. Appended as 4 big-endian bytes when set, a layout that's unverified as no STN
+    /// bootloader documentation defines the field.
+    pub target_offset: Option<u32>,
+}
+impl StartUploadRequest {
+    /// NACK status of a bootloader that can't accept an upload yet, e.g. right after a reset.
+    /// Unlike other rejections these clear up if the request is retried after a short delay.
+    ///
+    /// Unverified, no STN bootloader documentation defines these statuses, so they're only
+    /// retried if `Updater::busy_retry` is set.
+    pub const BUSY: u8 = 0x01;
+    pub const LOCKED: u8 = 0x02;
+
+    /// Set in the mode byte when `chunk_crc` is. Unverified, no STN bootloader documentation
+    /// defines per-chunk CRCs.
+    pub const CHUNK_CRC: u8 = 0x80;
 }
 impl IntoBytes for StartUploadRequest {
     fn into_bytes(&self) -> Vec<u8> {
         let mut output = self.image_size.to_be_bytes()[1..].to_vec();
-        output.push(self.mode);
+        if self.chunk_crc {
+            output.push(self.mode as u8 | StartUploadRequest::CHUNK_CRC);
+        } else {
+            output.push(self.mode as u8);
+        }
+        if let Some(target_offset) = self.target_offset {
+            output.extend_from_slice(&target_offset.to_be_bytes());
+        }
         output
     }
 }
@@ -185,18 +499,41 @@ impl Request for StartUploadRequest {
     type Response = StartUploadResponse;
 }
 
-#[derive(Deserialize, Debug)]
-pub struct StartUploadResponse(pub u16);
+/// Only `max_chunk_size` is documented, anything a bootloader sends after it is ignored.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StartUploadResponse {
+    pub max_chunk_size: u16,
+}
 impl Response for StartUploadResponse {}
 
 pub struct SendChunkRequest {
     pub chunk_num: u16,
     pub data: Vec<u8>,
+    /// CRC-16 (XMODEM) of `data`, appended for uploads started with `chunk_crc`. The CRC and its
+    /// placement are unverified, see `StartUploadRequest::CHUNK_CRC`.
+    pub crc: Option<u16>,
+}
+impl SendChunkRequest {
+    /// NACK status of a chunk whose data didn't match its CRC, the chunk should be sent again.
+    /// Unverified, like the rest of `StartUploadRequest::CHUNK_CRC`.
+    pub const BAD_CRC: u8 = 0x03;
+
+    pub fn with_crc(chunk_num: u16, data: Vec<u8>) -> SendChunkRequest {
+        let crc = Crc::<u16>::new(&crc::CRC_16_XMODEM).checksum(&data);
+        SendChunkRequest {
+            chunk_num,
+            data,
+            crc: Some(crc),
+        }
+    }
 }
 impl IntoBytes for SendChunkRequest {
     fn into_bytes(&self) -> Vec<u8> {
         let mut output = self.chunk_num.to_be_bytes().to_vec();
         output.extend_from_slice(&self.data);
+        if let Some(crc) = self.crc {
+            output.extend_from_slice(&crc.to_be_bytes());
+        }
         output
     }
 }
@@ -205,6 +542,129 @@ impl Request for SendChunkRequest {
     type Response = SendChunkResponse;
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct SendChunkResponse(pub u16);
 impl Response for SendChunkResponse {}
+
+/// CRC-32 (ISO-HDLC) of `size` bytes at `offset` into the image last uploaded, as written to
+/// flash. Not part of the bootloader spec, the opcode 0x0D and the request layout are unverified
+/// guesses.
+#[cfg(feature = "experimental")]
+pub struct GetChecksumRequest {
+    pub offset: u32,
+    pub size: u32,
+}
+#[cfg(feature = "experimental")]
+impl IntoBytes for GetChecksumRequest {
+    fn into_bytes(&self) -> Vec<u8> {
+        let mut output = self.offset.to_be_bytes().to_vec();
+        output.extend_from_slice(&self.size.to_be_bytes());
+        output
+    }
+}
+#[cfg(feature = "experimental")]
+impl Request for GetChecksumRequest {
+    const COMMAND: u8 = 0x0D;
+    type Response = GetChecksumResponse;
+}
+
+#[cfg(feature = "experimental")]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetChecksumResponse(pub u32);
+#[cfg(feature = "experimental")]
+impl Response for GetChecksumResponse {}
+
+/// `size` bytes at `offset` into the image last uploaded, read back from flash. Not part of the
+/// bootloader spec, the opcode 0x0E and the request layout are unverified guesses.
+#[cfg(feature = "experimental")]
+pub struct ReadFlashRequest {
+    pub offset: u32,
+    pub size: u8,
+}
+#[cfg(feature = "experimental")]
+impl ReadFlashRequest {
+    /// Most a single read can return, the length of a response frame is a single byte.
+    pub const MAX_SIZE: usize = u8::MAX as usize;
+}
+#[cfg(feature = "experimental")]
+impl IntoBytes for ReadFlashRequest {
+    fn into_bytes(&self) -> Vec<u8> {
+        let mut output = self.offset.to_be_bytes().to_vec();
+        output.push(self.size);
+        output
+    }
+}
+#[cfg(feature = "experimental")]
+impl Request for ReadFlashRequest {
+    const COMMAND: u8 = 0x0E;
+    type Response = ReadFlashResponse;
+}
+
+#[cfg(feature = "experimental")]
+#[derive(Debug)]
+pub struct ReadFlashResponse(pub Vec<u8>);
+#[cfg(feature = "experimental")]
+impl FromBytes for ReadFlashResponse {
+    type Error = Error;
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, Self::Error> {
+        Ok(ReadFlashResponse(bytes.to_vec()))
+    }
+}
+#[cfg(feature = "experimental")]
+impl Response for ReadFlashResponse {}
+
+/// Every request opcode, any new `Request` belongs here too so it's checked below.
+const OPCODES: &[(u8, &str)] = &[
+    (ResendLastRequest::<ConnectResponse>::COMMAND, "ResendLast"),
+    (ResetRequest::COMMAND, "Reset"),
+    (ConnectRequest::COMMAND, "Connect"),
+    (GetVersionRequest::COMMAND, "GetVersion"),
+    (GetDevIDRequest::COMMAND, "GetDevID"),
+    (GetHWRevRequest::COMMAND, "GetHWRev"),
+    #[cfg(feature = "experimental")]
+    (GetImageCrcRequest::COMMAND, "GetImageCrc"),
+    (GetSerialNumberRequest::COMMAND, "GetSerialNumber"),
+    (GetDeviceNameRequest::COMMAND, "GetDeviceName"),
+    #[cfg(feature = "experimental")]
+    (GetFlashInfoRequest::COMMAND, "GetFlashInfo"),
+    #[cfg(feature = "experimental")]
+    (GetChecksumRequest::COMMAND, "GetChecksum"),
+    #[cfg(feature = "experimental")]
+    (ReadFlashRequest::COMMAND, "ReadFlash"),
+    (GetFWStatusRequest::COMMAND, "GetFWStatus"),
+    #[cfg(feature = "experimental")]
+    (GetResetReasonRequest::COMMAND, "GetResetReason"),
+    (StartUploadRequest::COMMAND, "StartUpload"),
+    (SendChunkRequest::COMMAND, "SendChunk"),
+];
+
+// Fails the build on two requests sharing an opcode, or one not fitting the 6 bits left next to
+// the ack flag in a response
+const _: () = {
+    let mut i = 0;
+    while i < OPCODES.len() {
+        assert!(
+            OPCODES[i].0 & !ResponseFrame::COMMAND_MASK == 0,
+            "Opcode out of range"
+        );
+        let mut j = i + 1;
+        while j < OPCODES.len() {
+            assert!(OPCODES[i].0 != OPCODES[j].0, "Duplicate opcode");
+            j += 1;
+        }
+        i += 1;
+    }
+};
+
+/// Whether `command` is one of the opcodes above, e.g. to flag unknown frames while sniffing.
+pub fn known_opcode(command: u8) -> bool {
+    opcode_name(command).is_some()
+}
+
+pub fn opcode_name(command: u8) -> Option<&'static str> {
+    OPCODES
+        .iter()
+        .find(|(opcode, _)| *opcode == command)
+        .map(|(_, name)| *name)
+}
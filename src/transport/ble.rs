@@ -0,0 +1,362 @@
+use std::collections::VecDeque;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use btleplug::api::{
+    Central, CharPropFlags, Characteristic, Peripheral, PeripheralProperties, ScanFilter,
+    ValueNotification, WriteType,
+};
+use futures::{Future, Stream, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{self, Timeout};
+use uuid::Uuid;
+
+pub const UART_SERVICE_UUID: Uuid = Uuid::from_u128(0x0000FFF0_0000_1000_8000_00805F9B34FB);
+pub const UART_RX_CHAR_UUID: Uuid = Uuid::from_u128(0x0000FFF1_0000_1000_8000_00805F9B34FB);
+pub const UART_TX_CHAR_UUID: Uuid = Uuid::from_u128(0x0000FFF2_0000_1000_8000_00805F9B34FB);
+
+const SCAN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Scans until a peripheral whose advertised properties match `predicate` is discovered, or
+/// `max_duration` passes. Discovered peripherals are polled rather than followed through
+/// `Central::events`, which isn't reliable on every platform.
+pub async fn scan_for<A, F>(
+    adapter: &A,
+    predicate: F,
+    max_duration: Duration,
+) -> Result<Option<A::Peripheral>, anyhow::Error>
+where
+    A: Central,
+    F: Fn(&PeripheralProperties) -> bool,
+{
+    adapter.start_scan(ScanFilter::default()).await?;
+
+    let deadline = time::Instant::now() + max_duration;
+    let found = async {
+        loop {
+            for peripheral in adapter.peripherals().await? {
+                if let Some(properties) = peripheral.properties().await? {
+                    if predicate(&properties) {
+                        return Ok(Some(peripheral));
+                    }
+                }
+            }
+
+            let now = time::Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            time::sleep(std::cmp::min(SCAN_POLL_INTERVAL, deadline - now)).await;
+        }
+    }
+    .await;
+
+    adapter.stop_scan().await?;
+    found
+}
+
+pub struct DiscoveredDevice<P = btleplug::platform::Peripheral> {
+    pub peripheral: P,
+    pub name: Option<String>,
+    pub rssi: Option<i16>,
+}
+
+pub struct ScanOptions {
+    duration: Duration,
+    services: Vec<Uuid>,
+    min_rssi: Option<i16>,
+    sort_by_rssi: bool,
+}
+
+impl ScanOptions {
+    pub fn new(duration: Duration) -> ScanOptions {
+        ScanOptions {
+            duration,
+            services: vec![],
+            min_rssi: None,
+            sort_by_rssi: false,
+        }
+    }
+
+    /// Only report peripherals advertising every one of `services`.
+    pub fn services(mut self, services: Vec<Uuid>) -> Self {
+        self.services = services;
+        self
+    }
+
+    /// Drop peripherals weaker than `min_rssi` dBm, along with any that didn't report an RSSI.
+    pub fn min_rssi(mut self, min_rssi: i16) -> Self {
+        self.min_rssi = Some(min_rssi);
+        self
+    }
+
+    /// Report the strongest, usually nearest, peripheral first instead of in discovery order.
+    pub fn sort_by_rssi(mut self, sort_by_rssi: bool) -> Self {
+        self.sort_by_rssi = sort_by_rssi;
+        self
+    }
+}
+
+/// Scans for `options.duration` and reports every peripheral that passed the options' filters.
+pub async fn scan<A: Central>(
+    adapter: &A,
+    options: ScanOptions,
+) -> Result<Vec<DiscoveredDevice<A::Peripheral>>, anyhow::Error> {
+    adapter
+        .start_scan(ScanFilter {
+            services: options.services.clone(),
+        })
+        .await?;
+    time::sleep(options.duration).await;
+    let peripherals = adapter.peripherals().await;
+    adapter.stop_scan().await?;
+
+    let mut devices = vec![];
+    for peripheral in peripherals? {
+        let properties = match peripheral.properties().await? {
+            Some(properties) => properties,
+            None => continue,
+        };
+
+        // The scan filter is only a hint on some platforms
+        if !options
+            .services
+            .iter()
+            .all(|service| properties.services.contains(service))
+        {
+            continue;
+        }
+        if let Some(min_rssi) = options.min_rssi {
+            if !matches!(properties.rssi, Some(rssi) if rssi >= min_rssi) {
+                continue;
+            }
+        }
+
+        devices.push(DiscoveredDevice {
+            peripheral,
+            name: properties.local_name,
+            rssi: properties.rssi,
+        });
+    }
+
+    if options.sort_by_rssi {
+        devices.sort_by_key(|device| std::cmp::Reverse(device.rssi));
+    }
+
+    Ok(devices)
+}
+
+type WriteFuture = Pin<Box<dyn Future<Output = Result<(), btleplug::Error>> + Send>>;
+
+// The future owns clones of everything `Peripheral::write` borrows, so it can be stored next to
+// the peripheral it was created from without borrowing from it.
+fn write_task<P: Peripheral + 'static>(
+    periph: P,
+    characteristic: Characteristic,
+    buffer: Vec<u8>,
+    write_type: WriteType,
+) -> WriteFuture {
+    Box::pin(async move { periph.write(&characteristic, &buffer, write_type).await })
+}
+
+pub struct PeripheralStream<P = btleplug::platform::Peripheral> {
+    periph: P,
+    chars_rx: Vec<Characteristic>,
+    char_tx: Characteristic,
+    rx_stream: Pin<Box<dyn Stream<Item = ValueNotification> + Send>>,
+    rx_buffer: VecDeque<u8>,
+    /// Set once the notification stream ends, i.e. the peripheral disconnected.
+    rx_ended: bool,
+    write_timeout: Duration,
+    tx_write_task: Option<Pin<Box<Timeout<WriteFuture>>>>,
+}
+
+impl<P: Peripheral + 'static> PeripheralStream<P> {
+    pub async fn new(
+        periph: P,
+        service_uuid: Uuid,
+        rx_char_uuid: Uuid,
+        tx_char_uuid: Uuid,
+    ) -> Result<Self, anyhow::Error> {
+        PeripheralStream::with_rx_chars(periph, service_uuid, &[rx_char_uuid], tx_char_uuid).await
+    }
+
+    /// Like `new`, subscribing to every one of `rx_char_uuids` for profiles that split what the
+    /// device sends across several notify characteristics. Their notifications are read in the
+    /// order they arrive, notifications of any other characteristic are dropped.
+    pub async fn with_rx_chars(
+        periph: P,
+        service_uuid: Uuid,
+        rx_char_uuids: &[Uuid],
+        tx_char_uuid: Uuid,
+    ) -> Result<Self, anyhow::Error> {
+        if rx_char_uuids.is_empty() {
+            return Err(anyhow::anyhow!("No RX characteristic given"));
+        }
+        periph.connect().await?;
+        periph.discover_services().await?;
+
+        let mut chars_rx = vec![];
+        let mut char_tx = None;
+
+        for service in periph.services() {
+            if service.uuid == service_uuid {
+                for characteristic in service.characteristics {
+                    if rx_char_uuids.contains(&characteristic.uuid)
+                        && characteristic.properties.contains(CharPropFlags::NOTIFY)
+                    {
+                        periph.subscribe(&characteristic).await?;
+                        chars_rx.push(characteristic);
+                    } else if characteristic.uuid == tx_char_uuid {
+                        char_tx = Some(characteristic);
+                    }
+                }
+            }
+        }
+
+        if let Some(uuid) = rx_char_uuids.iter().find(|uuid| {
+            !chars_rx
+                .iter()
+                .any(|characteristic| characteristic.uuid == **uuid)
+        }) {
+            return Err(anyhow::anyhow!("RX characteristic {} not found", uuid));
+        }
+        // In the order asked for, so `rx_characteristic` is the first
+        chars_rx.sort_by_key(|characteristic| {
+            rx_char_uuids
+                .iter()
+                .position(|uuid| *uuid == characteristic.uuid)
+        });
+
+        let rx_uuids = rx_char_uuids.to_vec();
+        let rx_stream = periph
+            .notifications()
+            .await?
+            .filter(move |notification| {
+                futures::future::ready(rx_uuids.contains(&notification.uuid))
+            })
+            .boxed();
+
+        Ok(PeripheralStream {
+            periph,
+            chars_rx,
+            char_tx: char_tx.ok_or_else(|| anyhow::anyhow!("TX characteristic not found"))?,
+            rx_stream,
+            rx_buffer: VecDeque::new(),
+            rx_ended: false,
+            write_timeout: Duration::from_secs(5),
+            tx_write_task: None,
+        })
+    }
+
+    /// Fail a write with `io::ErrorKind::TimedOut` if the BLE stack hasn't completed it within
+    /// `write_timeout`.
+    pub fn write_timeout(mut self, write_timeout: Duration) -> Self {
+        self.write_timeout = write_timeout;
+        self
+    }
+
+    pub fn peripheral(&self) -> &P {
+        &self.periph
+    }
+
+    pub fn rx_characteristic(&self) -> &Characteristic {
+        &self.chars_rx[0]
+    }
+
+    /// Every characteristic subscribed to, see `with_rx_chars`.
+    pub fn rx_characteristics(&self) -> &[Characteristic] {
+        &self.chars_rx
+    }
+
+    pub fn tx_characteristic(&self) -> &Characteristic {
+        &self.char_tx
+    }
+
+    pub fn clear_rx_buffer(&mut self) {
+        self.rx_buffer.clear();
+    }
+}
+
+impl<P: Peripheral + Unpin + 'static> AsyncWrite for PeripheralStream<P> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        let write_task = this.tx_write_task.get_or_insert_with(|| {
+            let write_task = write_task(
+                this.periph.clone(),
+                this.char_tx.clone(),
+                buf.to_vec(),
+                WriteType::WithoutResponse,
+            );
+            Box::pin(tokio::time::timeout(this.write_timeout, write_task))
+        });
+
+        match write_task.as_mut().poll(cx) {
+            Poll::Ready(Ok(Ok(_))) => {
+                this.tx_write_task = None;
+                Poll::Ready(Ok(buf.len()))
+            }
+            Poll::Ready(Ok(Err(e))) => {
+                this.tx_write_task = None;
+                Poll::Ready(Err(io::Error::other(e)))
+            }
+            Poll::Ready(Err(_)) => {
+                this.tx_write_task = None;
+                Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "BLE write timed out",
+                )))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+impl<P: Peripheral + Unpin + 'static> AsyncRead for PeripheralStream<P> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let stream = self.get_mut();
+
+        // Coalesce every notification that has already arrived, up to what the caller can take
+        while !stream.rx_ended && stream.rx_buffer.len() < buf.remaining() {
+            match stream.rx_stream.poll_next_unpin(cx) {
+                Poll::Ready(Some(data)) => stream.rx_buffer.extend(data.value),
+                Poll::Ready(None) => stream.rx_ended = true,
+                Poll::Pending => break,
+            }
+        }
+
+        let amount = std::cmp::min(stream.rx_buffer.len(), buf.remaining());
+        if amount > 0 {
+            let data = stream.rx_buffer.drain(0..amount).collect::<Vec<_>>();
+            buf.put_slice(&data);
+            Poll::Ready(Ok(()))
+        } else if stream.rx_ended {
+            // Nothing filled is EOF
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
+        }
+    }
+}
@@ -0,0 +1,6 @@
+#[cfg(feature = "ble")]
+pub mod ble;
+#[cfg(feature = "serial")]
+pub mod serial;
+#[cfg(feature = "tcp")]
+pub mod tcp;
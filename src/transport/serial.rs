@@ -0,0 +1,23 @@
+use crate::error::Error;
+
+pub use tokio_serial::{SerialPortInfo, SerialPortType, UsbPortInfo};
+
+/// USB vendor and product IDs of the USB-serial bridge (FTDI FT-X) in OBDLink adapters.
+pub const STN_USB_IDS: [(u16, u16); 1] = [(0x0403, 0x6015)];
+
+/// Serial ports present on the system, for presenting a picker instead of requiring a path.
+pub fn available_ports() -> Result<Vec<SerialPortInfo>, Error> {
+    tokio_serial::available_ports().map_err(|err| Error::IOError(err.into()))
+}
+
+/// Keeps the USB ports among `ports` whose vendor and product ID are in `usb_ids`, e.g.
+/// `STN_USB_IDS`.
+pub fn usb_ports(ports: Vec<SerialPortInfo>, usb_ids: &[(u16, u16)]) -> Vec<SerialPortInfo> {
+    ports
+        .into_iter()
+        .filter(|port| match &port.port_type {
+            SerialPortType::UsbPort(usb) => usb_ids.contains(&(usb.vid, usb.pid)),
+            _ => false,
+        })
+        .collect()
+}
@@ -0,0 +1,36 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+use crate::error::Error;
+use crate::updater::Resetter;
+
+/// Time given to the device to restart after `ATZ` before it's probed.
+const RESET_SETTLE: Duration = Duration::from_millis(100);
+
+/// Connects to a TCP-to-serial bridge in front of the device.
+pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<TcpStream, Error> {
+    let stream = TcpStream::connect(addr).await?;
+    // Frames are small and every one is waited on, don't hold them back for coalescing
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+/// Resets the device with `ATZ`, as a bridge usually has no way to toggle DTR. This only works
+/// while the application firmware is running, which is the only time `Updater::connect` resets.
+pub struct TcpResetter;
+
+#[async_trait]
+impl Resetter for TcpResetter {
+    type Device = TcpStream;
+
+    async fn reset(device: &mut Self::Device) -> anyhow::Result<()> {
+        device.write_all(b"ATZ\r").await?;
+        device.flush().await?;
+        // The echo and banner are skipped by the decoder as garbage ahead of the first frame
+        tokio::time::sleep(RESET_SETTLE).await;
+        Ok(())
+    }
+}
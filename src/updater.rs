@@ -1,24 +1,373 @@
+use crate::capture::Recorder;
 use crate::codec::{RequestFrame, ResponseFrame};
-use crate::firmware::FirmwareImage;
+use crate::firmware::{Capabilities, FirmwareImage};
 use crate::protocol::{
-    ConnectRequest, ConnectResponse, GetDevIDRequest, GetDevIDResponse, GetHWRevRequest,
-    GetHWRevResponse, GetSerialNumberRequest, GetSerialNumberResponse, Request, ResendLastRequest,
-    ResetRequest, Response, SendChunkRequest, SendChunkResponse, StartUploadRequest,
-    StartUploadResponse,
+    ConnectRequest, ConnectResponse, FirmwareStatus, GetDevIDRequest, GetDevIDResponse,
+    GetDeviceNameRequest, GetDeviceNameResponse, GetFWStatusRequest, GetHWRevRequest,
+    GetHWRevResponse, GetSerialNumberRequest, GetSerialNumberResponse, GetVersionRequest,
+    GetVersionResponse, Request, ResendLastRequest, ResetRequest, Response, SendChunkRequest,
+    SendChunkResponse, StartUploadRequest, StartUploadResponse, UploadMode,
+};
+#[cfg(feature = "experimental")]
+use crate::protocol::{
+    GetChecksumRequest, GetChecksumResponse, GetFlashInfoRequest, GetFlashInfoResponse,
+    GetImageCrcRequest, GetImageCrcResponse, GetResetReasonRequest, ReadFlashRequest,
+    ReadFlashResponse, ResetReason,
 };
 use async_trait::async_trait;
-use futures::{sink::SinkExt, StreamExt};
+use bytes::BytesMut;
+use futures::future::BoxFuture;
+use futures::{sink::SinkExt, FutureExt, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::ControlFlow;
 use std::time::Duration;
-use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
 use tokio::time;
 use tokio_util::codec::{Decoder, Encoder, Framed};
 
+/// How far the index in a chunk ack may be from the chunk sent before it's a desync.
+const CHUNK_ACK_WINDOW: usize = 1;
+
+/// Sent ahead of the first `ConnectRequest` for an auto-baud device to measure, its alternating
+/// bits give the most edges to time.
+const AUTO_BAUD_BYTE: u8 = 0x55;
+/// Time an auto-baud device gets to configure its UART after `AUTO_BAUD_BYTE`.
+const AUTO_BAUD_SETTLE: Duration = Duration::from_millis(10);
+
+/// Chunk sizes are a multiple of this, and `Updater::pad_final_chunk` pads up to it.
+const CHUNK_ALIGNMENT: usize = 16;
+
+/// A later image negotiating a chunk size this many times smaller than an earlier one is worth a
+/// warning, it tends to point at trouble with that region of flash.
+const CHUNK_SIZE_DROP_RATIO: usize = 2;
+
+/// Transport initialization run by `Updater::connect` ahead of its first probe, see
+/// `Updater::pre_connect`.
+pub type PreConnectHook<T> =
+    Box<dyn for<'a> FnMut(&'a mut T) -> BoxFuture<'a, anyhow::Result<()>> + Send>;
+
+/// Patches an image in place before it's uploaded, see `Updater::transform`.
+pub type ImageTransform = Box<dyn FnMut(usize, &mut [u8]) + Send>;
+
 #[async_trait]
 pub trait Resetter {
     type Device;
     async fn reset(device: &mut Self::Device) -> anyhow::Result<()>;
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProgressEvent {
+    Connected,
+    DeviceInfo {
+        device_id: u16,
+    },
+    ChunkProgress {
+        image_idx: usize,
+        num_images: usize,
+        chunk_idx: usize,
+        num_chunks: usize,
+        /// Position across every image uploaded, for a single progress bar that doesn't start
+        /// over with each image.
+        overall_chunk_idx: usize,
+        /// Estimated from the chunk size in use, so it can change when the next image is started
+        /// with a different one. `overall_chunk_idx` never goes back.
+        overall_num_chunks: usize,
+    },
+    ImageComplete {
+        image_idx: usize,
+    },
+    Done,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct UploadReport {
+    /// Chunk transmissions beyond the first, summed over every chunk.
+    pub chunk_retries: usize,
+    /// Most retries needed by any single chunk.
+    pub max_chunk_retries: usize,
+    /// `ResendLastRequest`s issued, keyed by the command being resent.
+    pub resends: BTreeMap<u8, usize>,
+    /// Images skipped by `continue_on_image_error`, with the error they failed with.
+    pub failed_images: BTreeMap<usize, String>,
+    /// Whether the device was reset out of the bootloader once done, see `reset_on_complete`.
+    pub reset: bool,
+    /// Whether nothing was uploaded as the device already holds the firmware, see
+    /// `skip_if_current`.
+    #[cfg(feature = "experimental")]
+    pub skipped: bool,
+    /// Round trip of the connect handshake, see `Updater::link_rtt`.
+    pub link_rtt: Option<Duration>,
+    /// Uploads started over after failing verification, see `Updater::verify_retries`.
+    #[cfg(feature = "experimental")]
+    pub verify_retries: usize,
+    /// Every image the upload got to, in the order they were uploaded.
+    pub images: Vec<ImageReport>,
+}
+
+/// How the upload of a single image went, see `UploadReport::images`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ImageReport {
+    pub image_idx: usize,
+    /// Bytes acked by the device, padding included.
+    pub bytes_written: usize,
+    /// Chunk transmissions beyond the first, summed over the image's chunks.
+    pub chunk_retries: usize,
+    /// Most retries needed by any of the image's chunks.
+    pub max_chunk_retries: usize,
+    /// Chunk size negotiated for the image, `None` if nothing was sent.
+    pub chunk_size: Option<usize>,
+    /// Largest chunk size negotiated for an earlier image, if this image's is less than half of
+    /// it.
+    pub chunk_size_dropped_from: Option<usize>,
+    /// Why the image failed, for images the upload carried on past.
+    pub error: Option<String>,
+}
+
+/// Everything the bootloader reports about the device, from `Updater::device_info`. Only the
+/// device ID is required, the other queries are `None` if the device doesn't answer them.
+#[derive(Debug)]
+pub struct DeviceInfo {
+    pub device_id: u16,
+    pub bootloader_version: Option<(u8, u8)>,
+    pub hw_version: Option<(u8, u8)>,
+    pub serial_number: Option<String>,
+    pub device_name: Option<String>,
+    #[cfg(feature = "experimental")]
+    pub flash_info: Option<GetFlashInfoResponse>,
+}
+
+/// Bootloader version and application status together, from `Updater::firmware_state`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FirmwareState {
+    pub bootloader_version: (u8, u8),
+    pub status: FirmwareStatus,
+}
+
+/// Runs `future`, failing with `Error::DeadlineExceeded` if `deadline` passes first.
+async fn within<F: std::future::Future>(
+    deadline: Option<time::Instant>,
+    future: F,
+) -> Result<F::Output, crate::error::Error> {
+    match deadline {
+        Some(deadline) => time::timeout_at(deadline, future)
+            .await
+            .map_err(|_| crate::error::Error::DeadlineExceeded),
+        None => Ok(future.await),
+    }
+}
+
+/// Sizes of the images uploaded from `image_idx` on, following `next_idx`.
+fn chain_sizes(firmware: &FirmwareImage, mut image_idx: u8) -> Vec<u32> {
+    let mut image_sizes = vec![];
+    while image_idx != 0xFF && image_sizes.len() < firmware.descriptors.len() {
+        image_sizes.push(firmware.descriptors[image_idx as usize].image_size);
+        image_idx = firmware.descriptors[image_idx as usize].next_idx;
+    }
+    image_sizes
+}
+
+/// Throughput and latency of the link to the device, for `UploadPlan::estimated_duration`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinkParams {
+    /// Bytes per second the link carries in either direction.
+    pub bytes_per_sec: u32,
+    /// Time from a request being sent to its response arriving, on top of the time the frames
+    /// spend on the wire, e.g. the device writing a chunk to flash.
+    pub round_trip: Duration,
+}
+
+impl LinkParams {
+    /// A serial link at `baud`, 8N1 framing taking 10 bits per byte.
+    pub fn serial(baud: u32, round_trip: Duration) -> Self {
+        LinkParams {
+            bytes_per_sec: baud / 10,
+            round_trip,
+        }
+    }
+}
+
+/// What an upload of a container sends, for estimating it before connecting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UploadPlan {
+    /// Sizes of the images uploaded when every image succeeds, in upload order.
+    pub image_sizes: Vec<u32>,
+    pub chunk_size: usize,
+}
+
+impl UploadPlan {
+    /// STX pair, command, 2 byte length, CRC and ETX, ignoring byte stuffing.
+    const REQUEST_OVERHEAD: u64 = 8;
+    /// Same as a request, with a single length byte.
+    const RESPONSE_OVERHEAD: u64 = 7;
+
+    /// `chunk_size` is the one the upload ends up using, i.e. the smaller of
+    /// `Updater::chunk_size` and what the device offers. Fails with `Error::InvalidChunkSize` if
+    /// it's 0.
+    pub fn new(
+        firmware: &FirmwareImage,
+        chunk_size: usize,
+    ) -> Result<UploadPlan, crate::error::Error> {
+        if chunk_size == 0 {
+            return Err(crate::error::Error::InvalidChunkSize { chunk_size });
+        }
+        Ok(UploadPlan {
+            image_sizes: chain_sizes(firmware, firmware.root_idx() as u8),
+            chunk_size,
+        })
+    }
+
+    pub fn num_chunks(&self) -> usize {
+        self.image_sizes
+            .iter()
+            .map(|&size| (size as usize).div_ceil(self.chunk_size))
+            .sum()
+    }
+
+    /// Every exchange, one `StartUploadRequest` per non-empty image and one `SendChunkRequest`
+    /// per chunk, costs a `round_trip` plus its request and response bytes at `bytes_per_sec`.
+    /// Retries, verification and connecting aren't included.
+    pub fn estimated_duration(&self, link: LinkParams) -> Duration {
+        // Image size, mode / chunk number
+        const START_UPLOAD: u64 =
+            4 + UploadPlan::REQUEST_OVERHEAD + 2 + UploadPlan::RESPONSE_OVERHEAD;
+        const CHUNK: u64 = 2 + UploadPlan::REQUEST_OVERHEAD + 2 + UploadPlan::RESPONSE_OVERHEAD;
+
+        let images = self.image_sizes.iter().filter(|&&size| size > 0).count() as u64;
+        let chunks = self.num_chunks() as u64;
+        let data = self
+            .image_sizes
+            .iter()
+            .map(|&size| size as u64)
+            .sum::<u64>();
+
+        let bytes = images * START_UPLOAD + chunks * CHUNK + data;
+        let exchanges = images + chunks;
+        let wire = Duration::from_nanos(bytes * 1_000_000_000 / link.bytes_per_sec.max(1) as u64);
+        wire + link.round_trip * exchanges as u32
+    }
+}
+
+/// What to tell an operator after a failed upload, from `Updater::recovery_hint`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryHint {
+    /// Nothing was erased or written, the device is as it was before the upload.
+    SafeToPowerCycle,
+    /// An image is erased or partially written, but the failure looks transient. Upload again, or
+    /// `resume`, before power cycling.
+    RetryRecommended,
+    /// An image is erased or partially written and the device refused it. Power cycling may leave
+    /// it without a bootable image.
+    DoNotPowerCycle,
+}
+
+impl RecoveryHint {
+    fn after(err: &crate::error::Error, erased: bool) -> RecoveryHint {
+        use crate::error::Error;
+
+        if !erased {
+            return RecoveryHint::SafeToPowerCycle;
+        }
+        match err {
+            Error::IOError(_)
+            | Error::BadCrc(_)
+            | Error::BadFrame(_)
+            | Error::UnexpectedStx(_)
+            | Error::Timeout
+            | Error::DeadlineExceeded
+            | Error::DeviceReset
+            | Error::Disconnected
+            | Error::Cancelled
+            | Error::ChunkDesync { .. } => RecoveryHint::RetryRecommended,
+            _ => RecoveryHint::DoNotPowerCycle,
+        }
+    }
+}
+
+/// Where an interrupted upload stopped, from `Updater::resume_state`. Serializable so it can be
+/// persisted and passed to `Updater::resume` by a restarted process.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResumeState {
+    pub device_id: u16,
+    /// Image the upload stopped in. It's uploaded again from its first chunk, as
+    /// `StartUploadRequest` erases whatever was written of it.
+    pub image_idx: usize,
+    /// Chunks of `image_idx` the device acked before the upload stopped.
+    pub acked_chunks: usize,
+    /// Chunk size negotiated for `image_idx`.
+    pub chunk_size: usize,
+}
+
+/// Return type of an `upload_firmware` progress callback. Callbacks returning `()` always
+/// continue, callbacks returning `ControlFlow::Break(())` cancel the upload.
+pub trait ProgressControl {
+    fn control_flow(self) -> ControlFlow<()>;
+}
+
+impl ProgressControl for () {
+    fn control_flow(self) -> ControlFlow<()> {
+        ControlFlow::Continue(())
+    }
+}
+
+impl ProgressControl for ControlFlow<()> {
+    fn control_flow(self) -> ControlFlow<()> {
+        self
+    }
+}
+
+#[async_trait]
+trait ProgressSink: Send {
+    async fn event(&mut self, event: ProgressEvent) -> ControlFlow<()>;
+}
+
+/// Adapts a `(chunk_idx, num_chunks)` callback to the signature `upload_firmware` takes, for
+/// callers that don't care which image is being uploaded.
+pub fn chunk_progress<C: ProgressControl>(
+    progress_cb: impl Fn(usize, usize) -> C + Send,
+) -> impl Fn(usize, usize, usize, usize) -> C + Send {
+    move |_, _, chunk_idx, num_chunks| progress_cb(chunk_idx, num_chunks)
+}
+
+struct CallbackProgress<F>(F);
+
+#[async_trait]
+impl<F, C> ProgressSink for CallbackProgress<F>
+where
+    F: Fn(usize, usize, usize, usize) -> C + Send,
+    C: ProgressControl,
+{
+    async fn event(&mut self, event: ProgressEvent) -> ControlFlow<()> {
+        if let ProgressEvent::ChunkProgress {
+            image_idx,
+            num_images,
+            chunk_idx,
+            num_chunks,
+            ..
+        } = event
+        {
+            (self.0)(image_idx, num_images, chunk_idx, num_chunks).control_flow()
+        } else {
+            ControlFlow::Continue(())
+        }
+    }
+}
+
+#[async_trait]
+impl ProgressSink for mpsc::Sender<ProgressEvent> {
+    async fn event(&mut self, event: ProgressEvent) -> ControlFlow<()> {
+        // Waiting for room between chunks could hold the upload up long enough for the device to
+        // time out, a later `ChunkProgress` supersedes a dropped one anyway
+        if let ProgressEvent::ChunkProgress { .. } = event {
+            let _ = self.try_send(event);
+        } else {
+            let _ = self.send(event).await;
+        }
+        ControlFlow::Continue(())
+    }
+}
+
 pub struct Updater<T, U>
 where
     T: AsyncRead + AsyncWrite,
@@ -29,9 +378,58 @@ where
     resend_retry: usize,
     chunk_retry: usize,
     connect_timeout: Duration,
+    connect_retry_timeout: Duration,
+    connect_wake_burst: Option<(usize, Duration)>,
+    auto_baud: bool,
+    open_settle_delay: Duration,
+    opened_at: time::Instant,
     request_timeout: Duration,
+    send_timeout: Duration,
     chunk_timeout: Duration,
+    chunk_timeout_rtt_factor: Option<u32>,
     chunk_size: usize,
+    chunk_crc: bool,
+    region_offsets: bool,
+    resend_on_chunk_desync: bool,
+    max_throughput_bytes_per_sec: Option<u32>,
+    #[cfg(feature = "experimental")]
+    check_flash_size: bool,
+    #[cfg(feature = "experimental")]
+    skip_if_current: bool,
+    #[cfg(feature = "experimental")]
+    verify: bool,
+    #[cfg(feature = "experimental")]
+    verify_readback: bool,
+    #[cfg(feature = "experimental")]
+    verify_retries: usize,
+    #[cfg(feature = "experimental")]
+    verify_chunk_size: usize,
+    reset_on_complete: bool,
+    program_mode: UploadMode,
+    validation_mode: Option<UploadMode>,
+    busy_retry: usize,
+    busy_retry_delay: Duration,
+    min_chunk_size: Option<usize>,
+    chunk_size_retry: usize,
+    pad_final_chunk: Option<u8>,
+    continue_on_image_error: bool,
+    capabilities: Capabilities,
+    known_device_id: Option<u16>,
+    allowed_device_ids: Option<BTreeSet<u16>>,
+    supported_hw_versions: Option<BTreeSet<(u8, u8)>>,
+    force: bool,
+    resume_state: Option<ResumeState>,
+    recovery_hint: Option<RecoveryHint>,
+    pending_at_timeout: Vec<u8>,
+    chunks_acked: usize,
+    region_erased: bool,
+    deadline: Option<time::Instant>,
+    recorder: Option<Box<dyn Recorder>>,
+    pre_connect: Option<PreConnectHook<T>>,
+    transform: Option<ImageTransform>,
+    link_rtt: Option<Duration>,
+    upload_in_progress: bool,
+    resends: BTreeMap<u8, usize>,
 }
 
 impl<T, U> Updater<T, U>
@@ -47,39 +445,525 @@ where
             resend_retry: 5,
             chunk_retry: 5,
             connect_timeout: Duration::from_secs(1),
+            connect_retry_timeout: Duration::from_millis(200),
+            connect_wake_burst: None,
+            auto_baud: false,
+            open_settle_delay: Duration::from_millis(100),
+            opened_at: time::Instant::now(),
             request_timeout: Duration::from_millis(200),
+            send_timeout: Duration::from_secs(5),
             chunk_timeout: Duration::from_secs(5),
+            chunk_timeout_rtt_factor: None,
             chunk_size: 1024,
+            chunk_crc: false,
+            region_offsets: false,
+            resend_on_chunk_desync: false,
+            max_throughput_bytes_per_sec: None,
+            #[cfg(feature = "experimental")]
+            check_flash_size: false,
+            #[cfg(feature = "experimental")]
+            skip_if_current: false,
+            #[cfg(feature = "experimental")]
+            verify: false,
+            #[cfg(feature = "experimental")]
+            verify_readback: false,
+            #[cfg(feature = "experimental")]
+            verify_retries: 0,
+            #[cfg(feature = "experimental")]
+            verify_chunk_size: ReadFlashRequest::MAX_SIZE,
+            reset_on_complete: true,
+            program_mode: UploadMode::Program,
+            validation_mode: None,
+            busy_retry: 0,
+            busy_retry_delay: Duration::from_millis(100),
+            min_chunk_size: None,
+            chunk_size_retry: 1,
+            pad_final_chunk: None,
+            continue_on_image_error: false,
+            capabilities: Capabilities::default(),
+            known_device_id: None,
+            allowed_device_ids: None,
+            supported_hw_versions: None,
+            force: false,
+            resume_state: None,
+            recovery_hint: None,
+            pending_at_timeout: vec![],
+            chunks_acked: 0,
+            region_erased: false,
+            deadline: None,
+            recorder: None,
+            pre_connect: None,
+            transform: None,
+            link_rtt: None,
+            upload_in_progress: false,
+            resends: BTreeMap::new(),
         }
     }
 
-    async fn inner_recv_response<R: Request>(
+    /// Probes sent after resetting the device, if it didn't answer the first one. Zero means a
+    /// single probe and no reset.
+    pub fn connect_retry(mut self, connect_retry: usize) -> Self {
+        self.connect_retry = connect_retry;
+        self
+    }
+
+    pub fn resend_retry(mut self, resend_retry: usize) -> Self {
+        self.resend_retry = resend_retry;
+        self
+    }
+
+    pub fn chunk_retry(mut self, chunk_retry: usize) -> Self {
+        self.chunk_retry = chunk_retry;
+        self
+    }
+
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    pub fn connect_retry_timeout(mut self, connect_retry_timeout: Duration) -> Self {
+        self.connect_retry_timeout = connect_retry_timeout;
+        self
+    }
+
+    /// Probe with `count` `ConnectRequest`s, `interval` apart, before falling back to resetting
+    /// the device, to catch the short bootloader window after power-on. By default a single
+    /// probe waits for `connect_timeout`.
+    pub fn connect_wake_burst(mut self, count: usize, interval: Duration) -> Self {
+        self.connect_wake_burst = Some((count, interval));
+        self
+    }
+
+    /// Send a calibration byte and give the device a moment before probing, for auto-baud
+    /// bootloaders that use up the first byte received to detect the baud rate.
+    pub fn auto_baud(mut self, auto_baud: bool) -> Self {
+        self.auto_baud = auto_baud;
+        self
+    }
+
+    /// Time the device gets to boot after the transport is opened, i.e. after `Updater::new`,
+    /// before `connect` or `enter_bootloader` send anything. Covers USB-serial adapters that
+    /// reset the device on open. Only the part not already spent is waited for. 100 ms by
+    /// default.
+    pub fn open_settle_delay(mut self, open_settle_delay: Duration) -> Self {
+        self.open_settle_delay = open_settle_delay;
+        self
+    }
+
+    pub fn request_timeout(mut self, request_timeout: Duration) -> Self {
+        self.request_timeout = request_timeout;
+        self
+    }
+
+    /// How long writing a frame to the transport may take before failing with `Timeout`, so a
+    /// wedged write half can't hang an upload.
+    pub fn send_timeout(mut self, send_timeout: Duration) -> Self {
+        self.send_timeout = send_timeout;
+        self
+    }
+
+    pub fn chunk_timeout(mut self, chunk_timeout: Duration) -> Self {
+        self.chunk_timeout = chunk_timeout;
+        self
+    }
+
+    /// Raise `chunk_timeout` to `factor` times the `link_rtt` measured while connecting, when
+    /// that's longer, so slow links don't need a hand-tuned timeout.
+    pub fn chunk_timeout_rtt_factor(mut self, factor: u32) -> Self {
+        self.chunk_timeout_rtt_factor = Some(factor);
+        self
+    }
+
+    fn effective_chunk_timeout(&self) -> Duration {
+        match (self.chunk_timeout_rtt_factor, self.link_rtt) {
+            (Some(factor), Some(link_rtt)) => self.chunk_timeout.max(link_rtt * factor),
+            _ => self.chunk_timeout,
+        }
+    }
+
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Start uploads with `StartUploadRequest::chunk_crc` and append a CRC to every chunk, so the
+    /// device can NACK corrupted chunks with `SendChunkRequest::BAD_CRC` and have them resent.
+    /// Off by default, the wire format is unverified so only enable it for a bootloader known to
+    /// implement it.
+    pub fn chunk_crc(mut self, chunk_crc: bool) -> Self {
+        self.chunk_crc = chunk_crc;
+        self
+    }
+
+    /// Start every image's upload with its descriptor's `image_offset` as the
+    /// `StartUploadRequest::target_offset`, for bootloaders that program non-contiguous regions
+    /// of flash laid out as in the container. Off by default, the field's layout is unverified.
+    pub fn region_offsets(mut self, region_offsets: bool) -> Self {
+        self.region_offsets = region_offsets;
+        self
+    }
+
+    /// Resend a chunk the device acked with an index far from the one sent, rather than failing
+    /// with `Error::ChunkDesync`. Acks of a neighbouring chunk are always resent.
+    pub fn resend_on_chunk_desync(mut self, resend_on_chunk_desync: bool) -> Self {
+        self.resend_on_chunk_desync = resend_on_chunk_desync;
+        self
+    }
+
+    /// Delay chunks so they're sent no faster than this, for links that overrun the device's
+    /// receive buffer. Unlimited by default, or when 0.
+    pub fn max_throughput_bytes_per_sec(mut self, max_throughput_bytes_per_sec: u32) -> Self {
+        self.max_throughput_bytes_per_sec =
+            Some(max_throughput_bytes_per_sec).filter(|&rate| rate > 0);
+        self
+    }
+
+    /// Flash even if the device ID isn't one of the firmware's `device_ids`, instead of failing
+    /// with `Error::DeviceMismatch`. Only meant for recovering a device whose ID reads back
+    /// corrupt, `allowed_device_ids` still applies. Off by default.
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    /// Only ever upload to these devices, on top of the firmware's own `device_ids`, failing with
+    /// `Error::DeviceNotAllowed` otherwise. Unrestricted by default.
+    pub fn allowed_device_ids(mut self, allowed_device_ids: impl IntoIterator<Item = u16>) -> Self {
+        self.allowed_device_ids = Some(allowed_device_ids.into_iter().collect());
+        self
+    }
+
+    /// Only upload to boards whose `hw_version` is one of these, failing with
+    /// `Error::UnsupportedHardware` otherwise. Checked after the device ID, unrestricted by
+    /// default.
+    pub fn supported_hw_versions(
+        mut self,
+        supported_hw_versions: impl IntoIterator<Item = (u8, u8)>,
+    ) -> Self {
+        self.supported_hw_versions = Some(supported_hw_versions.into_iter().collect());
+        self
+    }
+
+    /// Query `flash_info` before uploading and reject images that don't fit, each at its
+    /// `image_offset` and padded if `pad_final_chunk` is set. Off by default as not every
+    /// bootloader implements the query. Experimental, see `flash_info`.
+    #[cfg(feature = "experimental")]
+    pub fn check_flash_size(mut self, check_flash_size: bool) -> Self {
+        self.check_flash_size = check_flash_size;
+        self
+    }
+
+    /// Skip uploading altogether if `firmware_is_current`, reporting it in
+    /// `UploadReport::skipped`. Doesn't apply to `resume`. Experimental, see `stored_image_crc`.
+    #[cfg(feature = "experimental")]
+    pub fn skip_if_current(mut self, skip_if_current: bool) -> Self {
+        self.skip_if_current = skip_if_current;
+        self
+    }
+
+    /// Compare `device_checksum` of every programmed image against `FirmwareImage::crc32` once
+    /// it's uploaded, catching writes that were acked but didn't stick. See also
+    /// `verify_readback`. Experimental, see `device_checksum`.
+    #[cfg(feature = "experimental")]
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// Verify by reading every image back with `read_flash` and comparing it byte for byte,
+    /// rather than by checksum. Only takes effect along with `verify`. Experimental, see
+    /// `read_flash`.
+    #[cfg(feature = "experimental")]
+    pub fn verify_readback(mut self, verify_readback: bool) -> Self {
+        self.verify_readback = verify_readback;
+        self
+    }
+
+    /// Start the whole upload over from the first image up to `verify_retries` times when an image
+    /// fails verification, for flash writes that didn't stick the first time. None by default,
+    /// and only takes effect along with `verify`.
+    #[cfg(feature = "experimental")]
+    pub fn verify_retries(mut self, verify_retries: usize) -> Self {
+        self.verify_retries = verify_retries;
+        self
+    }
+
+    /// Bytes read back per `read_flash` when verifying, independent of the upload chunk size and
+    /// capped at `ReadFlashRequest::MAX_SIZE`.
+    #[cfg(feature = "experimental")]
+    pub fn verify_chunk_size(mut self, verify_chunk_size: usize) -> Self {
+        self.verify_chunk_size = verify_chunk_size.clamp(1, ReadFlashRequest::MAX_SIZE);
+        self
+    }
+
+    /// Reset the device into the application once every image is uploaded. Disable to leave it in
+    /// the bootloader for further requests.
+    pub fn reset_on_complete(mut self, reset_on_complete: bool) -> Self {
+        self.reset_on_complete = reset_on_complete;
+        self
+    }
+
+    /// Mode used for normal images, `UploadMode::Program` by default.
+    pub fn program_mode(mut self, program_mode: UploadMode) -> Self {
+        self.program_mode = program_mode;
+        self
+    }
+
+    /// Mode used for validation images, e.g. `UploadMode::Verify` for bootloaders known to compare
+    /// them against flash. Sent with `UploadMode::Program` like every other image by default.
+    pub fn validation_mode(mut self, validation_mode: UploadMode) -> Self {
+        self.validation_mode = Some(validation_mode);
+        self
+    }
+
+    /// Times `start_upload` is retried while the device reports itself busy or locked, see
+    /// `StartUploadRequest::BUSY`. None by default, as those statuses are unverified.
+    pub fn busy_retry(mut self, busy_retry: usize) -> Self {
+        self.busy_retry = busy_retry;
+        self
+    }
+
+    pub fn busy_retry_delay(mut self, busy_retry_delay: Duration) -> Self {
+        self.busy_retry_delay = busy_retry_delay;
+        self
+    }
+
+    /// Smallest chunk size worth flashing with. A device offering less from `start_upload` is
+    /// asked again up to `chunk_size_retry` times, `busy_retry_delay` apart, before failing with
+    /// `ChunkSizeTooSmall`.
+    pub fn min_chunk_size(mut self, min_chunk_size: usize) -> Self {
+        self.min_chunk_size = Some(min_chunk_size);
+        self
+    }
+
+    pub fn chunk_size_retry(mut self, chunk_size_retry: usize) -> Self {
+        self.chunk_size_retry = chunk_size_retry;
+        self
+    }
+
+    /// Pad every image with `fill` up to the next 16 byte boundary, for flash writers that only
+    /// take whole pages. The size sent with `start_upload` includes the padding. Off by default.
+    pub fn pad_final_chunk(mut self, fill: u8) -> Self {
+        self.pad_final_chunk = Some(fill);
+        self
+    }
+
+    /// Record images the device rejects in `UploadReport::failed_images` and carry on with
+    /// `next_idx`, instead of failing the whole upload. Images of the tolerate-errors type still
+    /// follow their `error_idx`.
+    pub fn continue_on_image_error(mut self, continue_on_image_error: bool) -> Self {
+        self.continue_on_image_error = continue_on_image_error;
+        self
+    }
+
+    /// What the device's bootloader supports, the firmware is checked against it before anything
+    /// is uploaded. Defaults to everything this crate can upload.
+    pub fn capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    /// Hands every frame sent and received to `recorder`, e.g. a `FileRecorder` capturing a
+    /// session for a support case.
+    pub fn recorder(mut self, recorder: impl Recorder + 'static) -> Self {
+        self.recorder = Some(Box::new(recorder));
+        self
+    }
+
+    /// Runs `hook` on the transport at the start of every `connect`, before the first probe, for
+    /// hardware needing a wake sequence such as a break condition, an auto-baud byte or a magic
+    /// string. A failing hook fails the connect.
+    pub fn pre_connect<F>(mut self, hook: F) -> Self
+    where
+        F: for<'a> FnMut(&'a mut T) -> BoxFuture<'a, anyhow::Result<()>> + Send + 'static,
+    {
+        self.pre_connect = Some(Box::new(hook));
+        self
+    }
+
+    /// Runs `transform` with the index and a copy of the data of every image before it's
+    /// uploaded, e.g. to write a serial number or calibration data into it. The image keeps its
+    /// length, and verification checks the patched data. `firmware_is_current` still compares
+    /// against the unpatched image.
+    pub fn transform<F>(mut self, transform: F) -> Self
+    where
+        F: FnMut(usize, &mut [u8]) + Send + 'static,
+    {
+        self.transform = Some(Box::new(transform));
+        self
+    }
+
+    fn upload_mode(&self, image_type: u8) -> UploadMode {
+        match image_type {
+            // Validation
+            0x10 => self.validation_mode.unwrap_or(UploadMode::Program),
+            _ => self.program_mode,
+        }
+    }
+
+    /// An absolute cut-off for everything that follows, connecting, queries and uploads alike.
+    /// Every response timeout is shortened to end at the deadline at the latest, and once it has
+    /// passed requests fail with `DeadlineExceeded` without being sent. Resets, retry delays and
+    /// `drain_input` are cut off by it too. `None` lifts it.
+    pub fn set_deadline(&mut self, deadline: Option<time::Instant>) {
+        self.deadline = deadline;
+    }
+
+    pub fn deadline(&self) -> Option<time::Instant> {
+        self.deadline
+    }
+
+    /// `timeout` shortened to the deadline, along with whether it was.
+    fn bounded_timeout(&self, timeout: Duration) -> Result<(Duration, bool), crate::error::Error> {
+        match self.deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(time::Instant::now());
+                if remaining.is_zero() {
+                    Err(crate::error::Error::DeadlineExceeded)
+                } else if remaining <= timeout {
+                    Ok((remaining, true))
+                } else {
+                    Ok((timeout, false))
+                }
+            }
+            None => Ok((timeout, false)),
+        }
+    }
+
+    /// Whether an upload was started and neither completed nor followed by a `reset`, i.e. the
+    /// device may be sitting in the bootloader with a partially written image.
+    pub fn upload_in_progress(&self) -> bool {
+        self.upload_in_progress
+    }
+
+    /// Round trip of the last `ConnectRequest` answered, from sending it to its response being
+    /// decoded.
+    pub fn link_rtt(&self) -> Option<Duration> {
+        self.link_rtt
+    }
+
+    /// Where the last upload stopped, `None` if it completed or none was started.
+    pub fn resume_state(&self) -> Option<&ResumeState> {
+        self.resume_state.as_ref()
+    }
+
+    /// How safe it is to power cycle the device after the last upload failed, `None` if it
+    /// succeeded or none was started.
+    pub fn recovery_hint(&self) -> Option<RecoveryHint> {
+        self.recovery_hint
+    }
+
+    /// Bytes left undecoded when the last receive timed out, e.g. a frame cut off part way. They
+    /// are dropped from the read buffer, this keeps them for diagnostics. Empty if there were
+    /// none, or nothing timed out yet.
+    pub fn pending_at_timeout(&self) -> &[u8] {
+        &self.pending_at_timeout
+    }
+
+    /// The codec frames are decoded with, e.g. to read `SerialCodec`'s line-quality counters.
+    pub fn codec(&self) -> &U {
+        self.framed.codec()
+    }
+
+    /// The underlying transport, for out-of-band I/O such as AT commands between operations.
+    ///
+    /// Only use it while no frames are buffered, i.e. between completed requests. Bytes read here
+    /// are never seen by the codec and anything already buffered by it is not visible here, so
+    /// interleaving the two is undefined.
+    pub fn transport_mut(&mut self) -> &mut T {
+        self.framed.get_mut()
+    }
+
+    /// Raw frames as they arrive, bypassing the request/response pairing.
+    pub fn frames(
+        &mut self,
+    ) -> impl Stream<Item = Result<ResponseFrame, crate::error::Error>> + '_ {
+        &mut self.framed
+    }
+
+    fn clear_read_buffer(&mut self) {
+        self.framed.read_buffer_mut().clear();
+        // Lets decoders that keep state across calls see that any partial frame is gone
+        let _ = self.framed.codec_mut().decode(&mut BytesMut::new());
+    }
+
+    async fn send_frame(&mut self, frame: RequestFrame) -> Result<(), crate::error::Error> {
+        let (timeout, at_deadline) = self.bounded_timeout(self.send_timeout)?;
+        if let Some(recorder) = &mut self.recorder {
+            recorder.on_tx(&frame);
+        }
+        match time::timeout(timeout, self.framed.send(frame)).await {
+            Ok(result) => result,
+            Err(_) if at_deadline => Err(crate::error::Error::DeadlineExceeded),
+            Err(_) => Err(crate::error::Error::Timeout),
+        }
+    }
+
+    async fn recv_frame(
         &mut self,
         timeout: Duration,
-    ) -> Result<R::Response, crate::error::Error> {
-        let now = time::Instant::now();
-        loop {
-            let elapsed = now.elapsed();
-            if elapsed >= timeout {
-                self.framed.read_buffer_mut().clear();
-                return Err(crate::error::Error::Timeout);
+    ) -> Result<ResponseFrame, crate::error::Error> {
+        let (timeout, at_deadline) = self.bounded_timeout(timeout)?;
+        match tokio::time::timeout(timeout, self.framed.next()).await {
+            Ok(Some(Ok(response_frame))) => {
+                if let Some(recorder) = &mut self.recorder {
+                    recorder.on_rx(&response_frame);
+                }
+                Ok(response_frame)
             }
-
-            match tokio::time::timeout(timeout - elapsed, self.framed.next()).await {
-                Ok(frame) => {
-                    if let Some(Ok(response_frame)) = frame {
-                        let response: R::Response = Response::from_frame::<R>(response_frame)?;
-                        return Ok(response);
-                    }
+            Ok(Some(Err(err))) => {
+                // `Framed` ends the stream after any decoder error, the next poll only clears
+                // that state and is ready immediately
+                let _ = self.framed.next().now_or_never();
+                Err(err)
+            }
+            Ok(None) => Err(crate::error::Error::Disconnected),
+            Err(_) => {
+                self.pending_at_timeout = self.framed.read_buffer().to_vec();
+                #[cfg(feature = "tracing")]
+                if !self.pending_at_timeout.is_empty() {
+                    tracing::debug!(
+                        pending = ?self.pending_at_timeout,
+                        "Receive timed out part way through a frame"
+                    );
                 }
-                Err(_) => {
-                    self.framed.read_buffer_mut().clear();
-                    return Err(crate::error::Error::Timeout);
+                self.clear_read_buffer();
+                if at_deadline {
+                    Err(crate::error::Error::DeadlineExceeded)
+                } else {
+                    Err(crate::error::Error::Timeout)
                 }
             }
         }
     }
 
+    async fn inner_recv_response<R: Request>(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<R::Response, crate::error::Error> {
+        let response_frame = self.recv_frame(timeout).await?;
+        Response::from_frame::<R>(response_frame)
+    }
+
+    /// Collects frames of a multi-frame response until `is_last` returns true for one, which is
+    /// included. Every frame has to arrive within `timeout` of the previous one.
+    pub async fn recv_until<F: Fn(&ResponseFrame) -> bool>(
+        &mut self,
+        timeout: Duration,
+        is_last: F,
+    ) -> Result<Vec<ResponseFrame>, crate::error::Error> {
+        let mut frames = vec![];
+        loop {
+            let frame = self.recv_frame(timeout).await?;
+            let last = is_last(&frame);
+            frames.push(frame);
+            if last {
+                return Ok(frames);
+            }
+        }
+    }
+
     pub async fn recv_response<R: Request>(
         &mut self,
         timeout: Duration,
@@ -87,15 +971,20 @@ where
     ) -> Result<R::Response, crate::error::Error> {
         let mut response = self.inner_recv_response::<R>(timeout).await;
 
-        let mut index = 0;
-        while let Err(crate::error::Error::Timeout) = response {
-            self.framed
-                .send(ResendLastRequest::<R::Response>::new().frame())
-                .await?;
-            response = self.inner_recv_response::<R>(timeout).await;
-
-            index += 1;
-            if index >= resend_retry {
+        for _ in 0..resend_retry {
+            // A corrupted response is as good as a lost one, anything else is final
+            if let Err(
+                crate::error::Error::Timeout
+                | crate::error::Error::BadCrc(_)
+                | crate::error::Error::BadFrame(_)
+                | crate::error::Error::UnexpectedStx(_),
+            ) = response
+            {
+                self.send_frame(ResendLastRequest::<R::Response>::new().frame())
+                    .await?;
+                *self.resends.entry(R::COMMAND).or_default() += 1;
+                response = self.inner_recv_response::<R>(timeout).await;
+            } else {
                 break;
             }
         }
@@ -109,31 +998,160 @@ where
         timeout: Duration,
         resend_retry: usize,
     ) -> Result<R::Response, crate::error::Error> {
-        self.framed.send(request.frame()).await?;
+        self.send_frame(request.frame()).await?;
         self.recv_response::<R>(timeout, resend_retry).await
     }
 
+    /// Reads and discards input until nothing has arrived for `quiet_for`, returning the number
+    /// of bytes discarded. Useful before `connect` on a link that may hold stale chatter.
+    pub async fn drain_input(&mut self, quiet_for: Duration) -> Result<usize, crate::error::Error> {
+        let mut discarded = self.framed.read_buffer().len();
+        self.clear_read_buffer();
+
+        let mut buf = [0u8; 256];
+        loop {
+            let read = time::timeout(quiet_for, self.framed.get_mut().read(&mut buf));
+            match within(self.deadline, read).await? {
+                Ok(Ok(0)) => return Err(crate::error::Error::Disconnected),
+                Ok(Ok(count)) => discarded += count,
+                Ok(Err(err)) => return Err(err.into()),
+                Err(_) => return Ok(discarded),
+            }
+        }
+    }
+
+    /// Establishes a bootloader session.
+    ///
+    /// The `pre_connect` hook runs first, if set.
+    ///
+    /// A single `ConnectRequest` is sent and given `connect_timeout` to be answered, covering a
+    /// device that is already in the bootloader. Otherwise the device is reset with `D` and
+    /// probed up to `connect_retry` times, each probe waiting `connect_retry_timeout`. Connect
+    /// probes are never followed by `ResendLastRequest`, a fresh probe is sent instead.
+    ///
+    /// If a device ID was read earlier in this session it's read again after a reset, and a
+    /// different ID fails with `DeviceIdMismatch`.
     pub async fn connect<D: Resetter<Device = T>>(&mut self) -> Result<(), crate::error::Error> {
-        if let Ok(ConnectResponse) = self.transmit(ConnectRequest, self.connect_timeout, 0).await {
-            Ok(())
-        } else {
-            D::reset(self.framed.get_mut()).await?;
-            for _ in 0..self.connect_retry {
-                if let Ok(ConnectResponse) = self
-                    .transmit(ConnectRequest, Duration::from_millis(50), 0)
-                    .await
-                {
-                    return Ok(());
-                }
+        self.settle_after_open().await?;
+        if let Some(pre_connect) = &mut self.pre_connect {
+            within(self.deadline, pre_connect(self.framed.get_mut())).await??;
+        }
+
+        if self.auto_baud {
+            let device = self.framed.get_mut();
+            device.write_all(&[AUTO_BAUD_BYTE]).await?;
+            device.flush().await?;
+            within(self.deadline, time::sleep(AUTO_BAUD_SETTLE)).await?;
+        }
+
+        let probe = match self.connect_wake_burst {
+            Some((count, interval)) => self.wake_burst(count, interval).await,
+            None => self.connect_assume_bootloader().await,
+        };
+        match probe {
+            Ok(()) => Ok(()),
+            Err(
+                err @ (crate::error::Error::Disconnected | crate::error::Error::DeadlineExceeded),
+            ) => Err(err),
+            Err(err) if self.connect_retry == 0 => Err(err),
+            Err(_) => self.enter_bootloader::<D>().await,
+        }
+    }
+
+    /// Resets the device from the application into the bootloader with `D`, then probes it up to
+    /// `connect_retry` times (at least once), each probe waiting `connect_retry_timeout`. The
+    /// session is established once this returns, so any number of operations can follow a single
+    /// entry. Unlike `connect` it always resets, so only use it on a device in application mode.
+    ///
+    /// If a device ID was read earlier in this session it's read again, and a different ID fails
+    /// with `DeviceIdMismatch`.
+    pub async fn enter_bootloader<D: Resetter<Device = T>>(
+        &mut self,
+    ) -> Result<(), crate::error::Error> {
+        self.settle_after_open().await?;
+        within(self.deadline, D::reset(self.framed.get_mut())).await??;
+        for _ in 0..self.connect_retry.max(1) {
+            match self.probe(self.connect_retry_timeout).await {
+                Ok(()) => return self.confirm_device_id().await,
+                Err(
+                    err @ (crate::error::Error::Disconnected
+                    | crate::error::Error::DeadlineExceeded),
+                ) => return Err(err),
+                Err(_) => {}
             }
-            Err(crate::error::Error::Timeout)
+        }
+        Err(crate::error::Error::Timeout)
+    }
+
+    /// Sends a single `ConnectRequest` without ever resetting the device, for devices put into
+    /// the bootloader by other means (e.g. a programming jig).
+    pub async fn connect_assume_bootloader(&mut self) -> Result<(), crate::error::Error> {
+        self.settle_after_open().await?;
+        self.probe(self.connect_timeout).await
+    }
+
+    /// Whether the device is already in the bootloader, from a single `ConnectRequest` given
+    /// `connect_retry_timeout` to be answered. Never resets the device, so the caller can decide
+    /// whether `enter_bootloader` is needed. Only an unanswered probe counts as `false`.
+    pub async fn is_in_bootloader(&mut self) -> Result<bool, crate::error::Error> {
+        self.settle_after_open().await?;
+        match self.probe(self.connect_retry_timeout).await {
+            Ok(()) => Ok(true),
+            Err(crate::error::Error::Timeout) => Ok(false),
+            Err(err) => Err(err),
         }
     }
 
+    async fn settle_after_open(&self) -> Result<(), crate::error::Error> {
+        within(
+            self.deadline,
+            time::sleep_until(self.opened_at + self.open_settle_delay),
+        )
+        .await
+    }
+
+    /// A single `ConnectRequest`, timing the round trip into `link_rtt` if it's answered.
+    async fn probe(&mut self, timeout: Duration) -> Result<(), crate::error::Error> {
+        let sent = time::Instant::now();
+        let ConnectResponse = self.transmit(ConnectRequest, timeout, 0).await?;
+        self.link_rtt = Some(sent.elapsed());
+        Ok(())
+    }
+
+    async fn wake_burst(
+        &mut self,
+        count: usize,
+        interval: Duration,
+    ) -> Result<(), crate::error::Error> {
+        let mut error = crate::error::Error::Timeout;
+        for _ in 0..count {
+            match self.probe(interval).await {
+                Ok(()) => return Ok(()),
+                Err(
+                    err @ (crate::error::Error::Disconnected
+                    | crate::error::Error::DeadlineExceeded),
+                ) => return Err(err),
+                Err(err) => error = err,
+            }
+        }
+        Err(error)
+    }
+
+    async fn confirm_device_id(&mut self) -> Result<(), crate::error::Error> {
+        if let Some(expected) = self.known_device_id {
+            let actual = self.device_id().await?;
+            if actual != expected {
+                return Err(crate::error::Error::DeviceIdMismatch { expected, actual });
+            }
+        }
+        Ok(())
+    }
+
     pub async fn device_id(&mut self) -> Result<u16, crate::error::Error> {
         let GetDevIDResponse(device_id) = self
             .transmit(GetDevIDRequest, self.request_timeout, self.resend_retry)
             .await?;
+        self.known_device_id = Some(device_id);
         Ok(device_id)
     }
 
@@ -148,48 +1166,290 @@ where
         Ok(String::from_utf8_lossy(&serial).to_string())
     }
 
-    pub async fn hw_version(&mut self) -> Result<(u8, u8), crate::error::Error> {
-        let GetHWRevResponse { major, minor } = self
-            .transmit(GetHWRevRequest, self.request_timeout, self.resend_retry)
+    pub async fn device_name(&mut self) -> Result<String, crate::error::Error> {
+        let GetDeviceNameResponse { name } = self
+            .transmit(
+                GetDeviceNameRequest,
+                self.request_timeout,
+                self.resend_retry,
+            )
+            .await?;
+        Ok(name)
+    }
+
+    /// Relies on `GetFlashInfoRequest`, whose opcode is unverified.
+    #[cfg(feature = "experimental")]
+    pub async fn flash_info(&mut self) -> Result<GetFlashInfoResponse, crate::error::Error> {
+        self.transmit(GetFlashInfoRequest, self.request_timeout, self.resend_retry)
+            .await
+    }
+
+    /// `(major, minor)` of the bootloader protocol the device speaks.
+    pub async fn bootloader_version(&mut self) -> Result<(u8, u8), crate::error::Error> {
+        let GetVersionResponse { major, minor } = self
+            .transmit(GetVersionRequest, self.request_timeout, self.resend_retry)
             .await?;
         Ok((major, minor))
     }
 
-    pub async fn start_upload(&mut self, image_size: u32) -> Result<u16, crate::error::Error> {
-        let StartUploadResponse(max_chunk_size) = self
+    pub async fn firmware_status(&mut self) -> Result<FirmwareStatus, crate::error::Error> {
+        let response = self
+            .transmit(GetFWStatusRequest, self.request_timeout, self.resend_retry)
+            .await?;
+        Ok(response.status())
+    }
+
+    /// Relies on `GetResetReasonRequest`, whose opcode is unverified.
+    #[cfg(feature = "experimental")]
+    pub async fn reset_reason(&mut self) -> Result<ResetReason, crate::error::Error> {
+        let response = self
             .transmit(
-                StartUploadRequest {
-                    image_size,
-                    mode: 1,
+                GetResetReasonRequest,
+                self.request_timeout,
+                self.resend_retry,
+            )
+            .await?;
+        Ok(response.reason())
+    }
+
+    /// `bootloader_version` and `firmware_status` in one go, for showing e.g. "bootloader 1.2,
+    /// application invalid" without correlating the two.
+    pub async fn firmware_state(&mut self) -> Result<FirmwareState, crate::error::Error> {
+        Ok(FirmwareState {
+            bootloader_version: self.bootloader_version().await?,
+            status: self.firmware_status().await?,
+        })
+    }
+
+    /// CRC-32 the device computes over `region` of the image it was last sent. Relies on
+    /// `GetChecksumRequest`, whose opcode is unverified.
+    #[cfg(feature = "experimental")]
+    pub async fn device_checksum(
+        &mut self,
+        region: std::ops::Range<u32>,
+    ) -> Result<u32, crate::error::Error> {
+        let GetChecksumResponse(checksum) = self
+            .transmit(
+                GetChecksumRequest {
+                    offset: region.start,
+                    size: region.end - region.start,
                 },
                 self.request_timeout,
                 self.resend_retry,
             )
             .await?;
-        Ok(max_chunk_size)
+        Ok(checksum)
     }
 
-    pub async fn send_chunk(
+    /// Reads `size` bytes at `offset` into the image it was last sent back from flash. The device
+    /// may return fewer near the end of the image. Relies on `ReadFlashRequest`, whose opcode is
+    /// unverified.
+    #[cfg(feature = "experimental")]
+    pub async fn read_flash(
         &mut self,
-        index: usize,
-        chunk: &[u8],
+        offset: u32,
+        size: u8,
+    ) -> Result<Vec<u8>, crate::error::Error> {
+        let ReadFlashResponse(data) = self
+            .transmit(
+                ReadFlashRequest { offset, size },
+                self.request_timeout,
+                self.resend_retry,
+            )
+            .await?;
+        Ok(data)
+    }
+
+    /// Runs every device query within a single `budget`, tolerating the optional ones being
+    /// rejected, unsupported or cut off by the budget running out. The deadline set with
+    /// `set_deadline` still applies if it's earlier.
+    pub async fn device_info(
+        &mut self,
+        budget: Duration,
+    ) -> Result<DeviceInfo, crate::error::Error> {
+        let deadline = self.deadline;
+        let budget_deadline = time::Instant::now() + budget;
+        self.deadline =
+            Some(deadline.map_or(budget_deadline, |deadline| deadline.min(budget_deadline)));
+        let info = self.device_info_queries().await;
+        self.deadline = deadline;
+        info
+    }
+
+    async fn device_info_queries(&mut self) -> Result<DeviceInfo, crate::error::Error> {
+        fn optional<V>(
+            result: Result<V, crate::error::Error>,
+        ) -> Result<Option<V>, crate::error::Error> {
+            match result {
+                Ok(value) => Ok(Some(value)),
+                Err(
+                    crate::error::Error::InvalidCommand(_)
+                    | crate::error::Error::InvalidResponse(_)
+                    | crate::error::Error::BinCode(_)
+                    | crate::error::Error::Timeout
+                    | crate::error::Error::DeadlineExceeded,
+                ) => Ok(None),
+                Err(err) => Err(err),
+            }
+        }
+
+        Ok(DeviceInfo {
+            device_id: self.device_id().await?,
+            bootloader_version: optional(self.bootloader_version().await)?,
+            hw_version: optional(self.hw_version().await)?,
+            serial_number: optional(self.serial_number().await)?,
+            device_name: optional(self.device_name().await)?,
+            #[cfg(feature = "experimental")]
+            flash_info: optional(self.flash_info().await)?,
+        })
+    }
+
+    /// Relies on `GetImageCrcRequest`, whose opcode is unverified.
+    #[cfg(feature = "experimental")]
+    pub async fn stored_image_crc(&mut self) -> Result<GetImageCrcResponse, crate::error::Error> {
+        self.transmit(GetImageCrcRequest, self.request_timeout, self.resend_retry)
+            .await
+    }
+
+    /// Whether the image the device last had programmed is the last image `firmware` programs,
+    /// by size and CRC-32. A stronger check than comparing versions, as it covers the image
+    /// actually written. Experimental, see `stored_image_crc`.
+    #[cfg(feature = "experimental")]
+    pub async fn firmware_is_current(
+        &mut self,
+        firmware: &FirmwareImage,
+    ) -> Result<bool, crate::error::Error> {
+        let mut last_programmed = None;
+        let mut idx = firmware.root_idx() as u8;
+        let mut count = 0;
+        while idx != 0xFF && count < firmware.descriptors.len() {
+            let descriptor = &firmware.descriptors[idx as usize];
+            // Validation images are never written
+            if descriptor.image_type != 0x10 && descriptor.image_size > 0 {
+                last_programmed = Some(idx as usize);
+            }
+            idx = descriptor.next_idx;
+            count += 1;
+        }
+        let Some(image_idx) = last_programmed else {
+            return Ok(false);
+        };
+
+        let stored = self.stored_image_crc().await?;
+        Ok(stored.size == firmware.descriptors[image_idx].image_size
+            && stored.crc == firmware.crc32(image_idx))
+    }
+
+    pub async fn hw_version(&mut self) -> Result<(u8, u8), crate::error::Error> {
+        let GetHWRevResponse { major, minor } = self
+            .transmit(GetHWRevRequest, self.request_timeout, self.resend_retry)
+            .await?;
+        Ok((major, minor))
+    }
+
+    pub async fn start_upload(
+        &mut self,
+        image_size: u32,
+        mode: UploadMode,
     ) -> Result<u16, crate::error::Error> {
-        let mut error = crate::error::Error::Placeholder;
-        for _ in 0..self.chunk_retry {
+        self.start_upload_at(image_size, mode, None).await
+    }
+
+    /// Like `start_upload`, with the `StartUploadRequest::target_offset` the image goes to.
+    pub async fn start_upload_at(
+        &mut self,
+        image_size: u32,
+        mode: UploadMode,
+        target_offset: Option<u32>,
+    ) -> Result<u16, crate::error::Error> {
+        let mut busy_retry = self.busy_retry;
+        let mut chunk_size_retry = self.chunk_size_retry;
+        loop {
             match self
                 .transmit(
-                    SendChunkRequest {
-                        chunk_num: index as u16,
-                        data: chunk.to_vec(),
+                    StartUploadRequest {
+                        image_size,
+                        mode,
+                        chunk_crc: self.chunk_crc,
+                        target_offset,
                     },
-                    self.chunk_timeout,
+                    self.request_timeout,
                     self.resend_retry,
                 )
                 .await
+            {
+                Ok(StartUploadResponse { max_chunk_size, .. }) => {
+                    // The device erases the region as soon as it accepts the upload
+                    if mode != UploadMode::Verify {
+                        self.region_erased = true;
+                    }
+                    match self.min_chunk_size {
+                        Some(floor) if (max_chunk_size as usize) < floor => {
+                            if chunk_size_retry == 0 {
+                                return Err(crate::error::Error::ChunkSizeTooSmall {
+                                    offered: max_chunk_size,
+                                    floor,
+                                });
+                            }
+                            chunk_size_retry -= 1;
+                            within(self.deadline, time::sleep(self.busy_retry_delay)).await?;
+                        }
+                        _ => return Ok(max_chunk_size),
+                    }
+                }
+                Err(crate::error::Error::InvalidResponse(frame))
+                    if busy_retry > 0
+                        && matches!(
+                            frame.data.first(),
+                            Some(&StartUploadRequest::BUSY | &StartUploadRequest::LOCKED)
+                        ) =>
+                {
+                    busy_retry -= 1;
+                    within(self.deadline, time::sleep(self.busy_retry_delay)).await?;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    pub async fn send_chunk(
+        &mut self,
+        index: usize,
+        chunk: &[u8],
+    ) -> Result<u16, crate::error::Error> {
+        let (response_index, _) = self.send_chunk_attempts(index, chunk).await?;
+        Ok(response_index)
+    }
+
+    /// Same as `send_chunk`, also returning how many transmissions it took.
+    async fn send_chunk_attempts(
+        &mut self,
+        index: usize,
+        chunk: &[u8],
+    ) -> Result<(u16, usize), crate::error::Error> {
+        let mut error = crate::error::Error::Placeholder;
+        for attempt in 1..=self.chunk_retry {
+            let request = if self.chunk_crc {
+                SendChunkRequest::with_crc(index as u16, chunk.to_vec())
+            } else {
+                SendChunkRequest {
+                    chunk_num: index as u16,
+                    data: chunk.to_vec(),
+                    crc: None,
+                }
+            };
+            // A NACK, e.g. `SendChunkRequest::BAD_CRC`, fails the attempt and the chunk is resent
+            match self
+                .transmit(request, self.effective_chunk_timeout(), self.resend_retry)
+                .await
             {
                 Ok(SendChunkResponse(response_index)) => {
-                    return Ok(response_index);
+                    return Ok((response_index, attempt));
                 }
+                Err(
+                    err
+                    @ (crate::error::Error::DeadlineExceeded | crate::error::Error::DeviceReset),
+                ) => return Err(err),
                 Err(err) => {
                     error = err;
                 }
@@ -200,71 +1460,575 @@ where
 
     pub async fn reset(&mut self) -> Result<(), crate::error::Error> {
         let _ = self.transmit(ResetRequest, self.request_timeout, 0).await?;
+        self.upload_in_progress = false;
         Ok(())
     }
 
-    pub async fn upload_firmware<D: Resetter<Device = T>>(
+    /// Resets out of the bootloader before failing with `err`. A failed reset is only logged, so
+    /// it doesn't hide `err`.
+    async fn reset_after(&mut self, err: crate::error::Error) -> crate::error::Error {
+        if let Err(_reset_err) = self.reset().await {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(error = ?_reset_err, "Reset after a failed upload failed");
+        }
+        err
+    }
+
+    /// Flashes `firmware`, calling `progress_cb` with the image index, image count, chunk index
+    /// and chunk count after each chunk. A callback returning `ControlFlow::Break(())` resets the
+    /// device and fails the upload with `Error::Cancelled`. The chunk index starts over with each
+    /// image, the `ProgressEvent::ChunkProgress` of `upload_firmware_with_channel` also has the
+    /// position across all of them.
+    ///
+    /// `firmware` is only borrowed, so one image can be flashed to several devices in turn.
+    ///
+    /// A device that restarts mid-upload fails it with `Error::DeviceReset`, after which it can be
+    /// restarted, or picked up again with `resume` from `resume_state`.
+    pub async fn upload_firmware<D: Resetter<Device = T>, C: ProgressControl>(
         &mut self,
-        firmware: FirmwareImage,
-        progress_cb: impl Fn(usize, usize) -> (),
-    ) -> Result<(), crate::error::Error> {
+        firmware: &FirmwareImage,
+        progress_cb: impl Fn(usize, usize, usize, usize) -> C + Send,
+    ) -> Result<UploadReport, crate::error::Error> {
+        self.upload::<D, _>(firmware, None, &mut CallbackProgress(progress_cb))
+            .await
+    }
+
+    /// Continues an upload of `firmware` that stopped at `state`, e.g. in another process,
+    /// skipping the images already uploaded. Fails with `Error::DeviceIdMismatch` if a different
+    /// device is connected.
+    pub async fn resume<D: Resetter<Device = T>, C: ProgressControl>(
+        &mut self,
+        firmware: &FirmwareImage,
+        state: ResumeState,
+        progress_cb: impl Fn(usize, usize, usize, usize) -> C + Send,
+    ) -> Result<UploadReport, crate::error::Error> {
+        self.upload::<D, _>(firmware, Some(state), &mut CallbackProgress(progress_cb))
+            .await
+    }
+
+    /// Same as `upload_firmware`, but reports progress as `ProgressEvent`s sent on `events`.
+    ///
+    /// The upload carries on if the receiving end of the channel has been dropped.
+    /// `ProgressEvent::ChunkProgress` is dropped rather than waited for when the channel is full,
+    /// so a slow consumer can't stall the upload between chunks. The other events are waited for.
+    pub async fn upload_firmware_with_channel<D: Resetter<Device = T>>(
+        &mut self,
+        firmware: &FirmwareImage,
+        mut events: mpsc::Sender<ProgressEvent>,
+    ) -> Result<UploadReport, crate::error::Error> {
+        self.upload::<D, _>(firmware, None, &mut events).await
+    }
+
+    async fn upload<D: Resetter<Device = T>, P: ProgressSink>(
+        &mut self,
+        firmware: &FirmwareImage,
+        resume: Option<ResumeState>,
+        progress: &mut P,
+    ) -> Result<UploadReport, crate::error::Error> {
+        // A resumed upload may have erased or written the device in an earlier session, even if
+        // no chunk of it was acked
+        let resumed = resume.is_some();
+        self.chunks_acked = 0;
+        self.region_erased = false;
+        // Summed over every attempt of `verify_retries`
+        self.resends.clear();
+
+        #[cfg(feature = "experimental")]
+        let result = self
+            .upload_retrying_verification::<D, _>(firmware, resume, progress)
+            .await;
+        #[cfg(not(feature = "experimental"))]
+        let result = self.upload_chain::<D, _>(firmware, resume, progress).await;
+        self.recovery_hint = result
+            .as_ref()
+            .err()
+            .map(|err| RecoveryHint::after(err, resumed || self.region_erased));
+        result
+    }
+
+    /// `upload_chain`, started over up to `verify_retries` times when verification fails.
+    #[cfg(feature = "experimental")]
+    async fn upload_retrying_verification<D: Resetter<Device = T>, P: ProgressSink>(
+        &mut self,
+        firmware: &FirmwareImage,
+        mut resume: Option<ResumeState>,
+        progress: &mut P,
+    ) -> Result<UploadReport, crate::error::Error> {
+        // Retries start over from scratch, not from `resume`
+        let mut verify_retries = 0;
+        loop {
+            match self
+                .upload_chain::<D, _>(firmware, resume.take(), progress)
+                .await
+            {
+                Err(
+                    err @ (crate::error::Error::VerificationFailed { .. }
+                    | crate::error::Error::ReadbackMismatch { .. }),
+                ) => {
+                    if verify_retries == self.verify_retries {
+                        break Err(err);
+                    }
+                    verify_retries += 1;
+                    #[cfg(feature = "tracing")]
+                    tracing::warn!(
+                        error = ?err,
+                        retry = verify_retries,
+                        "Verification failed, starting the upload over"
+                    );
+                }
+                Ok(mut report) => {
+                    report.verify_retries = verify_retries;
+                    break Ok(report);
+                }
+                Err(err) => break Err(err),
+            }
+        }
+    }
+
+    async fn upload_chain<D: Resetter<Device = T>, P: ProgressSink>(
+        &mut self,
+        firmware: &FirmwareImage,
+        resume: Option<ResumeState>,
+        progress: &mut P,
+    ) -> Result<UploadReport, crate::error::Error> {
+        firmware.validate()?;
+        if let Some(state) = &resume {
+            if state.image_idx >= firmware.descriptors.len() {
+                return Err(crate::error::Error::Other(anyhow::anyhow!(
+                    "Resume state image {} out of range",
+                    state.image_idx
+                )));
+            }
+        }
+
+        let mut report = UploadReport::default();
+
         self.connect::<D>().await?;
+        self.upload_in_progress = true;
+        report.link_rtt = self.link_rtt;
+        let _ = progress.event(ProgressEvent::Connected).await;
+
         let device_id = self.device_id().await?;
+        let _ = progress
+            .event(ProgressEvent::DeviceInfo { device_id })
+            .await;
 
-        if firmware.device_ids.contains(&device_id) {
-            let mut image_idx = 0;
+        if let Some(state) = &resume {
+            if state.device_id != device_id {
+                return Err(crate::error::Error::DeviceIdMismatch {
+                    expected: state.device_id,
+                    actual: device_id,
+                });
+            }
+        }
 
-            loop {
-                let descriptor = &firmware.descriptors[image_idx];
-                let offset = descriptor.image_offset as usize;
-                let size = descriptor.image_size as usize;
-                let firmware_data = &firmware.data[offset..offset + size];
+        if !firmware.device_ids.contains(&device_id) && self.force {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(
+                device_id,
+                expected = ?firmware.device_ids,
+                "Device ID doesn't match the firmware, flashing anyway as forced"
+            );
+        } else if !firmware.device_ids.contains(&device_id) {
+            let err = crate::error::Error::DeviceMismatch {
+                device_id,
+                expected: firmware.device_ids.iter().copied().collect(),
+            };
+            return Err(self.reset_after(err).await);
+        }
 
-                let mut chunk_size = self.chunk_size;
-                let max_chunk_size = self.start_upload(firmware_data.len() as u32).await?;
+        if let Some(allowed_device_ids) = &self.allowed_device_ids {
+            if !allowed_device_ids.contains(&device_id) {
+                let err = crate::error::Error::DeviceNotAllowed { device_id };
+                return Err(self.reset_after(err).await);
+            }
+        }
 
-                // Rounded down to the nearest multiple of 16
-                chunk_size = (std::cmp::min(chunk_size as u16, max_chunk_size) & !15) as usize;
+        if let Some(supported_hw_versions) = self.supported_hw_versions.clone() {
+            let found = self.hw_version().await?;
+            if !supported_hw_versions.contains(&found) {
+                let err = crate::error::Error::UnsupportedHardware { found };
+                return Err(self.reset_after(err).await);
+            }
+        }
 
-                let num_chunks = (firmware_data.len() + chunk_size - 1) / chunk_size;
+        if let Err(err) = self.check_compatibility(firmware) {
+            return Err(self.reset_after(err).await);
+        }
 
-                for (idx, chunk) in firmware_data.chunks(chunk_size).enumerate() {
-                    for _ in 0..self.chunk_retry {
-                        let chunk_idx = self.send_chunk(idx, chunk).await?;
-                        if idx == chunk_idx as usize {
-                            break;
-                        }
-                    }
-                    progress_cb(idx, num_chunks);
+        #[cfg(feature = "experimental")]
+        if self.check_flash_size {
+            let GetFlashInfoResponse { size, .. } = self.flash_info().await?;
+            for (image_idx, descriptor) in firmware.descriptors.iter().enumerate() {
+                let image_size = match self.pad_final_chunk {
+                    Some(_) => descriptor
+                        .image_size
+                        .next_multiple_of(CHUNK_ALIGNMENT as u32),
+                    None => descriptor.image_size,
+                };
+                if descriptor.image_offset as u64 + image_size as u64 > size as u64 {
+                    let err = crate::error::Error::ImageTooLarge {
+                        image_idx,
+                        offset: descriptor.image_offset,
+                        size: image_size,
+                        available: size,
+                    };
+                    return Err(self.reset_after(err).await);
                 }
+            }
+        }
 
-                if descriptor.next_idx != 0xFF {
-                    match descriptor.image_type {
-                        // Normal
-                        0x00 => {
-                            image_idx = descriptor.next_idx as usize;
-                        }
+        #[cfg(feature = "experimental")]
+        if self.skip_if_current && resume.is_none() && self.firmware_is_current(firmware).await? {
+            report.skipped = true;
+            self.upload_in_progress = false;
+            if self.reset_on_complete {
+                self.reset().await?;
+                report.reset = true;
+            }
+            let _ = progress.event(ProgressEvent::Done).await;
+            return Ok(report);
+        }
 
-                        // Normal, Tolerate Errors
-                        0x01 => {
-                            // TODO: Implement
-                        }
+        let num_images = firmware.num_images();
+        let mut image_idx = firmware.root_idx();
+        // The resumed chunk size only applies to this upload
+        let mut chunk_size = self.chunk_size;
+        if let Some(state) = resume {
+            image_idx = state.image_idx;
+            chunk_size = state.chunk_size;
+        }
 
-                        // Validation
-                        0x10 => {
-                            // TODO: Implement
-                        }
+        loop {
+            let descriptor = &firmware.descriptors[image_idx];
+            let patched;
+            let firmware_data = match &mut self.transform {
+                Some(transform) if descriptor.image_size > 0 => {
+                    let mut data = firmware.image_data(image_idx).to_vec();
+                    transform(image_idx, &mut data);
+                    patched = data;
+                    &patched[..]
+                }
+                _ => firmware.image_data(image_idx),
+            };
+
+            self.resume_state = Some(ResumeState {
+                device_id,
+                image_idx,
+                acked_chunks: 0,
+                chunk_size,
+            });
+
+            let mut image_report = ImageReport {
+                image_idx,
+                ..ImageReport::default()
+            };
+            let result = if firmware_data.is_empty() {
+                // Only validation images get here, see `FirmwareImage::validate`
+                Ok(())
+            } else {
+                self.upload_image(
+                    (image_idx, num_images),
+                    firmware,
+                    firmware_data,
+                    chunk_size,
+                    progress,
+                    &mut image_report,
+                )
+                .await
+            };
+            let largest_chunk_size = report
+                .images
+                .iter()
+                .filter_map(|image| image.chunk_size)
+                .max();
+            match (largest_chunk_size, image_report.chunk_size) {
+                (Some(largest), Some(chunk_size))
+                    if chunk_size * CHUNK_SIZE_DROP_RATIO < largest =>
+                {
+                    #[cfg(feature = "tracing")]
+                    tracing::warn!(
+                        image_idx,
+                        chunk_size,
+                        earlier = largest,
+                        "Chunk size dropped from an earlier image"
+                    );
+                    image_report.chunk_size_dropped_from = Some(largest);
+                }
+                _ => {}
+            }
+            report.chunk_retries += image_report.chunk_retries;
+            report.max_chunk_retries =
+                std::cmp::max(report.max_chunk_retries, image_report.max_chunk_retries);
 
-                        _ => unreachable!(),
+            // Validation images are never written
+            #[cfg(feature = "experimental")]
+            let result = match result {
+                Ok(())
+                    if self.verify
+                        && descriptor.image_type != 0x10
+                        && self.upload_mode(descriptor.image_type) != UploadMode::Verify =>
+                {
+                    self.verify_image(image_idx, firmware_data).await
+                }
+                result => result,
+            };
+            if let Err(err) = &result {
+                image_report.error = Some(err.to_string());
+            }
+            report.images.push(image_report);
+
+            let result = match result {
+                Err(
+                    err @ (crate::error::Error::InvalidCommand(_)
+                    | crate::error::Error::InvalidResponse(_)
+                    | crate::error::Error::BinCode(_)
+                    | crate::error::Error::Timeout),
+                ) if self.continue_on_image_error && descriptor.image_type != 0x01 => {
+                    report.failed_images.insert(image_idx, err.to_string());
+                    Ok(())
+                }
+                result => result,
+            };
+
+            let next_idx = match descriptor.image_type {
+                // Normal
+                0x00 => {
+                    result?;
+                    descriptor.next_idx
+                }
+
+                // Normal, Tolerate Errors
+                0x01 => match result {
+                    Ok(()) => descriptor.next_idx,
+                    // Rejected by the device, carry on from the error image
+                    Err(crate::error::Error::InvalidResponse(_) | crate::error::Error::Timeout) => {
+                        descriptor.error_idx
                     }
-                } else {
+                    Err(err) => return Err(err),
+                },
+
+                // Validation
+                0x10 => {
+                    result?;
+                    descriptor.next_idx
+                }
+
+                image_type => {
+                    return Err(crate::error::Error::UnsupportedImageType {
+                        image_idx,
+                        image_type,
+                    })
+                }
+            };
+
+            if next_idx == 0xFF {
+                break;
+            }
+            image_idx = next_idx as usize;
+        }
+        self.resume_state = None;
+        self.upload_in_progress = false;
+
+        if self.reset_on_complete {
+            self.reset().await?;
+            report.reset = true;
+        }
+        let _ = progress.event(ProgressEvent::Done).await;
+
+        report.resends = std::mem::take(&mut self.resends);
+        Ok(report)
+    }
+
+    #[cfg(feature = "experimental")]
+    async fn verify_image(
+        &mut self,
+        image_idx: usize,
+        image: &[u8],
+    ) -> Result<(), crate::error::Error> {
+        if self.verify_readback {
+            return self.verify_image_readback(image_idx, image).await;
+        }
+
+        let expected = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC).checksum(image);
+        let actual = self.device_checksum(0..image.len() as u32).await?;
+        if actual != expected {
+            return Err(crate::error::Error::VerificationFailed {
+                image_idx,
+                expected,
+                actual,
+            });
+        }
+        Ok(())
+    }
+
+    /// Compares as it reads, so the image is never buffered twice.
+    #[cfg(feature = "experimental")]
+    async fn verify_image_readback(
+        &mut self,
+        image_idx: usize,
+        image: &[u8],
+    ) -> Result<(), crate::error::Error> {
+        for (chunk_idx, expected) in image.chunks(self.verify_chunk_size).enumerate() {
+            let chunk_offset = (chunk_idx * self.verify_chunk_size) as u32;
+            let actual = self.read_flash(chunk_offset, expected.len() as u8).await?;
+            // A short read counts as a mismatch at the first missing byte
+            if let Some(position) =
+                (0..expected.len()).find(|&idx| actual.get(idx) != Some(&expected[idx]))
+            {
+                return Err(crate::error::Error::ReadbackMismatch {
+                    image_idx,
+                    offset: chunk_offset + position as u32,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn check_compatibility(&self, firmware: &FirmwareImage) -> Result<(), crate::error::Error> {
+        firmware.check_compatibility(&self.capabilities)?;
+
+        // `FirmwareImage` assumes `UploadMode::Program`, images may be configured to be sent
+        // differently
+        for (image_idx, descriptor) in firmware.descriptors.iter().enumerate() {
+            let mode = self.upload_mode(descriptor.image_type);
+            if !self.capabilities.upload_modes.contains(&mode) {
+                return Err(crate::error::Error::UnsupportedMode { image_idx, mode });
+            }
+        }
+        Ok(())
+    }
+
+    async fn upload_image<P: ProgressSink>(
+        &mut self,
+        (image_idx, num_images): (usize, usize),
+        firmware: &FirmwareImage,
+        firmware_data: &[u8],
+        chunk_size: usize,
+        progress: &mut P,
+        image_report: &mut ImageReport,
+    ) -> Result<(), crate::error::Error> {
+        let descriptor = &firmware.descriptors[image_idx];
+        let padded;
+        let firmware_data = match self.pad_final_chunk {
+            Some(fill) if firmware_data.len() % CHUNK_ALIGNMENT != 0 => {
+                let mut data = firmware_data.to_vec();
+                data.resize(firmware_data.len().next_multiple_of(CHUNK_ALIGNMENT), fill);
+                padded = data;
+                &padded[..]
+            }
+            _ => firmware_data,
+        };
+
+        let mode = self.upload_mode(descriptor.image_type);
+        let target_offset = self.region_offsets.then_some(descriptor.image_offset);
+        let max_chunk_size = self
+            .start_upload_at(firmware_data.len() as u32, mode, target_offset)
+            .await?;
+
+        // Rounded down to the nearest multiple of `CHUNK_ALIGNMENT`
+        let chunk_size =
+            std::cmp::min(chunk_size, max_chunk_size as usize) / CHUNK_ALIGNMENT * CHUNK_ALIGNMENT;
+        // A device offering less than one aligned chunk can't be uploaded to at all
+        if chunk_size == 0 {
+            return Err(crate::error::Error::InvalidNegotiation { max_chunk_size });
+        }
+        if let Some(state) = &mut self.resume_state {
+            state.chunk_size = chunk_size;
+        }
+        image_report.chunk_size = Some(chunk_size);
+
+        let num_chunks = firmware_data.len().div_ceil(chunk_size);
+        let overall_num_chunks = self.chunks_acked
+            + num_chunks
+            + chain_sizes(firmware, descriptor.next_idx)
+                .iter()
+                .map(|&size| (size as usize).div_ceil(chunk_size))
+                .sum::<usize>();
+        let mut next_send = time::Instant::now();
+
+        for (idx, chunk) in firmware_data.chunks(chunk_size).enumerate() {
+            if let Some(rate) = self.max_throughput_bytes_per_sec {
+                within(self.deadline, time::sleep_until(next_send)).await?;
+                next_send = time::Instant::now()
+                    + Duration::from_secs_f64(chunk.len() as f64 / rate as f64);
+            }
+
+            let mut transmissions = 0;
+            let mut acked = None;
+            for _ in 0..self.chunk_retry {
+                let (chunk_idx, attempts) = self.send_chunk_attempts(idx, chunk).await?;
+                transmissions += attempts;
+                acked = Some(chunk_idx);
+                if idx == chunk_idx as usize {
                     break;
                 }
+
+                // Anything beyond a stale ack means the device lost track of the upload
+                if idx.abs_diff(chunk_idx as usize) > CHUNK_ACK_WINDOW {
+                    self.clear_read_buffer();
+                    if !self.resend_on_chunk_desync {
+                        break;
+                    }
+                }
+            }
+            match acked {
+                Some(chunk_idx) if idx != chunk_idx as usize => {
+                    return Err(crate::error::Error::ChunkDesync {
+                        expected: idx,
+                        actual: chunk_idx,
+                    })
+                }
+                _ => {}
+            }
+
+            if let Some(state) = &mut self.resume_state {
+                state.acked_chunks = idx + 1;
+            }
+
+            let retries = transmissions.saturating_sub(1);
+            image_report.chunk_retries += retries;
+            image_report.max_chunk_retries = std::cmp::max(image_report.max_chunk_retries, retries);
+            image_report.bytes_written += chunk.len();
+
+            let flow = progress
+                .event(ProgressEvent::ChunkProgress {
+                    image_idx,
+                    num_images,
+                    chunk_idx: idx,
+                    num_chunks,
+                    overall_chunk_idx: self.chunks_acked,
+                    overall_num_chunks,
+                })
+                .await;
+            self.chunks_acked += 1;
+            if flow.is_break() {
+                return Err(self.reset_after(crate::error::Error::Cancelled).await);
             }
         }
 
-        self.reset().await?;
+        let _ = progress
+            .event(ProgressEvent::ImageComplete { image_idx })
+            .await;
 
         Ok(())
     }
 }
+
+/// Dropping mid-upload leaves the device in the bootloader, which can't be helped without an async
+/// drop, but shouldn't go unnoticed either.
+#[cfg(feature = "tracing")]
+impl<T, U> Drop for Updater<T, U>
+where
+    T: AsyncRead + AsyncWrite,
+    U: Encoder<RequestFrame> + Decoder<Item = ResponseFrame>,
+{
+    fn drop(&mut self) {
+        if self.upload_in_progress {
+            tracing::warn!(
+                resume_state = ?self.resume_state,
+                "Updater dropped mid-upload, the device was not reset"
+            );
+        }
+    }
+}
@@ -1,4 +1,37 @@
-use stn_updater::codec::{RequestFrame, ResponseFrame, SerialCodec};
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::ControlFlow;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use crc::Crc;
+use futures::StreamExt;
+use stn_updater::capture::{
+    load_capture, load_trace, CaptureEntry, Direction, FileRecorder, JsonRecorder, Replay,
+    TraceEntry, WireTap,
+};
+use stn_updater::codec::{frame_stream, RequestFrame, ResponseFrame, SerialCodec};
+use stn_updater::error::Error;
+use stn_updater::firmware::{Capabilities, FirmwareImage, FirmwareImageDescriptor, ParseLimits};
+use stn_updater::flash_file;
+use stn_updater::protocol::{
+    encode_exchange, known_opcode, opcode_name, ConnectRequest, ConnectResponse, DecodeOptions,
+    FirmwareStatus, FromBytes, GetDevIDRequest, GetDevIDResponse, GetDeviceNameRequest,
+    GetFWStatusRequest, GetHWRevRequest, GetSerialNumberRequest, GetVersionRequest, Request,
+    ResendLastRequest, ResetRequest, Response, SendChunkRequest, SendChunkResponse,
+    StartUploadRequest, StartUploadResponse, UploadMode,
+};
+#[cfg(feature = "experimental")]
+use stn_updater::protocol::{
+    GetChecksumRequest, GetFlashInfoRequest, GetFlashInfoResponse, GetImageCrcRequest,
+    GetImageCrcResponse, GetResetReasonRequest, ReadFlashRequest, ResetReason,
+};
+use stn_updater::updater::{
+    chunk_progress, FirmwareState, ImageReport, LinkParams, ProgressEvent, RecoveryHint, Resetter,
+    ResumeState, Updater, UploadPlan, UploadReport,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 use tokio_util::codec::{Decoder, Encoder};
 
 use test_case::test_case;
@@ -34,3 +67,4729 @@ fn test_decoder(data: &[u8], response: ResponseFrame) {
 
     assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), response);
 }
+
+#[test]
+fn test_decoder_extended_flag() {
+    let mut payload = vec![0xC6, 0x01, 0x2A];
+    payload.extend_from_slice(&CRC.checksum(&payload).to_be_bytes());
+    let mut buf = bytes::BytesMut::from(&[SerialCodec::STX, SerialCodec::STX][..]);
+    buf.extend_from_slice(&payload);
+    buf.extend_from_slice(&[SerialCodec::ETX]);
+
+    let response = SerialCodec::new().decode(&mut buf).unwrap().unwrap();
+    assert!(response.ack);
+    assert!(response.extended);
+    assert_eq!(response.command, 0x06);
+    assert_eq!(response.command_byte(), 0xC6);
+    assert_eq!(response, ResponseFrame::from_command_byte(0xC6, vec![0x2A]));
+
+    // Round-trips through the device side encoder
+    let mut encoded = bytes::BytesMut::new();
+    SerialCodec::new().encode(response, &mut encoded).unwrap();
+    assert_eq!(encoded[2], 0xC6);
+}
+
+#[test]
+fn test_codec_custom_sentinels() {
+    let sentinels = [0xAA, 0x0D, 0x1B];
+    let mut codec = SerialCodec::with_sentinels(0xAA, 0x0D, 0x1B);
+
+    let mut buf = bytes::BytesMut::new();
+    codec
+        .encode(
+            RequestFrame::new(0x31, vec![0xAA, 0x55, 0x0D, 0x04]),
+            &mut buf,
+        )
+        .unwrap();
+    assert_eq!(
+        &buf[..9],
+        &[0xAA, 0xAA, 0x31, 0x00, 0x04, 0x1B, 0xAA, 0x55, 0x1B]
+    );
+    assert_eq!(buf.last(), Some(&0x0D));
+
+    let response = ResponseFrame::new(true, 0x31, vec![0xAA, 0x0D, 0x1B, 0x55, 0x04, 0x05]);
+    let mut buf = bytes::BytesMut::from(&encode_response_with(&response, sentinels)[..]);
+    assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), response);
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn test_codec_line_quality() {
+    let mut codec = SerialCodec::new();
+    let valid = encode_response(&ResponseFrame::new(true, 0x07, vec![0x12, 0x34]));
+
+    let mut corrupt = valid.clone();
+    corrupt[5] ^= 0xFF;
+    let mut buf = bytes::BytesMut::from(&corrupt[..]);
+    buf.extend_from_slice(&valid);
+    assert!(codec.decode(&mut buf).is_err());
+    assert_eq!(codec.crc_errors(), 1);
+    assert_eq!(
+        codec.decode(&mut buf).unwrap().unwrap(),
+        ResponseFrame::new(true, 0x07, vec![0x12, 0x34])
+    );
+
+    // Leading garbage is skipped, a lone trailing STX is kept for the next read
+    let mut buf = bytes::BytesMut::from(&b"ATZ\r\nELM"[..]);
+    buf.extend_from_slice(&valid);
+    buf.extend_from_slice(&[0x00, SerialCodec::STX]);
+    assert!(codec.decode(&mut buf).unwrap().is_some());
+    assert!(codec.decode(&mut buf).unwrap().is_none());
+    assert_eq!(&buf[..], &[SerialCodec::STX]);
+
+    // A frame cut short by the next one
+    let mut buf = bytes::BytesMut::from(&valid[..4]);
+    buf.extend_from_slice(&valid);
+    assert!(codec.decode(&mut buf).is_err());
+    assert!(codec.decode(&mut buf).unwrap().is_some());
+
+    assert_eq!(codec.crc_errors(), 1);
+    assert_eq!(codec.resyncs(), 3);
+    assert_eq!(codec.discarded_bytes(), 8 + 1 + 4);
+}
+
+#[test]
+fn test_opcode_names() {
+    assert!(known_opcode(SendChunkRequest::COMMAND));
+    assert_eq!(opcode_name(SendChunkRequest::COMMAND), Some("SendChunk"));
+    assert_eq!(opcode_name(0x01), Some("ResendLast"));
+
+    assert!(!known_opcode(0x3F));
+    assert_eq!(opcode_name(0x3F), None);
+}
+
+#[test]
+fn test_opcodes_unique() {
+    let opcodes = [
+        ResendLastRequest::<ConnectResponse>::COMMAND,
+        ResetRequest::COMMAND,
+        ConnectRequest::COMMAND,
+        GetVersionRequest::COMMAND,
+        GetDevIDRequest::COMMAND,
+        GetHWRevRequest::COMMAND,
+        #[cfg(feature = "experimental")]
+        GetImageCrcRequest::COMMAND,
+        GetSerialNumberRequest::COMMAND,
+        GetDeviceNameRequest::COMMAND,
+        #[cfg(feature = "experimental")]
+        GetFlashInfoRequest::COMMAND,
+        #[cfg(feature = "experimental")]
+        GetChecksumRequest::COMMAND,
+        #[cfg(feature = "experimental")]
+        ReadFlashRequest::COMMAND,
+        GetFWStatusRequest::COMMAND,
+        StartUploadRequest::COMMAND,
+        SendChunkRequest::COMMAND,
+    ];
+    assert_eq!(opcodes.iter().collect::<BTreeSet<_>>().len(), opcodes.len());
+    assert!(opcodes.iter().all(|opcode| known_opcode(*opcode)));
+}
+
+#[test]
+fn test_encode_exchange() {
+    let (request, response) = encode_exchange(&GetDevIDRequest, &GetDevIDResponse(0x1234)).unwrap();
+    let mut expected = bytes::BytesMut::new();
+    SerialCodec::new()
+        .encode(GetDevIDRequest.frame(), &mut expected)
+        .unwrap();
+    assert_eq!(request, expected);
+    assert_eq!(
+        response,
+        encode_response(&ResponseFrame::new(true, 0x07, vec![0x12, 0x34]))
+    );
+
+    // Values needing byte stuffing survive the round trip
+    let (_, response) = encode_exchange(&GetDevIDRequest, &GetDevIDResponse(0x5504)).unwrap();
+    let mut codec = SerialCodec::new();
+    let frame = codec
+        .decode(&mut bytes::BytesMut::from(&response[..]))
+        .unwrap()
+        .unwrap();
+    let GetDevIDResponse(device_id) =
+        GetDevIDResponse::from_frame::<GetDevIDRequest>(frame).unwrap();
+    assert_eq!(device_id, 0x5504);
+}
+
+#[test]
+fn test_stray_connect_response() {
+    let connect = || ResponseFrame::new(true, ConnectRequest::COMMAND, vec![]);
+    assert!(matches!(
+        SendChunkResponse::from_frame::<SendChunkRequest>(connect()),
+        Err(Error::DeviceReset)
+    ));
+    // Even a NACK
+    assert!(matches!(
+        GetDevIDResponse::from_frame::<GetDevIDRequest>(ResponseFrame::new(
+            false,
+            ConnectRequest::COMMAND,
+            vec![]
+        )),
+        Err(Error::DeviceReset)
+    ));
+    ConnectResponse::from_frame::<ConnectRequest>(connect()).unwrap();
+
+    // Any other mismatch is still just the wrong command
+    assert!(matches!(
+        SendChunkResponse::from_frame::<SendChunkRequest>(ResponseFrame::new(
+            true,
+            GetDevIDRequest::COMMAND,
+            vec![0x12, 0x34]
+        )),
+        Err(Error::InvalidCommand(_))
+    ));
+}
+
+#[test]
+fn test_decoder_errors() {
+    let valid = encode_response(&ResponseFrame::new(true, 0x07, vec![0x12, 0x34]));
+    let mut codec = SerialCodec::new();
+
+    let mut corrupt = valid.clone();
+    corrupt[4] = 0x13;
+    match codec.decode(&mut bytes::BytesMut::from(&corrupt[..])) {
+        Err(Error::BadCrc(bytes)) => assert_eq!(bytes, corrupt),
+        other => panic!("unexpected result: {:?}", other),
+    }
+
+    let mut short = valid.clone();
+    short[3] = 0x03;
+    match codec.decode(&mut bytes::BytesMut::from(&short[..])) {
+        Err(Error::BadFrame(bytes)) => assert_eq!(bytes, short),
+        other => panic!("unexpected result: {:?}", other),
+    }
+
+    let mut cut = bytes::BytesMut::from(&valid[..5]);
+    cut.extend_from_slice(&valid);
+    match codec.decode(&mut cut) {
+        Err(Error::UnexpectedStx(bytes)) => {
+            assert_eq!(bytes, [&valid[..5], &[SerialCodec::STX]].concat())
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn test_decoder_runt_frame() {
+    let frame = ResponseFrame::new(true, 0x07, vec![0x12, 0x34]);
+    let mut input = vec![SerialCodec::STX, SerialCodec::STX, 0x47, SerialCodec::ETX];
+    input.extend_from_slice(&encode_response(&frame));
+
+    let mut codec = SerialCodec::new();
+    let mut buf = bytes::BytesMut::from(&input[..]);
+    assert_eq!(codec.decode(&mut buf).unwrap(), Some(frame.clone()));
+    assert!(buf.is_empty());
+    assert_eq!((codec.resyncs(), codec.discarded_bytes()), (1, 4));
+
+    assert_eq!(
+        codec.decode_one(&input).unwrap(),
+        Some((frame, input.len()))
+    );
+
+    let mut codec = SerialCodec::new().with_runt_frame_errors();
+    assert!(matches!(
+        codec.decode(&mut bytes::BytesMut::from(&input[..])),
+        Err(Error::BadFrame(bytes)) if bytes == input[..4]
+    ));
+}
+
+#[test]
+fn test_verify_frame() {
+    let codec = SerialCodec::new();
+    // Holds a stuffed ETX
+    let frame = encode_response(&ResponseFrame::new(true, 0x06, vec![0x04, 0x01]));
+    assert!(codec.verify_frame(&frame).unwrap());
+
+    let mut corrupted = frame.clone();
+    corrupted[6] ^= 0x01;
+    assert!(!codec.verify_frame(&corrupted).unwrap());
+    // Nothing counted, unlike decoding
+    assert_eq!(codec.crc_errors(), 0);
+
+    let truncated = &frame[..frame.len() - 1];
+    assert!(matches!(
+        codec.verify_frame(truncated),
+        Err(Error::BadFrame(_))
+    ));
+    let trailing = [&frame[..], &[0x00]].concat();
+    assert!(matches!(
+        codec.verify_frame(&trailing),
+        Err(Error::BadFrame(_))
+    ));
+}
+
+#[test]
+fn test_decoder_incremental_crc() {
+    let frame = ResponseFrame::new(
+        true,
+        0x0C,
+        vec![
+            SerialCodec::STX,
+            SerialCodec::DLE,
+            0x00,
+            SerialCodec::ETX,
+            0xFF,
+        ],
+    );
+    let bytes = encode_response(&frame);
+    let one_shot = SerialCodec::new()
+        .decode(&mut bytes::BytesMut::from(&bytes[..]))
+        .unwrap();
+    assert_eq!(one_shot.as_ref(), Some(&frame));
+
+    // One byte per call, the CRC carried over between them
+    let mut codec = SerialCodec::new();
+    let mut buf = bytes::BytesMut::new();
+    let mut decoded = vec![];
+    for byte in &bytes {
+        buf.extend_from_slice(&[*byte]);
+        decoded.extend(codec.decode(&mut buf).unwrap());
+    }
+    assert_eq!(decoded, vec![frame]);
+    assert!(buf.is_empty());
+
+    let mut corrupt = bytes.clone();
+    let last = corrupt.iter().position(|&byte| byte == 0xFF).unwrap();
+    corrupt[last] = 0xFE;
+    let mut buf = bytes::BytesMut::new();
+    for (idx, byte) in corrupt.iter().enumerate() {
+        buf.extend_from_slice(&[*byte]);
+        let result = codec.decode(&mut buf);
+        if idx + 1 < corrupt.len() {
+            assert!(result.unwrap().is_none());
+        } else {
+            assert!(matches!(result, Err(Error::BadCrc(_))));
+        }
+    }
+    assert_eq!(codec.crc_errors(), 1);
+
+    // A buffer cleared mid-frame drops the partial frame
+    let valid = encode_response(&ResponseFrame::new(true, 0x07, vec![0x12, 0x34]));
+    let mut buf = bytes::BytesMut::from(&bytes[..bytes.len() / 2]);
+    assert!(codec.decode(&mut buf).unwrap().is_none());
+    buf.clear();
+    assert!(codec.decode(&mut buf).unwrap().is_none());
+    buf.extend_from_slice(&valid);
+    assert_eq!(
+        codec.decode(&mut buf).unwrap(),
+        Some(ResponseFrame::new(true, 0x07, vec![0x12, 0x34]))
+    );
+}
+
+#[test]
+fn test_decode_one() {
+    let frame = ResponseFrame::new(true, 0x07, vec![0x12, SerialCodec::STX]);
+    let bytes = encode_response(&frame);
+    let mut codec = SerialCodec::new();
+
+    for len in 0..bytes.len() {
+        assert_eq!(codec.decode_one(&bytes[..len]).unwrap(), None);
+    }
+
+    // Garbage ahead of the frame is consumed with it, the next frame isn't
+    let mut input = vec![0x00, 0xFF];
+    input.extend_from_slice(&bytes);
+    input.extend_from_slice(&bytes[..3]);
+    assert_eq!(
+        codec.decode_one(&input).unwrap(),
+        Some((frame.clone(), 2 + bytes.len()))
+    );
+
+    // Skipping a byte after an error resyncs on the next frame
+    let mut corrupt = bytes.clone();
+    corrupt[4] ^= 0x01;
+    corrupt.extend_from_slice(&bytes);
+    assert!(matches!(codec.decode_one(&corrupt), Err(Error::BadCrc(_))));
+    assert_eq!(codec.crc_errors(), 1);
+    assert_eq!(
+        codec.decode_one(&corrupt[1..]).unwrap(),
+        Some((frame, corrupt.len() - 1))
+    );
+}
+
+// Device side of the link: decodes request frames, encodes response frames and plays back
+// whatever the test's handler scripts for each received item.
+
+const CRC: Crc<u16> = Crc::<u16>::new(&crc::CRC_16_XMODEM);
+
+const RESEND_LAST: u8 = 0x01;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Received {
+    Frame(RequestFrame),
+    Raw(Vec<u8>),
+}
+
+enum Reply {
+    Frame(ResponseFrame),
+    Raw(Vec<u8>),
+    Delay(Duration),
+    Close,
+}
+
+fn ack(command: u8, data: Vec<u8>) -> Reply {
+    Reply::Frame(ResponseFrame::new(true, command, data))
+}
+
+const SENTINELS: [u8; 3] = [SerialCodec::STX, SerialCodec::ETX, SerialCodec::DLE];
+
+fn stuff(byte: u8, [stx, etx, dle]: [u8; 3], dst: &mut Vec<u8>) {
+    if byte == stx || byte == etx || byte == dle {
+        dst.push(dle);
+    }
+    dst.push(byte);
+}
+
+fn encode_response(frame: &ResponseFrame) -> Vec<u8> {
+    encode_response_with(frame, SENTINELS)
+}
+
+fn encode_response_with(frame: &ResponseFrame, sentinels: [u8; 3]) -> Vec<u8> {
+    let mut payload = vec![frame.command_byte(), frame.data.len() as u8];
+    payload.extend_from_slice(&frame.data);
+    payload.extend_from_slice(&CRC.checksum(&payload).to_be_bytes());
+
+    let [stx, etx, _] = sentinels;
+    let mut bytes = vec![stx, stx];
+    for byte in payload {
+        stuff(byte, sentinels, &mut bytes);
+    }
+    bytes.push(etx);
+    bytes
+}
+
+fn parse_received(buf: &mut Vec<u8>) -> Option<Received> {
+    let is_frame = match buf.as_slice() {
+        [] | [SerialCodec::STX] | [SerialCodec::STX, SerialCodec::STX] => return None,
+        [SerialCodec::STX, SerialCodec::STX, SerialCodec::STX, ..] => false,
+        [SerialCodec::STX, SerialCodec::STX, ..] => true,
+        _ => false,
+    };
+
+    if !is_frame {
+        let end = buf[1..]
+            .iter()
+            .position(|&byte| byte == SerialCodec::STX)
+            .map_or(buf.len(), |position| position + 1);
+        return Some(Received::Raw(buf.drain(..end).collect()));
+    }
+
+    let mut data = vec![];
+    let mut skip = false;
+    for idx in 2..buf.len() {
+        if skip {
+            skip = false;
+            data.push(buf[idx]);
+        } else if buf[idx] == SerialCodec::DLE {
+            skip = true;
+        } else if buf[idx] == SerialCodec::ETX {
+            assert_eq!(CRC.checksum(&data), 0, "request CRC");
+            let command = data[0];
+            let length = u16::from_be_bytes([data[1], data[2]]) as usize;
+            let frame = RequestFrame::new(command, data[3..3 + length].to_vec());
+            buf.drain(..idx + 1);
+            return Some(Received::Frame(frame));
+        } else {
+            data.push(buf[idx]);
+        }
+    }
+    None
+}
+
+fn mock_device<F>(handler: F) -> (DuplexStream, JoinHandle<Vec<Received>>)
+where
+    F: FnMut(&Received) -> Vec<Reply> + Send + 'static,
+{
+    let (host, device) = tokio::io::duplex(4096);
+    (host, mock_device_on(device, handler))
+}
+
+/// Runs `handler` on the device end of any transport.
+fn mock_device_on<S, F>(mut device: S, mut handler: F) -> JoinHandle<Vec<Received>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    F: FnMut(&Received) -> Vec<Reply> + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut received: Vec<Received> = vec![];
+        let mut buf = vec![];
+        let mut read_buf = [0u8; 1024];
+        loop {
+            match device.read(&mut read_buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(count) => buf.extend_from_slice(&read_buf[..count]),
+            }
+
+            while let Some(item) = parse_received(&mut buf) {
+                for reply in handler(&item) {
+                    match reply {
+                        Reply::Frame(frame) => {
+                            if device.write_all(&encode_response(&frame)).await.is_err() {
+                                break;
+                            }
+                        }
+                        Reply::Raw(bytes) => {
+                            if device.write_all(&bytes).await.is_err() {
+                                break;
+                            }
+                        }
+                        Reply::Delay(delay) => tokio::time::sleep(delay).await,
+                        Reply::Close => {
+                            received.push(item);
+                            return received;
+                        }
+                    }
+                }
+
+                match (received.last_mut(), item) {
+                    (Some(Received::Raw(last)), Received::Raw(raw)) => last.extend(raw),
+                    (_, item) => received.push(item),
+                }
+            }
+        }
+        received
+    })
+}
+
+struct AtzResetter;
+#[async_trait]
+impl Resetter for AtzResetter {
+    type Device = DuplexStream;
+
+    async fn reset(device: &mut Self::Device) -> anyhow::Result<()> {
+        device.write_all(b"ATZ\r").await?;
+        Ok(())
+    }
+}
+
+/// A device in application mode that only answers `ConnectRequest` once reset, after `delay`.
+fn slow_bootloader(delay: Duration) -> impl FnMut(&Received) -> Vec<Reply> + Send + 'static {
+    let mut bootloader = false;
+    move |received| match received {
+        Received::Raw(_) => {
+            bootloader = true;
+            vec![]
+        }
+        Received::Frame(frame) if bootloader && frame.command == ConnectRequest::COMMAND => {
+            vec![Reply::Delay(delay), ack(ConnectRequest::COMMAND, vec![])]
+        }
+        Received::Frame(_) => vec![],
+    }
+}
+
+#[tokio::test]
+async fn test_connect_slow_device_after_reset() {
+    let (io, device) = mock_device(slow_bootloader(Duration::from_millis(80)));
+
+    let mut updater =
+        Updater::new(io, SerialCodec::new()).connect_timeout(Duration::from_millis(100));
+    updater.connect::<AtzResetter>().await.unwrap();
+    drop(updater);
+
+    let received = device.await.unwrap();
+    assert_eq!(
+        received,
+        vec![
+            Received::Frame(ConnectRequest.frame()),
+            Received::Raw(b"ATZ\r".to_vec()),
+            Received::Frame(ConnectRequest.frame()),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_connect_retry_timeout_too_short() {
+    let (io, _device) = mock_device(slow_bootloader(Duration::from_millis(80)));
+
+    let mut updater = Updater::new(io, SerialCodec::new())
+        .connect_timeout(Duration::from_millis(100))
+        .connect_retry(1)
+        .connect_retry_timeout(Duration::from_millis(20));
+    assert!(matches!(
+        updater.connect::<AtzResetter>().await,
+        Err(Error::Timeout)
+    ));
+}
+
+/// A device already in the bootloader that accepts every upload.
+const CRC32: Crc<u32> = Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+
+const BOOTLOADER_VERSION: [u8; 2] = [2, 0];
+
+fn bootloader(device_id: u16, max_chunk_size: u16) -> impl FnMut(&Received) -> Vec<Reply> + Send {
+    let mut last_response: Option<ResponseFrame> = None;
+    move |received| {
+        let Received::Frame(frame) = received else {
+            return vec![];
+        };
+        let response = match frame.command {
+            RESEND_LAST => match &last_response {
+                Some(response) => {
+                    ResponseFrame::new(response.ack, response.command, response.data.clone())
+                }
+                None => return vec![],
+            },
+            ConnectRequest::COMMAND | ResetRequest::COMMAND => {
+                ResponseFrame::new(true, frame.command, vec![])
+            }
+            GetDevIDRequest::COMMAND => {
+                ResponseFrame::new(true, frame.command, device_id.to_be_bytes().to_vec())
+            }
+            GetVersionRequest::COMMAND => {
+                ResponseFrame::new(true, frame.command, BOOTLOADER_VERSION.to_vec())
+            }
+            StartUploadRequest::COMMAND => {
+                ResponseFrame::new(true, frame.command, max_chunk_size.to_be_bytes().to_vec())
+            }
+            SendChunkRequest::COMMAND => {
+                ResponseFrame::new(true, frame.command, frame.data[..2].to_vec())
+            }
+            _ => return vec![],
+        };
+        last_response = Some(ResponseFrame::new(
+            response.ack,
+            response.command,
+            response.data.clone(),
+        ));
+        vec![Reply::Frame(response)]
+    }
+}
+
+/// A device running its application, that only enters the bootloader once reset.
+fn application_mode(device_id: u16) -> impl FnMut(&Received) -> Vec<Reply> + Send {
+    let mut inner = bootloader(device_id, 16);
+    let mut reset = false;
+    move |received| match received {
+        Received::Raw(_) => {
+            reset = true;
+            vec![]
+        }
+        Received::Frame(_) if reset => inner(received),
+        Received::Frame(_) => vec![],
+    }
+}
+
+#[tokio::test]
+async fn test_pre_connect() {
+    let (io, device) = mock_device(bootloader(0x1234, 16));
+    let mut updater = Updater::new(io, SerialCodec::new()).pre_connect(|io: &mut DuplexStream| {
+        Box::pin(async move {
+            io.write_all(b"WAKE").await?;
+            Ok(())
+        })
+    });
+    updater.connect::<AtzResetter>().await.unwrap();
+    updater.connect::<AtzResetter>().await.unwrap();
+    drop(updater);
+
+    let connect = Received::Frame(RequestFrame::new(ConnectRequest::COMMAND, vec![]));
+    assert_eq!(
+        device.await.unwrap(),
+        vec![
+            Received::Raw(b"WAKE".to_vec()),
+            connect.clone(),
+            Received::Raw(b"WAKE".to_vec()),
+            connect,
+        ]
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_open_settle_delay() {
+    let first_frame = std::sync::Arc::new(std::sync::Mutex::new(None));
+    let mut inner = bootloader(0x1234, 16);
+    let handler = {
+        let first_frame = first_frame.clone();
+        move |received: &Received| {
+            first_frame
+                .lock()
+                .unwrap()
+                .get_or_insert_with(tokio::time::Instant::now);
+            inner(received)
+        }
+    };
+    let (io, _device) = mock_device(handler);
+    let opened = tokio::time::Instant::now();
+    let mut updater =
+        Updater::new(io, SerialCodec::new()).open_settle_delay(Duration::from_millis(500));
+
+    updater.connect::<AtzResetter>().await.unwrap();
+    let first_frame = first_frame.lock().unwrap().unwrap();
+    assert!(first_frame - opened >= Duration::from_millis(500));
+
+    // Only waited for once
+    let start = tokio::time::Instant::now();
+    updater.connect::<AtzResetter>().await.unwrap();
+    assert!(start.elapsed() < Duration::from_millis(500));
+}
+
+/// A device on an auto-baud link, where the first byte sent only sets the baud rate.
+fn auto_baud_device<F>(handler: F) -> (DuplexStream, JoinHandle<Vec<Received>>)
+where
+    F: FnMut(&Received) -> Vec<Reply> + Send + 'static,
+{
+    let (host, link) = tokio::io::duplex(4096);
+    let (uart, device) = tokio::io::duplex(4096);
+    let (mut link_rx, mut link_tx) = tokio::io::split(link);
+    let (mut uart_rx, mut uart_tx) = tokio::io::split(uart);
+    tokio::spawn(async move {
+        if link_rx.read_u8().await.is_ok() {
+            let _ = tokio::io::copy(&mut link_rx, &mut uart_tx).await;
+        }
+    });
+    tokio::spawn(async move { tokio::io::copy(&mut uart_rx, &mut link_tx).await });
+    (host, mock_device_on(device, handler))
+}
+
+async fn connect_auto_baud(auto_baud: bool) -> Result<(), Error> {
+    let (io, _device) = auto_baud_device(bootloader(0x1234, 16));
+    let mut updater = Updater::new(io, SerialCodec::new())
+        .auto_baud(auto_baud)
+        .connect_retry(0)
+        .connect_timeout(Duration::from_millis(100));
+    updater.connect::<AtzResetter>().await
+}
+
+#[tokio::test]
+async fn test_auto_baud() {
+    connect_auto_baud(true).await.unwrap();
+
+    // The first probe loses its leading STX
+    assert!(matches!(
+        connect_auto_baud(false).await,
+        Err(Error::Timeout)
+    ));
+}
+
+#[tokio::test]
+async fn test_is_in_bootloader() {
+    let (io, device) = mock_device(bootloader(0x1234, 16));
+    let mut updater = Updater::new(io, SerialCodec::new());
+    assert!(updater.is_in_bootloader().await.unwrap());
+    drop(updater);
+    assert_eq!(
+        device.await.unwrap(),
+        vec![Received::Frame(ConnectRequest.frame())]
+    );
+
+    // No reset to get it there
+    let (io, device) = mock_device(application_mode(0x1234));
+    let mut updater =
+        Updater::new(io, SerialCodec::new()).connect_retry_timeout(Duration::from_millis(50));
+    assert!(!updater.is_in_bootloader().await.unwrap());
+    drop(updater);
+    assert_eq!(
+        device.await.unwrap(),
+        vec![Received::Frame(ConnectRequest.frame())]
+    );
+}
+
+#[tokio::test]
+async fn test_enter_bootloader() {
+    let (io, device) = mock_device(application_mode(0x1234));
+    let mut updater = Updater::new(io, SerialCodec::new());
+    updater.enter_bootloader::<AtzResetter>().await.unwrap();
+    // Several operations after a single entry
+    assert_eq!(updater.device_id().await.unwrap(), 0x1234);
+    assert_eq!(updater.bootloader_version().await.unwrap(), (2, 0));
+    drop(updater);
+
+    // Reset straight away, no probe first
+    let received = device.await.unwrap();
+    assert_eq!(received[0], Received::Raw(b"ATZ\r".to_vec()));
+    assert_eq!(
+        received[1],
+        Received::Frame(RequestFrame::new(ConnectRequest::COMMAND, vec![]))
+    );
+    assert_eq!(received.len(), 4);
+}
+
+/// Swallows the replies to the first `drops[chunk]` frames (chunk or resend) sent for each chunk.
+fn lossy_chunks(
+    mut inner: impl FnMut(&Received) -> Vec<Reply> + Send,
+    mut drops: Vec<usize>,
+) -> impl FnMut(&Received) -> Vec<Reply> + Send {
+    let mut chunk = 0;
+    move |received| {
+        let replies = inner(received);
+        if let Received::Frame(frame) = received {
+            if frame.command == SendChunkRequest::COMMAND {
+                chunk = u16::from_be_bytes([frame.data[0], frame.data[1]]) as usize;
+            }
+            if let SendChunkRequest::COMMAND | RESEND_LAST = frame.command {
+                if drops.get(chunk).copied().unwrap_or(0) > 0 {
+                    drops[chunk] -= 1;
+                    return vec![];
+                }
+            }
+        }
+        replies
+    }
+}
+
+/// NACKs the first `count` `StartUploadRequest`s with `status`.
+fn nack_start_upload(
+    mut inner: impl FnMut(&Received) -> Vec<Reply> + Send,
+    status: u8,
+    mut count: usize,
+) -> impl FnMut(&Received) -> Vec<Reply> + Send {
+    move |received| match received {
+        Received::Frame(frame) if frame.command == StartUploadRequest::COMMAND && count > 0 => {
+            count -= 1;
+            vec![Reply::Frame(ResponseFrame::new(
+                false,
+                StartUploadRequest::COMMAND,
+                vec![status],
+            ))]
+        }
+        _ => inner(received),
+    }
+}
+
+/// Builds a container holding `images` chained in order.
+fn firmware(device_ids: &[u16], images: &[&[u8]]) -> FirmwareImage {
+    let mut data = vec![];
+    let mut descriptors = vec![];
+    for (idx, image) in images.iter().enumerate() {
+        descriptors.push(FirmwareImageDescriptor {
+            image_type: 0x00,
+            reserved: 0x00,
+            next_idx: if idx + 1 < images.len() {
+                (idx + 1) as u8
+            } else {
+                0xFF
+            },
+            error_idx: 0x00,
+            image_offset: data.len() as u32,
+            image_size: image.len() as u32,
+        });
+        data.extend_from_slice(image);
+    }
+    FirmwareImage {
+        device_ids: device_ids.iter().copied().collect(),
+        descriptors,
+        data,
+    }
+}
+
+#[tokio::test]
+async fn test_upload_borrowed_firmware() {
+    let firmware = firmware(&[0x1234], &[&[0xAA; 32], &[0xBB; 16]]);
+    for _ in 0..2 {
+        let (io, device) = mock_device(bootloader(0x1234, 16));
+        let mut updater = Updater::new(io, SerialCodec::new());
+        updater
+            .upload_firmware::<AtzResetter, _>(&firmware, |_, _, _, _| {})
+            .await
+            .unwrap();
+        drop(updater);
+
+        assert_eq!(
+            sent_chunks(&device.await.unwrap()),
+            vec![vec![0xAA; 16], vec![0xAA; 16], vec![0xBB; 16]]
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_upload_firmware_with_channel() {
+    let (io, _device) = mock_device(bootloader(0x1234, 16));
+    let firmware = firmware(&[0x1234], &[&[0xAA; 40]]);
+    let (events_tx, mut events_rx) = mpsc::channel(16);
+
+    let mut updater = Updater::new(io, SerialCodec::new());
+    let upload = updater.upload_firmware_with_channel::<AtzResetter>(&firmware, events_tx);
+    let collect = async {
+        let mut events = vec![];
+        while let Some(event) = events_rx.recv().await {
+            events.push(event);
+        }
+        events
+    };
+    let (result, events) = tokio::join!(upload, collect);
+
+    result.unwrap();
+    assert_eq!(
+        events,
+        vec![
+            ProgressEvent::Connected,
+            ProgressEvent::DeviceInfo { device_id: 0x1234 },
+            ProgressEvent::ChunkProgress {
+                image_idx: 0,
+                num_images: 1,
+                chunk_idx: 0,
+                num_chunks: 3,
+                overall_chunk_idx: 0,
+                overall_num_chunks: 3,
+            },
+            ProgressEvent::ChunkProgress {
+                image_idx: 0,
+                num_images: 1,
+                chunk_idx: 1,
+                num_chunks: 3,
+                overall_chunk_idx: 1,
+                overall_num_chunks: 3,
+            },
+            ProgressEvent::ChunkProgress {
+                image_idx: 0,
+                num_images: 1,
+                chunk_idx: 2,
+                num_chunks: 3,
+                overall_chunk_idx: 2,
+                overall_num_chunks: 3,
+            },
+            ProgressEvent::ImageComplete { image_idx: 0 },
+            ProgressEvent::Done,
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_overall_progress() {
+    let (io, _device) = mock_device(bootloader(0x1234, 16));
+    let firmware = firmware(&[0x1234], &[&[0xAA; 32], &[0xBB; 48]]);
+    let (events_tx, mut events_rx) = mpsc::channel(16);
+
+    let mut updater = Updater::new(io, SerialCodec::new());
+    let upload = updater.upload_firmware_with_channel::<AtzResetter>(&firmware, events_tx);
+    let collect = async {
+        let mut positions = vec![];
+        while let Some(event) = events_rx.recv().await {
+            if let ProgressEvent::ChunkProgress {
+                image_idx,
+                chunk_idx,
+                overall_chunk_idx,
+                overall_num_chunks,
+                ..
+            } = event
+            {
+                positions.push((image_idx, chunk_idx, overall_chunk_idx, overall_num_chunks));
+            }
+        }
+        positions
+    };
+    let (result, positions) = tokio::join!(upload, collect);
+
+    result.unwrap();
+    assert_eq!(
+        positions,
+        vec![
+            (0, 0, 0, 5),
+            (0, 1, 1, 5),
+            (1, 0, 2, 5),
+            (1, 1, 3, 5),
+            (1, 2, 4, 5),
+        ]
+    );
+}
+
+/// Uploads two images, with the device going quiet or NACKing the `nth` frame of `command`.
+async fn recovery_hint_after(command: u8, nth: usize, nack: bool) -> Option<RecoveryHint> {
+    let mut inner = bootloader(0x1234, 16);
+    let mut count = 0;
+    let handler = move |received: &Received| match received {
+        Received::Frame(frame) if frame.command == command => {
+            count += 1;
+            match count == nth {
+                true if nack => vec![Reply::Frame(ResponseFrame::new(false, command, vec![0x01]))],
+                true => vec![],
+                false => inner(received),
+            }
+        }
+        _ => inner(received),
+    };
+    let (io, _device) = mock_device(handler);
+    let mut updater = Updater::new(io, SerialCodec::new())
+        .request_timeout(Duration::from_millis(50))
+        .chunk_timeout(Duration::from_millis(50))
+        .chunk_retry(1)
+        .resend_retry(0)
+        .busy_retry(0);
+    let result = updater
+        .upload_firmware::<AtzResetter, _>(
+            &firmware(&[0x1234], &[&[0xAA; 32], &[0xBB; 32]]),
+            |_, _, _, _| {},
+        )
+        .await;
+    assert_eq!(result.is_ok(), updater.recovery_hint().is_none());
+    updater.recovery_hint()
+}
+
+#[test_case(StartUploadRequest::COMMAND, 1, true, Some(RecoveryHint::SafeToPowerCycle) ; "rejected before writing")]
+#[test_case(SendChunkRequest::COMMAND, 1, true, Some(RecoveryHint::DoNotPowerCycle) ; "first chunk rejected after erasing")]
+#[test_case(SendChunkRequest::COMMAND, 1, false, Some(RecoveryHint::RetryRecommended) ; "first chunk timed out after erasing")]
+#[test_case(SendChunkRequest::COMMAND, 2, false, Some(RecoveryHint::RetryRecommended) ; "timed out mid-image")]
+#[test_case(StartUploadRequest::COMMAND, 2, false, Some(RecoveryHint::RetryRecommended) ; "timed out between images")]
+#[test_case(SendChunkRequest::COMMAND, 3, true, Some(RecoveryHint::DoNotPowerCycle) ; "rejected mid-upload")]
+#[test_case(SendChunkRequest::COMMAND, 0, false, None ; "completed")]
+#[tokio::test]
+async fn test_recovery_hint(command: u8, nth: usize, nack: bool, hint: Option<RecoveryHint>) {
+    assert_eq!(recovery_hint_after(command, nth, nack).await, hint);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_upload_firmware_with_slow_channel() {
+    let (io, _device) = mock_device(bootloader(0x1234, 16));
+    let firmware = firmware(&[0x1234], &[&[0xAA; 160]]);
+    let (events_tx, mut events_rx) = mpsc::channel(1);
+
+    let mut updater = Updater::new(io, SerialCodec::new());
+    let upload = updater.upload_firmware_with_channel::<AtzResetter>(&firmware, events_tx);
+    let collect = async {
+        let mut events = vec![];
+        while let Some(event) = events_rx.recv().await {
+            events.push(event);
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        events
+    };
+    let (result, events) = tokio::join!(upload, collect);
+
+    // Chunk progress is dropped, everything else still arrives
+    result.unwrap();
+    let chunk_events = events
+        .iter()
+        .filter(|event| matches!(event, ProgressEvent::ChunkProgress { .. }))
+        .count();
+    assert!(chunk_events < 10, "{:?}", events);
+    assert_eq!(
+        events[..2],
+        [
+            ProgressEvent::Connected,
+            ProgressEvent::DeviceInfo { device_id: 0x1234 }
+        ]
+    );
+    assert_eq!(
+        events[events.len() - 2..],
+        [
+            ProgressEvent::ImageComplete { image_idx: 0 },
+            ProgressEvent::Done
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_upload_firmware_with_dropped_channel() {
+    let (io, _device) = mock_device(bootloader(0x1234, 16));
+    let firmware = firmware(&[0x1234], &[&[0xAA; 40]]);
+    let (events_tx, events_rx) = mpsc::channel(1);
+    drop(events_rx);
+
+    let mut updater = Updater::new(io, SerialCodec::new());
+    updater
+        .upload_firmware_with_channel::<AtzResetter>(&firmware, events_tx)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_recv_response_disconnected() {
+    let (io, _device) = mock_device(|received| match received {
+        Received::Frame(frame) if frame.command == GetDevIDRequest::COMMAND => vec![Reply::Close],
+        _ => vec![],
+    });
+
+    let mut updater = Updater::new(io, SerialCodec::new()).request_timeout(Duration::from_secs(5));
+    let start = tokio::time::Instant::now();
+    assert!(matches!(
+        updater.device_id().await,
+        Err(Error::Disconnected)
+    ));
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
+
+/// Answers `GetDevIDRequest` with `first` once, and properly from then on.
+fn corrupt_first_reply(first: Vec<u8>) -> impl FnMut(&Received) -> Vec<Reply> + Send {
+    let mut first = Some(first);
+    move |received| match received {
+        Received::Frame(frame) if frame.command == GetDevIDRequest::COMMAND => {
+            vec![Reply::Raw(first.take().unwrap())]
+        }
+        Received::Frame(frame) if frame.command == RESEND_LAST => {
+            vec![ack(GetDevIDRequest::COMMAND, vec![0x12, 0x34])]
+        }
+        _ => vec![],
+    }
+}
+
+#[tokio::test]
+async fn test_corrupt_response_is_resent() {
+    let valid = encode_response(&ResponseFrame::new(true, 0x07, vec![0x12, 0x34]));
+    let mut bad_crc = valid.clone();
+    bad_crc[4] = 0x13;
+    let mut bad_frame = valid.clone();
+    bad_frame[3] = 0x03;
+
+    for first in [bad_crc, bad_frame] {
+        let (io, device) = mock_device(corrupt_first_reply(first));
+        let mut updater = Updater::new(io, SerialCodec::new());
+        assert_eq!(updater.device_id().await.unwrap(), 0x1234);
+        drop(updater);
+
+        let resends = device
+            .await
+            .unwrap()
+            .iter()
+            .filter(|received| matches!(received, Received::Frame(frame) if frame.command == RESEND_LAST))
+            .count();
+        assert_eq!(resends, 1);
+    }
+}
+
+/// Accepts writes, fails every read.
+struct BrokenIo;
+
+impl tokio::io::AsyncRead for BrokenIo {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+        _buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Err(std::io::Error::other("broken")))
+    }
+}
+
+impl tokio::io::AsyncWrite for BrokenIo {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        std::task::Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+#[tokio::test]
+async fn test_pending_at_timeout() {
+    // The device ID response stops short of its CRC and ETX
+    let reply = encode_response(&ResponseFrame::new(
+        true,
+        GetDevIDRequest::COMMAND,
+        vec![0x12, 0x34],
+    ));
+    let cut = reply[..reply.len() - 3].to_vec();
+    let mut inner = bootloader(0x1234, 16);
+    let handler = {
+        let cut = cut.clone();
+        move |received: &Received| match received {
+            Received::Frame(frame) if frame.command == GetDevIDRequest::COMMAND => {
+                vec![Reply::Raw(cut.clone())]
+            }
+            _ => inner(received),
+        }
+    };
+    let (io, _device) = mock_device(handler);
+    let mut updater = Updater::new(io, SerialCodec::new())
+        .resend_retry(0)
+        .request_timeout(Duration::from_millis(50));
+    assert!(updater.pending_at_timeout().is_empty());
+
+    assert!(matches!(updater.device_id().await, Err(Error::Timeout)));
+    assert_eq!(updater.pending_at_timeout(), &cut[..]);
+
+    // Still dropped from the read buffer
+    assert_eq!(updater.bootloader_version().await.unwrap(), (2, 0));
+}
+
+#[tokio::test]
+async fn test_io_error_is_not_resent() {
+    let mut updater = Updater::new(BrokenIo, SerialCodec::new());
+    assert!(matches!(updater.device_id().await, Err(Error::IOError(_))));
+}
+
+#[tokio::test]
+async fn test_drain_input() {
+    let (io, mut device) = tokio::io::duplex(4096);
+    let device = tokio::spawn(async move {
+        for garbage in [&b"\x55\x55\x46\x02"[..], b"ATZ\r\r>", b"\x04\x05\x04"] {
+            device.write_all(garbage).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let mut buf = vec![0u8; 64];
+        let count = device.read(&mut buf).await.unwrap();
+        let mut buf = buf[..count].to_vec();
+        assert_eq!(
+            parse_received(&mut buf),
+            Some(Received::Frame(ConnectRequest.frame()))
+        );
+        device
+            .write_all(&encode_response(&ResponseFrame::new(
+                true,
+                ConnectRequest::COMMAND,
+                vec![],
+            )))
+            .await
+            .unwrap();
+        device
+    });
+
+    let mut updater = Updater::new(io, SerialCodec::new());
+    assert_eq!(
+        updater
+            .drain_input(Duration::from_millis(50))
+            .await
+            .unwrap(),
+        13
+    );
+    updater.connect::<AtzResetter>().await.unwrap();
+    drop(updater);
+    device.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_upload_report_retries() {
+    let (io, _device) = mock_device(lossy_chunks(bootloader(0x1234, 16), vec![0, 4, 0, 2]));
+    let firmware = firmware(&[0x1234], &[&[0xAA; 64]]);
+
+    let mut updater = Updater::new(io, SerialCodec::new())
+        .resend_retry(1)
+        .chunk_timeout(Duration::from_millis(30));
+    let report = updater
+        .upload_firmware::<AtzResetter, _>(&firmware, |_, _, _, _| {})
+        .await
+        .unwrap();
+
+    assert_eq!(
+        report,
+        UploadReport {
+            chunk_retries: 3,
+            max_chunk_retries: 2,
+            resends: BTreeMap::from([(SendChunkRequest::COMMAND, 3)]),
+            reset: true,
+            // Timing, not retries
+            link_rtt: report.link_rtt,
+            images: vec![ImageReport {
+                image_idx: 0,
+                bytes_written: 64,
+                chunk_retries: 3,
+                max_chunk_retries: 2,
+                chunk_size: Some(16),
+                chunk_size_dropped_from: None,
+                error: None,
+            }],
+            ..Default::default()
+        }
+    );
+    assert!(report.link_rtt.is_some());
+}
+
+#[test]
+fn test_chunk_crc_encoding() {
+    let data = vec![0x31, 0x32, 0x33, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39];
+    let frame = SendChunkRequest::with_crc(0x0102, data.clone()).frame();
+    // CRC-16/XMODEM check value of "123456789"
+    assert_eq!(
+        frame.data,
+        [&[0x01, 0x02], &data[..], &[0x31, 0xC3]].concat()
+    );
+
+    let frame = SendChunkRequest {
+        chunk_num: 0x0102,
+        data: data.clone(),
+        crc: None,
+    }
+    .frame();
+    assert_eq!(frame.data, [&[0x01, 0x02], &data[..]].concat());
+
+    let frame = StartUploadRequest {
+        image_size: 0x123456,
+        mode: UploadMode::ProgramVerify,
+        chunk_crc: true,
+        target_offset: None,
+    }
+    .frame();
+    assert_eq!(frame.data, vec![0x12, 0x34, 0x56, 0x82]);
+
+    let frame = StartUploadRequest {
+        image_size: 0x123456,
+        mode: UploadMode::Program,
+        chunk_crc: false,
+        target_offset: Some(0x0800_4000),
+    }
+    .frame();
+    assert_eq!(
+        frame.data,
+        vec![0x12, 0x34, 0x56, 0x01, 0x08, 0x00, 0x40, 0x00]
+    );
+}
+
+/// Checks the CRC of every chunk, NACKing the first `corrupt` chunks as if they arrived damaged.
+fn chunk_crc_device(
+    mut inner: impl FnMut(&Received) -> Vec<Reply> + Send,
+    mut corrupt: usize,
+) -> impl FnMut(&Received) -> Vec<Reply> + Send {
+    move |received| match received {
+        Received::Frame(frame) if frame.command == SendChunkRequest::COMMAND => {
+            let (data, crc) = frame.data[2..].split_at(frame.data.len() - 4);
+            assert_eq!(CRC.checksum(data).to_be_bytes(), crc);
+            if corrupt > 0 {
+                corrupt -= 1;
+                vec![Reply::Frame(ResponseFrame::new(
+                    false,
+                    SendChunkRequest::COMMAND,
+                    vec![SendChunkRequest::BAD_CRC],
+                ))]
+            } else {
+                inner(received)
+            }
+        }
+        _ => inner(received),
+    }
+}
+
+#[tokio::test]
+async fn test_chunk_crc_nack_resends_chunk() {
+    let (io, device) = mock_device(chunk_crc_device(bootloader(0x1234, 16), 1));
+    let mut updater = Updater::new(io, SerialCodec::new()).chunk_crc(true);
+    let report = updater
+        .upload_firmware::<AtzResetter, _>(&firmware(&[0x1234], &[&[0xAA; 32]]), |_, _, _, _| {})
+        .await
+        .unwrap();
+    assert_eq!(report.chunk_retries, 1);
+    drop(updater);
+
+    let frames = device
+        .await
+        .unwrap()
+        .into_iter()
+        .filter_map(|received| match received {
+            Received::Frame(frame)
+                if frame.command == StartUploadRequest::COMMAND
+                    || frame.command == SendChunkRequest::COMMAND =>
+            {
+                Some((frame.command, frame.data[..4].to_vec()))
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        frames,
+        vec![
+            (StartUploadRequest::COMMAND, vec![0x00, 0x00, 32, 0x81]),
+            (SendChunkRequest::COMMAND, vec![0x00, 0x00, 0xAA, 0xAA]),
+            (SendChunkRequest::COMMAND, vec![0x00, 0x00, 0xAA, 0xAA]),
+            (SendChunkRequest::COMMAND, vec![0x00, 0x01, 0xAA, 0xAA]),
+        ]
+    );
+}
+
+/// Offers `offers` in turn as the max chunk size, the last one from then on.
+async fn upload_with_chunk_offers(offers: &'static [u16]) -> (Result<UploadReport, Error>, usize) {
+    let mut inner = bootloader(0x1234, 16);
+    let mut start_uploads = 0;
+    let handler = move |received: &Received| match received {
+        Received::Frame(frame) if frame.command == StartUploadRequest::COMMAND => {
+            let offer = offers[start_uploads.min(offers.len() - 1)];
+            start_uploads += 1;
+            vec![ack(frame.command, offer.to_be_bytes().to_vec())]
+        }
+        _ => inner(received),
+    };
+    let (io, device) = mock_device(handler);
+    let mut updater = Updater::new(io, SerialCodec::new())
+        .min_chunk_size(16)
+        .busy_retry_delay(Duration::from_millis(1));
+    let result = updater
+        .upload_firmware::<AtzResetter, _>(&firmware(&[0x1234], &[&[0xAA; 32]]), |_, _, _, _| {})
+        .await;
+    drop(updater);
+    let start_uploads = start_upload_sizes(&device.await.unwrap()).len();
+    (result, start_uploads)
+}
+
+#[tokio::test]
+async fn test_min_chunk_size() {
+    // Renegotiated on retry
+    let (result, start_uploads) = upload_with_chunk_offers(&[8, 16]).await;
+    result.unwrap();
+    assert_eq!(start_uploads, 2);
+
+    let (result, start_uploads) = upload_with_chunk_offers(&[8]).await;
+    assert!(matches!(
+        result,
+        Err(Error::ChunkSizeTooSmall {
+            offered: 8,
+            floor: 16
+        })
+    ));
+    assert_eq!(start_uploads, 2);
+}
+
+/// Sizes of the images `StartUploadRequest` was sent for.
+async fn upload_padded(pad_final_chunk: Option<u8>) -> Vec<Received> {
+    let (io, device) = mock_device(bootloader(0x1234, 16));
+    let mut updater = Updater::new(io, SerialCodec::new());
+    if let Some(fill) = pad_final_chunk {
+        updater = updater.pad_final_chunk(fill);
+    }
+    updater
+        .upload_firmware::<AtzResetter, _>(&firmware(&[0x1234], &[&[0xAA; 20]]), |_, _, _, _| {})
+        .await
+        .unwrap();
+    drop(updater);
+    device.await.unwrap()
+}
+
+fn sent_chunks(received: &[Received]) -> Vec<Vec<u8>> {
+    received
+        .iter()
+        .filter_map(|received| match received {
+            Received::Frame(frame) if frame.command == SendChunkRequest::COMMAND => {
+                Some(frame.data[2..].to_vec())
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn test_pad_final_chunk() {
+    let received = upload_padded(None).await;
+    assert_eq!(start_upload_sizes(&received), vec![20]);
+    assert_eq!(sent_chunks(&received), vec![vec![0xAA; 16], vec![0xAA; 4]]);
+
+    let received = upload_padded(Some(0xFF)).await;
+    assert_eq!(start_upload_sizes(&received), vec![32]);
+    let mut last = vec![0xAA; 4];
+    last.resize(16, 0xFF);
+    assert_eq!(sent_chunks(&received), vec![vec![0xAA; 16], last]);
+}
+
+#[test_case(0 ; "zero")]
+#[test_case(8 ; "below alignment")]
+#[tokio::test]
+async fn test_invalid_negotiation(offer: u16) {
+    let (io, _device) = mock_device(bootloader(0x1234, offer));
+    let mut updater = Updater::new(io, SerialCodec::new());
+    let result = updater
+        .upload_firmware::<AtzResetter, _>(&firmware(&[0x1234], &[&[0xAA; 32]]), |_, _, _, _| {})
+        .await;
+    assert!(matches!(
+        result,
+        Err(Error::InvalidNegotiation { max_chunk_size }) if max_chunk_size == offer
+    ));
+}
+
+fn start_upload_sizes(received: &[Received]) -> Vec<u8> {
+    received
+        .iter()
+        .filter_map(|received| match received {
+            Received::Frame(frame) if frame.command == StartUploadRequest::COMMAND => {
+                Some(frame.data[2])
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn test_resume_persisted_state() {
+    // Goes away while receiving the second chunk of the 32 byte image
+    let mut inner = bootloader(0x1234, 16);
+    let mut image_size = 0;
+    let handler = move |received: &Received| match received {
+        Received::Frame(frame) if frame.command == StartUploadRequest::COMMAND => {
+            image_size = frame.data[2];
+            inner(received)
+        }
+        Received::Frame(frame)
+            if frame.command == SendChunkRequest::COMMAND
+                && image_size == 32
+                && frame.data[..2] == [0x00, 0x01] =>
+        {
+            vec![Reply::Close]
+        }
+        _ => inner(received),
+    };
+    let (io, _device) = mock_device(handler);
+    let images: [&[u8]; 3] = [&[0xAA; 16], &[0xBB; 32], &[0xCC; 48]];
+
+    let mut updater = Updater::new(io, SerialCodec::new()).chunk_retry(1);
+    assert!(updater
+        .upload_firmware::<AtzResetter, _>(&firmware(&[0x1234], &images), |_, _, _, _| {})
+        .await
+        .is_err());
+    let state = updater.resume_state().unwrap().clone();
+    assert_eq!(
+        state,
+        ResumeState {
+            device_id: 0x1234,
+            image_idx: 1,
+            acked_chunks: 1,
+            chunk_size: 16,
+        }
+    );
+    let persisted = bincode::serialize(&state).unwrap();
+    drop(updater);
+
+    // A restarted process
+    let state: ResumeState = bincode::deserialize(&persisted).unwrap();
+    let firmware = firmware(&[0x1234], &images);
+    let (io, device) = mock_device(bootloader(0x1234, 16));
+    let mut updater = Updater::new(io, SerialCodec::new());
+    let report = updater
+        .resume::<AtzResetter, _>(&firmware, state.clone(), |_, _, _, _| {})
+        .await
+        .unwrap();
+    assert!(report.reset);
+    assert!(updater.resume_state().is_none());
+    drop(updater);
+    assert_eq!(start_upload_sizes(&device.await.unwrap()), vec![32, 48]);
+
+    let (io, device) = mock_device(bootloader(0x4321, 16));
+    let mut updater = Updater::new(io, SerialCodec::new());
+    let result = updater
+        .resume::<AtzResetter, _>(&firmware, state, |_, _, _, _| {})
+        .await;
+    assert!(matches!(
+        result,
+        Err(Error::DeviceIdMismatch {
+            expected: 0x1234,
+            actual: 0x4321
+        })
+    ));
+    drop(updater);
+    assert!(start_upload_sizes(&device.await.unwrap()).is_empty());
+}
+
+#[tokio::test]
+async fn test_resume_recovery_hint() {
+    // Saved once `StartUploadRequest` was accepted, before any chunk was acked
+    let state = ResumeState {
+        device_id: 0x1234,
+        image_idx: 0,
+        acked_chunks: 0,
+        chunk_size: 16,
+    };
+    let (io, _device) = mock_device(|_: &Received| vec![]);
+    let mut updater = Updater::new(io, SerialCodec::new())
+        .request_timeout(Duration::from_millis(50))
+        .resend_retry(0);
+    let result = updater
+        .resume::<AtzResetter, _>(&firmware(&[0x1234], &[&[0xAA; 32]]), state, |_, _, _, _| {})
+        .await;
+    assert!(matches!(result, Err(Error::Timeout)));
+    assert_eq!(
+        updater.recovery_hint(),
+        Some(RecoveryHint::RetryRecommended)
+    );
+}
+
+#[tokio::test]
+async fn test_resume_keeps_chunk_size() {
+    let (io, device) = mock_device(bootloader(0x1234, 64));
+    let firmware = firmware(&[0x1234], &[&[0xAA; 64]]);
+    let state = ResumeState {
+        device_id: 0x1234,
+        image_idx: 0,
+        acked_chunks: 1,
+        chunk_size: 16,
+    };
+
+    let mut updater = Updater::new(io, SerialCodec::new()).chunk_size(32);
+    updater
+        .resume::<AtzResetter, _>(&firmware, state, |_, _, _, _| {})
+        .await
+        .unwrap();
+    updater
+        .upload_firmware::<AtzResetter, _>(&firmware, |_, _, _, _| {})
+        .await
+        .unwrap();
+    drop(updater);
+
+    let chunk_sizes = sent_chunks(&device.await.unwrap())
+        .iter()
+        .map(|chunk| chunk.len())
+        .collect::<Vec<_>>();
+    assert_eq!(chunk_sizes, vec![16, 16, 16, 16, 32, 32]);
+}
+
+/// Acks the first transmission of chunk 1 with `echo` instead of its index.
+fn misacking_device(echo: u16) -> impl FnMut(&Received) -> Vec<Reply> + Send {
+    let mut inner = bootloader(0x1234, 16);
+    let mut misacked = false;
+    move |received| match received {
+        Received::Frame(frame)
+            if frame.command == SendChunkRequest::COMMAND
+                && frame.data[..2] == [0x00, 0x01]
+                && !misacked =>
+        {
+            misacked = true;
+            vec![ack(SendChunkRequest::COMMAND, echo.to_be_bytes().to_vec())]
+        }
+        _ => inner(received),
+    }
+}
+
+async fn upload_misacked(echo: u16, resend_on_chunk_desync: bool) -> Result<UploadReport, Error> {
+    let (io, _device) = mock_device(misacking_device(echo));
+    let mut updater =
+        Updater::new(io, SerialCodec::new()).resend_on_chunk_desync(resend_on_chunk_desync);
+    updater
+        .upload_firmware::<AtzResetter, _>(&firmware(&[0x1234], &[&[0xAA; 48]]), |_, _, _, _| {})
+        .await
+}
+
+#[tokio::test]
+async fn test_chunk_desync() {
+    // A stale ack of the previous chunk is resent
+    let report = upload_misacked(0, false).await.unwrap();
+    assert_eq!(report.chunk_retries, 1);
+
+    assert!(matches!(
+        upload_misacked(0x1234, false).await,
+        Err(Error::ChunkDesync {
+            expected: 1,
+            actual: 0x1234
+        })
+    ));
+
+    let report = upload_misacked(0x1234, true).await.unwrap();
+    assert_eq!(report.chunk_retries, 1);
+}
+
+#[tokio::test]
+async fn test_device_reset_mid_upload() {
+    // Restarts while chunk 1 is sent, announcing itself with a connect response
+    let mut inner = bootloader(0x1234, 16);
+    let mut browned_out = false;
+    let handler = move |received: &Received| match received {
+        Received::Frame(frame)
+            if frame.command == SendChunkRequest::COMMAND
+                && frame.data[..2] == [0x00, 0x01]
+                && !browned_out =>
+        {
+            browned_out = true;
+            vec![ack(ConnectRequest::COMMAND, vec![])]
+        }
+        _ => inner(received),
+    };
+    let (io, device) = mock_device(handler);
+    let mut updater = Updater::new(io, SerialCodec::new());
+    let firmware = || firmware(&[0x1234], &[&[0xAA; 48]]);
+    let result = updater
+        .upload_firmware::<AtzResetter, _>(&firmware(), |_, _, _, _| {})
+        .await;
+    assert!(matches!(result, Err(Error::DeviceReset)));
+
+    let state = updater.resume_state().unwrap().clone();
+    assert_eq!(state.acked_chunks, 1);
+    updater
+        .resume::<AtzResetter, _>(&firmware(), state, |_, _, _, _| {})
+        .await
+        .unwrap();
+    drop(updater);
+
+    // Failed straight away, without resending the chunk
+    let chunk_1 = device
+        .await
+        .unwrap()
+        .iter()
+        .filter(|received| {
+            matches!(
+                received,
+                Received::Frame(frame)
+                    if frame.command == SendChunkRequest::COMMAND && frame.data[..2] == [0x00, 0x01]
+            )
+        })
+        .count();
+    assert_eq!(chunk_1, 2);
+}
+
+#[tokio::test]
+async fn test_no_reset_on_complete() {
+    let (io, device) = mock_device(bootloader(0x1234, 16));
+    let mut updater = Updater::new(io, SerialCodec::new()).reset_on_complete(false);
+    let report = updater
+        .upload_firmware::<AtzResetter, _>(&firmware(&[0x1234], &[&[0xAA; 32]]), |_, _, _, _| {})
+        .await
+        .unwrap();
+    assert!(!report.reset);
+
+    // Still in the bootloader
+    assert_eq!(updater.device_id().await.unwrap(), 0x1234);
+    drop(updater);
+
+    assert!(!device.await.unwrap().iter().any(|received| matches!(
+        received,
+        Received::Frame(frame) if frame.command == ResetRequest::COMMAND
+    )));
+}
+
+fn device_name_reply(name: &'static [u8]) -> impl FnMut(&Received) -> Vec<Reply> + Send {
+    move |received| match received {
+        Received::Frame(frame) if frame.command == GetDeviceNameRequest::COMMAND => {
+            vec![ack(frame.command, name.to_vec())]
+        }
+        _ => vec![],
+    }
+}
+
+#[test_case(b"OBDLink MX+ Bluetooth Interface!", "OBDLink MX+ Bluetooth Interface!" ; "full length")]
+#[test_case(b"OBDLink SX\0", "OBDLink SX" ; "nul terminated")]
+#[test_case(b"STN1110\0\xFF\xFF", "STN1110" ; "nul terminated with trailing bytes")]
+#[tokio::test]
+async fn test_device_name(reply: &'static [u8], name: &str) {
+    let (io, _device) = mock_device(device_name_reply(reply));
+
+    let mut updater = Updater::new(io, SerialCodec::new());
+    assert_eq!(updater.device_name().await.unwrap(), name);
+}
+
+#[cfg(feature = "experimental")]
+#[test_case(0x00, ResetReason::Commanded ; "commanded")]
+#[test_case(0x01, ResetReason::Watchdog ; "watchdog")]
+#[test_case(0x02, ResetReason::Brownout ; "brownout")]
+#[test_case(0x7F, ResetReason::Unknown(0x7F) ; "unknown")]
+#[tokio::test]
+async fn test_reset_reason(reply: u8, reason: ResetReason) {
+    let (io, _device) = mock_device(move |received: &Received| match received {
+        Received::Frame(frame) if frame.command == GetResetReasonRequest::COMMAND => {
+            vec![ack(frame.command, vec![reply])]
+        }
+        _ => vec![],
+    });
+
+    let mut updater = Updater::new(io, SerialCodec::new());
+    assert_eq!(updater.reset_reason().await.unwrap(), reason);
+}
+
+#[tokio::test]
+async fn test_device_name_too_long() {
+    let (io, _device) = mock_device(device_name_reply(&[b'A'; 33]));
+
+    let mut updater = Updater::new(io, SerialCodec::new());
+    assert!(matches!(
+        updater.device_name().await,
+        Err(Error::IOError(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_upload_progress_image_indices() {
+    let (io, _device) = mock_device(bootloader(0x1234, 16));
+    let firmware = firmware(&[0x1234], &[&[0xAA; 32], &[0xBB; 48]]);
+    let calls = std::sync::Mutex::new(vec![]);
+
+    let mut updater = Updater::new(io, SerialCodec::new());
+    updater
+        .upload_firmware::<AtzResetter, _>(&firmware, |image_idx, num_images, idx, num_chunks| {
+            calls
+                .lock()
+                .unwrap()
+                .push((image_idx, num_images, idx, num_chunks));
+        })
+        .await
+        .unwrap();
+
+    assert_eq!(
+        calls.into_inner().unwrap(),
+        vec![
+            (0, 2, 0, 2),
+            (0, 2, 1, 2),
+            (1, 2, 0, 3),
+            (1, 2, 1, 3),
+            (1, 2, 2, 3)
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_upload_cancelled_from_callback() {
+    let (io, device) = mock_device(bootloader(0x1234, 16));
+    let firmware = firmware(&[0x1234], &[&[0xAA; 128]]);
+
+    let mut updater = Updater::new(io, SerialCodec::new());
+    let result = updater
+        .upload_firmware::<AtzResetter, _>(
+            &firmware,
+            chunk_progress(|idx, _| {
+                if idx == 3 {
+                    ControlFlow::Break(())
+                } else {
+                    ControlFlow::Continue(())
+                }
+            }),
+        )
+        .await;
+    assert!(matches!(result, Err(Error::Cancelled)));
+    drop(updater);
+
+    let commands = device
+        .await
+        .unwrap()
+        .into_iter()
+        .filter_map(|received| match received {
+            Received::Frame(frame) => Some(frame.command),
+            Received::Raw(_) => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        commands,
+        vec![
+            ConnectRequest::COMMAND,
+            GetDevIDRequest::COMMAND,
+            StartUploadRequest::COMMAND,
+            SendChunkRequest::COMMAND,
+            SendChunkRequest::COMMAND,
+            SendChunkRequest::COMMAND,
+            SendChunkRequest::COMMAND,
+            ResetRequest::COMMAND,
+        ]
+    );
+
+    // Still reported as cancelled if the reset fails
+    let (io, _device) = mock_device(ignoring_reset(bootloader(0x1234, 16)));
+    let mut updater =
+        Updater::new(io, SerialCodec::new()).request_timeout(Duration::from_millis(50));
+    let result = updater
+        .upload_firmware::<AtzResetter, _>(&firmware, |_, _, _, _| ControlFlow::Break(()))
+        .await;
+    assert!(matches!(result, Err(Error::Cancelled)));
+    assert_eq!(
+        updater.recovery_hint(),
+        Some(RecoveryHint::RetryRecommended)
+    );
+}
+
+#[cfg(feature = "experimental")]
+#[test]
+fn test_flash_info_encode_decode() {
+    let mut codec = SerialCodec::new();
+    let mut buf = bytes::BytesMut::new();
+    codec.encode(GetFlashInfoRequest.frame(), &mut buf).unwrap();
+    assert_eq!(
+        &buf[..5],
+        &[SerialCodec::STX, SerialCodec::STX, 0x0C, 0x00, 0x00]
+    );
+
+    let GetFlashInfoResponse {
+        start,
+        size,
+        page_size,
+    } = GetFlashInfoResponse::from_bytes(&[
+        0x00, 0x00, 0x40, 0x00, 0x00, 0x03, 0xC0, 0x00, 0x04, 0x00,
+    ])
+    .unwrap();
+    assert_eq!((start, size, page_size), (0x4000, 0x3C000, 0x400));
+}
+
+#[cfg(feature = "experimental")]
+#[test]
+fn test_image_crc_encode_decode() {
+    let mut codec = SerialCodec::new();
+    let mut buf = bytes::BytesMut::new();
+    codec.encode(GetImageCrcRequest.frame(), &mut buf).unwrap();
+    assert_eq!(
+        &buf[..5],
+        &[SerialCodec::STX, SerialCodec::STX, 0x09, 0x00, 0x00]
+    );
+
+    let response =
+        GetImageCrcResponse::from_bytes(&[0x00, 0x00, 0x01, 0x00, 0xDE, 0xAD, 0xBE, 0xEF]).unwrap();
+    assert_eq!(
+        response,
+        GetImageCrcResponse {
+            size: 0x100,
+            crc: 0xDEADBEEF
+        }
+    );
+}
+
+#[test_case(&[0x01, 0x00] ; "chunk size only")]
+#[test_case(&[0x01, 0x00, 0x01] ; "one trailing byte")]
+#[test_case(&[0x01, 0x00, 0x01, 0x23] ; "two trailing bytes")]
+#[test_case(&[0x01, 0x00, 0x01, 0x23, 0x45, 0x02] ; "four trailing bytes")]
+fn test_start_upload_response_trailing_bytes(bytes: &[u8]) {
+    assert_eq!(
+        StartUploadResponse::from_bytes(bytes).unwrap(),
+        StartUploadResponse {
+            max_chunk_size: 0x100
+        }
+    );
+}
+
+/// A device that last had `stored` programmed.
+#[cfg(feature = "experimental")]
+async fn upload_over(firmware: &FirmwareImage, stored: &'static [u8]) -> (UploadReport, Vec<u8>) {
+    let mut inner = bootloader(0x1234, 16);
+    let handler = move |received: &Received| match received {
+        Received::Frame(frame) if frame.command == GetImageCrcRequest::COMMAND => {
+            let mut data = (stored.len() as u32).to_be_bytes().to_vec();
+            data.extend_from_slice(&CRC32.checksum(stored).to_be_bytes());
+            vec![ack(frame.command, data)]
+        }
+        _ => inner(received),
+    };
+    let (io, device) = mock_device(handler);
+    let mut updater = Updater::new(io, SerialCodec::new()).skip_if_current(true);
+    let report = updater
+        .upload_firmware::<AtzResetter, _>(firmware, |_, _, _, _| {})
+        .await
+        .unwrap();
+    drop(updater);
+    (report, start_upload_sizes(&device.await.unwrap()))
+}
+
+#[cfg(feature = "experimental")]
+#[tokio::test]
+async fn test_skip_if_current() {
+    let single = firmware(&[0x1234], &[&[0xAA; 32]]);
+    let (report, start_uploads) = upload_over(&single, &[0xAA; 32]).await;
+    assert!(report.skipped);
+    assert!(report.reset);
+    assert!(start_uploads.is_empty());
+
+    // Same size, different contents
+    let (report, start_uploads) = upload_over(&single, &[0xAB; 32]).await;
+    assert!(!report.skipped);
+    assert_eq!(start_uploads, vec![32]);
+
+    let (report, start_uploads) = upload_over(&single, &[0xAA; 16]).await;
+    assert!(!report.skipped);
+    assert_eq!(start_uploads, vec![32]);
+
+    // Image 0 is only the error fallback of image 1, the upload goes 1, 2
+    let mut chained = firmware(&[0x1234], &[&[0xAA; 16], &[0xBB; 16], &[0xCC; 32]]);
+    chained.descriptors[0].next_idx = 0xFF;
+    chained.descriptors[1].image_type = 0x01;
+    chained.descriptors[1].error_idx = 0;
+    chained.validate().unwrap();
+    assert_eq!(chained.root_idx(), 1);
+    let (report, start_uploads) = upload_over(&chained, &[0xCC; 32]).await;
+    assert!(report.skipped);
+    assert!(start_uploads.is_empty());
+
+    let (report, start_uploads) = upload_over(&chained, &[0xAA; 16]).await;
+    assert!(!report.skipped);
+    assert_eq!(start_uploads, vec![16, 32]);
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct LittleEndianResponse {
+    value: u16,
+    offset: u32,
+}
+impl Response for LittleEndianResponse {
+    fn decode_options() -> DecodeOptions {
+        DecodeOptions::new().with_little_endian()
+    }
+}
+
+#[test]
+fn test_response_decode_options() {
+    let bytes = [0x34, 0x12, 0x00, 0x40, 0x00, 0x00];
+
+    let LittleEndianResponse { value, offset } = LittleEndianResponse::from_bytes(&bytes).unwrap();
+    assert_eq!((value, offset), (0x1234, 0x4000));
+
+    // Default options are still big-endian
+    let big_endian: (u16, u32) = DecodeOptions::default().deserialize(&bytes).unwrap();
+    assert_eq!(big_endian, (0x3412, 0x00400000));
+
+    let trailing = [0x34, 0x12, 0x00, 0x40, 0x00, 0x00, 0xFF];
+    assert!(LittleEndianResponse::from_bytes(&trailing).is_ok());
+    assert!(DecodeOptions::new()
+        .with_little_endian()
+        .reject_trailing_bytes()
+        .deserialize::<(u16, u32)>(&trailing)
+        .is_err());
+}
+
+#[cfg(feature = "experimental")]
+fn with_flash_size(
+    mut inner: impl FnMut(&Received) -> Vec<Reply> + Send,
+    size: u32,
+) -> impl FnMut(&Received) -> Vec<Reply> + Send {
+    move |received| match received {
+        Received::Frame(frame) if frame.command == GetFlashInfoRequest::COMMAND => {
+            let mut data = 0x4000u32.to_be_bytes().to_vec();
+            data.extend_from_slice(&size.to_be_bytes());
+            data.extend_from_slice(&0x400u16.to_be_bytes());
+            vec![ack(frame.command, data)]
+        }
+        _ => inner(received),
+    }
+}
+
+#[cfg(feature = "experimental")]
+#[tokio::test]
+async fn test_upload_rejects_image_too_large() {
+    let (io, device) = mock_device(with_flash_size(bootloader(0x1234, 16), 48));
+    let firmware = firmware(&[0x1234], &[&[0xAA; 32], &[0xBB; 64]]);
+
+    let mut updater = Updater::new(io, SerialCodec::new()).check_flash_size(true);
+    let result = updater
+        .upload_firmware::<AtzResetter, _>(&firmware, |_, _, _, _| {})
+        .await;
+    assert!(matches!(
+        result,
+        Err(Error::ImageTooLarge {
+            image_idx: 1,
+            offset: 32,
+            size: 64,
+            available: 48
+        })
+    ));
+    drop(updater);
+
+    let received = device.await.unwrap();
+    assert!(start_upload_sizes(&received).is_empty());
+    assert!(received.iter().any(|received| matches!(
+        received,
+        Received::Frame(frame) if frame.command == ResetRequest::COMMAND
+    )));
+
+    // Fits as is, but not once padded. A failed reset doesn't hide the error either.
+    let (io, device) = mock_device(ignoring_reset(with_flash_size(bootloader(0x1234, 16), 40)));
+    let padded = self::firmware(&[0x1234], &[&[0xAA; 36]]);
+    let mut updater = Updater::new(io, SerialCodec::new())
+        .request_timeout(Duration::from_millis(50))
+        .check_flash_size(true)
+        .pad_final_chunk(0xFF);
+    let result = updater
+        .upload_firmware::<AtzResetter, _>(&padded, |_, _, _, _| {})
+        .await;
+    assert!(matches!(
+        result,
+        Err(Error::ImageTooLarge {
+            image_idx: 0,
+            offset: 0,
+            size: 48,
+            available: 40
+        })
+    ));
+    drop(updater);
+    assert!(start_upload_sizes(&device.await.unwrap()).is_empty());
+
+    // Each image fits on its own, but the second is placed past the end of flash
+    let (io, device) = mock_device(with_flash_size(bootloader(0x1234, 16), 48));
+    let chained = self::firmware(&[0x1234], &[&[0xAA; 32], &[0xBB; 32]]);
+    let mut updater = Updater::new(io, SerialCodec::new()).check_flash_size(true);
+    let result = updater
+        .upload_firmware::<AtzResetter, _>(&chained, |_, _, _, _| {})
+        .await;
+    assert!(matches!(
+        result,
+        Err(Error::ImageTooLarge {
+            image_idx: 1,
+            offset: 32,
+            size: 32,
+            available: 48
+        })
+    ));
+    drop(updater);
+    assert!(start_upload_sizes(&device.await.unwrap()).is_empty());
+}
+
+#[tokio::test]
+async fn test_frame_stream() {
+    let frames = [
+        ResponseFrame::new(true, 0x03, vec![]),
+        ResponseFrame::new(false, 0x31, vec![0x00, 0x05]),
+        ResponseFrame::new(true, 0x07, vec![0x12, 0x34]),
+    ];
+    let wire = frames.iter().flat_map(encode_response).collect::<Vec<_>>();
+
+    let (mut device, io) = tokio::io::duplex(1024);
+    device.write_all(&wire).await.unwrap();
+    drop(device);
+    let received = frame_stream(io, SerialCodec::new())
+        .map(Result::unwrap)
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(received, frames);
+
+    let (mut device, io) = tokio::io::duplex(1024);
+    device.write_all(&wire).await.unwrap();
+    let mut updater = Updater::new(io, SerialCodec::new());
+    let received = updater
+        .frames()
+        .take(2)
+        .map(Result::unwrap)
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(received, frames[..2]);
+}
+
+#[tokio::test]
+async fn test_connect_assume_bootloader() {
+    let (io, device) = mock_device(bootloader(0x1234, 16));
+    let mut updater = Updater::new(io, SerialCodec::new());
+    updater.connect_assume_bootloader().await.unwrap();
+    drop(updater);
+    assert!(!device
+        .await
+        .unwrap()
+        .iter()
+        .any(|received| matches!(received, Received::Raw(_))));
+
+    // Still in the application, fails without trying to reset
+    let (io, device) = mock_device(slow_bootloader(Duration::ZERO));
+    let mut updater =
+        Updater::new(io, SerialCodec::new()).connect_timeout(Duration::from_millis(50));
+    assert!(matches!(
+        updater.connect_assume_bootloader().await,
+        Err(Error::Timeout)
+    ));
+    drop(updater);
+    assert!(!device
+        .await
+        .unwrap()
+        .iter()
+        .any(|received| matches!(received, Received::Raw(_))));
+}
+
+#[tokio::test]
+async fn test_connect_retry_zero() {
+    let (io, device) = mock_device(bootloader(0x1234, 16));
+    let mut updater = Updater::new(io, SerialCodec::new()).connect_retry(0);
+    updater.connect::<AtzResetter>().await.unwrap();
+    drop(updater);
+    let received = device.await.unwrap();
+    assert_eq!(received.len(), 1);
+    assert!(
+        matches!(&received[0], Received::Frame(frame) if frame.command == ConnectRequest::COMMAND)
+    );
+
+    // Would answer after a reset, but none is attempted
+    let (io, device) = mock_device(slow_bootloader(Duration::ZERO));
+    let mut updater = Updater::new(io, SerialCodec::new())
+        .connect_retry(0)
+        .connect_timeout(Duration::from_millis(50));
+    assert!(matches!(
+        updater.connect::<AtzResetter>().await,
+        Err(Error::Timeout)
+    ));
+    drop(updater);
+    assert!(!device
+        .await
+        .unwrap()
+        .iter()
+        .any(|received| matches!(received, Received::Raw(_))));
+}
+
+/// A device that only wakes up to the `nth` `ConnectRequest` it receives.
+fn sleepy_bootloader(nth: usize) -> impl FnMut(&Received) -> Vec<Reply> + Send {
+    let mut inner = bootloader(0x1234, 16);
+    let mut connects = 0;
+    move |received| match received {
+        Received::Frame(frame) if frame.command == ConnectRequest::COMMAND => {
+            connects += 1;
+            if connects >= nth {
+                inner(received)
+            } else {
+                vec![]
+            }
+        }
+        _ => inner(received),
+    }
+}
+
+#[tokio::test]
+async fn test_connect_wake_burst() {
+    let (io, device) = mock_device(sleepy_bootloader(4));
+    let mut updater = Updater::new(io, SerialCodec::new())
+        .connect_wake_burst(5, Duration::from_millis(20))
+        .connect_retry(0);
+    updater.connect::<AtzResetter>().await.unwrap();
+    drop(updater);
+
+    let received = device.await.unwrap();
+    assert!(!received
+        .iter()
+        .any(|received| matches!(received, Received::Raw(_))));
+    let connects = received
+        .iter()
+        .filter(|received| {
+            matches!(received, Received::Frame(frame) if frame.command == ConnectRequest::COMMAND)
+        })
+        .count();
+    assert_eq!(connects, 4);
+
+    // Single-shot by default
+    let (io, device) = mock_device(sleepy_bootloader(4));
+    let mut updater = Updater::new(io, SerialCodec::new())
+        .connect_timeout(Duration::from_millis(20))
+        .connect_retry(0);
+    assert!(matches!(
+        updater.connect::<AtzResetter>().await,
+        Err(Error::Timeout)
+    ));
+    drop(updater);
+    assert_eq!(device.await.unwrap().len(), 1);
+}
+
+/// A device that answers `GetDevIDRequest` with `before` until reset, and with `after` once it
+/// has reset into the bootloader. `ConnectRequest` is only answered after the reset.
+fn resetting_device(before: u16, after: u16) -> impl FnMut(&Received) -> Vec<Reply> + Send {
+    let mut bootloader = false;
+    move |received| match received {
+        Received::Raw(_) => {
+            bootloader = true;
+            vec![]
+        }
+        Received::Frame(frame) if frame.command == GetDevIDRequest::COMMAND => {
+            let device_id = if bootloader { after } else { before };
+            vec![ack(
+                GetDevIDRequest::COMMAND,
+                device_id.to_be_bytes().to_vec(),
+            )]
+        }
+        Received::Frame(frame) if bootloader && frame.command == ConnectRequest::COMMAND => {
+            vec![ack(ConnectRequest::COMMAND, vec![])]
+        }
+        Received::Frame(_) => vec![],
+    }
+}
+
+#[tokio::test]
+async fn test_connect_confirms_device_id_after_reset() {
+    let (io, device) = mock_device(resetting_device(0x1234, 0x1234));
+    let mut updater =
+        Updater::new(io, SerialCodec::new()).connect_timeout(Duration::from_millis(50));
+    assert_eq!(updater.device_id().await.unwrap(), 0x1234);
+    updater.connect::<AtzResetter>().await.unwrap();
+    drop(updater);
+
+    let queries = device
+        .await
+        .unwrap()
+        .iter()
+        .filter(|received| {
+            matches!(received, Received::Frame(frame) if frame.command == GetDevIDRequest::COMMAND)
+        })
+        .count();
+    assert_eq!(queries, 2);
+
+    let (io, _device) = mock_device(resetting_device(0x1234, 0x5678));
+    let mut updater =
+        Updater::new(io, SerialCodec::new()).connect_timeout(Duration::from_millis(50));
+    assert_eq!(updater.device_id().await.unwrap(), 0x1234);
+    assert!(matches!(
+        updater.connect::<AtzResetter>().await,
+        Err(Error::DeviceIdMismatch {
+            expected: 0x1234,
+            actual: 0x5678
+        })
+    ));
+}
+
+#[test]
+fn test_select_for_device() {
+    let images = [
+        firmware(&[0x1100, 0x1101], &[&[0x11; 16]]),
+        firmware(&[0x1200], &[&[0x12; 16]]),
+        firmware(&[0x1300, 0x1101], &[&[0x13; 16]]),
+    ];
+
+    let selected = FirmwareImage::select_for_device(&images, 0x1200).unwrap();
+    assert_eq!(selected.data, vec![0x12; 16]);
+
+    assert!(matches!(
+        FirmwareImage::select_for_device(&images, 0x1400),
+        Err(Error::NoMatchingImage { device_id: 0x1400 })
+    ));
+    match FirmwareImage::select_for_device(&images, 0x1101) {
+        Err(Error::AmbiguousImage { device_id, matches }) => {
+            assert_eq!((device_id, matches), (0x1101, vec![0, 2]))
+        }
+        _ => panic!("expected AmbiguousImage"),
+    }
+}
+
+async fn start_upload_attempts(
+    status: u8,
+    count: usize,
+    busy_retry: Option<usize>,
+) -> (Result<UploadReport, Error>, usize) {
+    let (io, device) = mock_device(nack_start_upload(bootloader(0x1234, 16), status, count));
+    let firmware = firmware(&[0x1234], &[&[0xAA; 20]]);
+
+    let mut updater =
+        Updater::new(io, SerialCodec::new()).busy_retry_delay(Duration::from_millis(10));
+    if let Some(busy_retry) = busy_retry {
+        updater = updater.busy_retry(busy_retry);
+    }
+    let result = updater
+        .upload_firmware::<AtzResetter, _>(&firmware, |_, _, _, _| {})
+        .await;
+    drop(updater);
+
+    let attempts = device
+        .await
+        .unwrap()
+        .iter()
+        .filter(|received| {
+            matches!(received, Received::Frame(frame) if frame.command == StartUploadRequest::COMMAND)
+        })
+        .count();
+    (result, attempts)
+}
+
+#[tokio::test]
+async fn test_start_upload_busy_retry() {
+    let (result, attempts) = start_upload_attempts(StartUploadRequest::BUSY, 2, Some(3)).await;
+    result.unwrap();
+    assert_eq!(attempts, 3);
+
+    let (result, attempts) = start_upload_attempts(StartUploadRequest::LOCKED, 5, Some(3)).await;
+    assert!(matches!(result, Err(Error::InvalidResponse(_))));
+    assert_eq!(attempts, 4);
+
+    // Any other status is a genuine rejection
+    let (result, attempts) = start_upload_attempts(0x7F, 1, Some(3)).await;
+    assert!(matches!(result, Err(Error::InvalidResponse(_))));
+    assert_eq!(attempts, 1);
+
+    // Not retried unless enabled
+    let (result, attempts) = start_upload_attempts(StartUploadRequest::BUSY, 1, None).await;
+    assert!(matches!(result, Err(Error::InvalidResponse(_))));
+    assert_eq!(attempts, 1);
+}
+
+#[test]
+fn test_firmware_header_padding() {
+    // Descriptor table padded out to 64 bytes before the payload
+    let first = (0..40).collect::<Vec<u8>>();
+    let second = vec![0xBB; 24];
+    let mut file = b"STNFWv05".to_vec();
+    file.extend_from_slice(&[0x01, 0x12, 0x34]);
+    file.push(0x02);
+    file.extend_from_slice(&[0x00, 0x00, 0x01, 0x00]);
+    file.extend_from_slice(&64u32.to_be_bytes());
+    file.extend_from_slice(&(first.len() as u32).to_be_bytes());
+    file.extend_from_slice(&[0x00, 0x00, 0xFF, 0x00]);
+    file.extend_from_slice(&(64 + first.len() as u32).to_be_bytes());
+    file.extend_from_slice(&(second.len() as u32).to_be_bytes());
+    assert!(file.len() < 64);
+    file.resize(64, 0xFF);
+    file.extend_from_slice(&first);
+    file.extend_from_slice(&second);
+
+    let firmware = FirmwareImage::from_bytes(file).unwrap();
+    assert_eq!(firmware.image_data(0), &first[..]);
+    assert_eq!(firmware.image_data(1), &second[..]);
+    assert_eq!(firmware.crc32(0), CRC32.checksum(&first));
+}
+
+#[test]
+fn test_firmware_open_descriptors() {
+    let mut file = b"STNFWv05".to_vec();
+    file.extend_from_slice(&[0x02, 0x11, 0x00, 0x11, 0x01]);
+    file.push(0x02);
+    file.extend_from_slice(&[
+        0x01, 0x00, 0x01, 0x01, 0x00, 0x00, 0x00, 0x26, 0x00, 0x00, 0x00, 0x10,
+    ]);
+    file.extend_from_slice(&[
+        0x10, 0x5A, 0xFF, 0xFF, 0x00, 0x00, 0x00, 0x36, 0x00, 0x00, 0x00, 0x08,
+    ]);
+    file.extend_from_slice(&[0xAA; 0x18]);
+
+    let path = std::env::temp_dir().join(format!("stn_updater_{}.bin", std::process::id()));
+    std::fs::write(&path, &file).unwrap();
+    let firmware = FirmwareImage::open(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(firmware.device_ids, [0x1100, 0x1101].into_iter().collect());
+    let fields = firmware
+        .descriptors
+        .iter()
+        .map(|descriptor| {
+            (
+                descriptor.image_type,
+                descriptor.reserved,
+                descriptor.next_idx,
+                descriptor.error_idx,
+                descriptor.image_offset,
+                descriptor.image_size,
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        fields,
+        vec![
+            (0x01, 0x00, 0x01, 0x01, 0x26, 0x10),
+            (0x10, 0x5A, 0xFF, 0xFF, 0x36, 0x08)
+        ]
+    );
+}
+
+/// Serializes a v05 container, images are chained in order.
+fn write_container(device_ids: &[u16], images: &[&[u8]]) -> Vec<u8> {
+    let mut file = b"STNFWv05".to_vec();
+    file.push(device_ids.len() as u8);
+    for device_id in device_ids {
+        file.extend_from_slice(&device_id.to_be_bytes());
+    }
+
+    file.push(images.len() as u8);
+    let mut image_offset = file.len() + images.len() * 12;
+    for (idx, image) in images.iter().enumerate() {
+        let next_idx = if idx + 1 < images.len() {
+            (idx + 1) as u8
+        } else {
+            0xFF
+        };
+        file.extend_from_slice(&[0x00, 0x00, next_idx, 0x00]);
+        file.extend_from_slice(&(image_offset as u32).to_be_bytes());
+        file.extend_from_slice(&(image.len() as u32).to_be_bytes());
+        image_offset += image.len();
+    }
+
+    for image in images {
+        file.extend_from_slice(image);
+    }
+    file
+}
+
+#[test]
+fn test_parse_limits() {
+    let limits = ParseLimits {
+        max_descriptors: 2,
+        max_device_ids: 3,
+    };
+    let file = write_container(&[0x1100, 0x1101, 0x1102], &[&[0xAA; 16], &[0xBB; 16]]);
+    let firmware = FirmwareImage::from_bytes_with_limits(file, limits).unwrap();
+    assert_eq!(firmware.num_images(), 2);
+
+    let file = write_container(&[0x1100, 0x1101, 0x1102, 0x1103], &[&[0xAA; 16]]);
+    assert!(matches!(
+        FirmwareImage::from_bytes_with_limits(file, limits),
+        Err(Error::TooManyDeviceIds { count: 4, max: 3 })
+    ));
+
+    let file = write_container(&[0x1100], &[&[0xAA; 16], &[0xBB; 16], &[0xCC; 16]]);
+    assert!(matches!(
+        FirmwareImage::from_bytes_with_limits(file.clone(), limits),
+        Err(Error::TooManyDescriptors { count: 3, max: 2 })
+    ));
+
+    let path = std::env::temp_dir().join(format!("stn_updater_limits_{}.bin", std::process::id()));
+    std::fs::write(&path, &file).unwrap();
+    let err = FirmwareImage::open_with_limits(&path, limits)
+        .err()
+        .unwrap();
+    assert!(FirmwareImage::open(&path).is_ok());
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[cfg(feature = "tracing")]
+mod tracing_events {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    type Fields = BTreeMap<String, String>;
+
+    /// Collects the fields of every event, formatted with `Debug`.
+    #[derive(Clone, Default)]
+    struct Collector(Arc<Mutex<Vec<Fields>>>);
+
+    struct FieldVisitor<'a>(&'a mut Fields);
+
+    impl Visit for FieldVisitor<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.0
+                .insert(field.name().to_string(), format!("{:?}", value));
+        }
+    }
+
+    impl Subscriber for Collector {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, _: &Attributes<'_>) -> Id {
+            Id::from_u64(1)
+        }
+
+        fn record(&self, _: &Id, _: &Record<'_>) {}
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            let mut fields = Fields::new();
+            event.record(&mut FieldVisitor(&mut fields));
+            self.0.lock().unwrap().push(fields);
+        }
+
+        fn enter(&self, _: &Id) {}
+
+        fn exit(&self, _: &Id) {}
+    }
+
+    fn open_traced(file: &[u8]) -> (std::io::Result<FirmwareImage>, Vec<Fields>) {
+        let path = std::env::temp_dir().join(format!(
+            "stn_updater_traced_{}_{}.bin",
+            std::process::id(),
+            file.len()
+        ));
+        std::fs::write(&path, file).unwrap();
+        let collector = Collector::default();
+        let result =
+            tracing::subscriber::with_default(collector.clone(), || FirmwareImage::open(&path));
+        std::fs::remove_file(&path).unwrap();
+        let events = collector.0.lock().unwrap().clone();
+        (result, events)
+    }
+
+    #[test]
+    fn test_firmware_open_events() {
+        let file = write_container(&[0x1100, 0x1234], &[&[0xAA; 80], &[0xBB; 40]]);
+        let (result, events) = open_traced(&file);
+        result.unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["message"], "Loaded firmware container");
+        assert_eq!(events[0]["version"], "\"05\"");
+        assert_eq!(events[0]["device_ids"], "{4352, 4660}");
+        assert_eq!(events[0]["images"], "2");
+        assert_eq!(events[0]["size"], file.len().to_string());
+
+        let mut file = file;
+        file[7] = b'6';
+        let (result, events) = open_traced(&file);
+        assert!(result.is_err());
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["message"], "Invalid firmware file version");
+        assert_eq!(events[0]["offset"], "6");
+        assert_eq!(events[0]["version"], "06");
+    }
+
+    #[tokio::test]
+    async fn test_drop_mid_upload_warns() {
+        let collector = Collector::default();
+        // With a single dispatcher registered, tracing caches a callsite's interest as seen from
+        // the thread hitting it first, e.g. another test dropping an `Updater` without a
+        // subscriber. A second one makes it ask every registered dispatcher instead.
+        let _second = tracing::Dispatch::new(tracing::subscriber::NoSubscriber::default());
+        let _guard = tracing::subscriber::set_default(collector.clone());
+
+        // Goes away after the first chunk
+        let mut inner = bootloader(0x1234, 16);
+        let handler = move |received: &Received| match received {
+            Received::Frame(frame)
+                if frame.command == SendChunkRequest::COMMAND
+                    && frame.data[..2] == [0x00, 0x01] =>
+            {
+                vec![Reply::Close]
+            }
+            _ => inner(received),
+        };
+        let (io, _device) = mock_device(handler);
+        let mut updater = Updater::new(io, SerialCodec::new());
+        let result = updater
+            .upload_firmware::<AtzResetter, _>(
+                &firmware(&[0x1234], &[&[0xAA; 48]]),
+                |_, _, _, _| {},
+            )
+            .await;
+        assert!(result.is_err());
+        assert!(updater.upload_in_progress());
+        drop(updater);
+
+        let events = collector.0.lock().unwrap().clone();
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            events[0]["message"],
+            "Updater dropped mid-upload, the device was not reset"
+        );
+
+        // Nothing to warn about once done
+        let (io, _device) = mock_device(bootloader(0x1234, 16));
+        let mut updater = Updater::new(io, SerialCodec::new()).reset_on_complete(false);
+        updater
+            .upload_firmware::<AtzResetter, _>(
+                &firmware(&[0x1234], &[&[0xAA; 48]]),
+                |_, _, _, _| {},
+            )
+            .await
+            .unwrap();
+        assert!(!updater.upload_in_progress());
+        drop(updater);
+        assert_eq!(collector.0.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_force_warns() {
+        let collector = Collector::default();
+        // See `test_drop_mid_upload_warns`
+        let _second = tracing::Dispatch::new(tracing::subscriber::NoSubscriber::default());
+        let _guard = tracing::subscriber::set_default(collector.clone());
+
+        let (io, _device) = mock_device(bootloader(0x1234, 16));
+        let mut updater = Updater::new(io, SerialCodec::new()).force(true);
+        updater
+            .upload_firmware::<AtzResetter, _>(
+                &firmware(&[0x1100], &[&[0xAA; 16]]),
+                |_, _, _, _| {},
+            )
+            .await
+            .unwrap();
+
+        let events = collector.0.lock().unwrap().clone();
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            events[0]["message"],
+            "Device ID doesn't match the firmware, flashing anyway as forced"
+        );
+        assert_eq!(events[0]["device_id"], "4660");
+    }
+}
+
+#[tokio::test]
+async fn test_firmware_from_async_reader() {
+    let file = write_container(&[0x1100, 0x1234], &[&[0xAA; 80], &[0xBB; 40]]);
+    let firmware = FirmwareImage::from_async_reader(tokio::io::BufReader::new(&file[..]))
+        .await
+        .unwrap();
+    assert_eq!(firmware.device_ids, BTreeSet::from([0x1100, 0x1234]));
+    assert_eq!(firmware.num_images(), 2);
+    assert_eq!(firmware.data, file);
+    assert_eq!(firmware.crc32(1), CRC32.checksum(&[0xBB; 40]));
+
+    // The header is checked before reading on
+    let reader = tokio::io::AsyncReadExt::chain(&b"STNFWv06"[..], BrokenIo);
+    match FirmwareImage::from_async_reader(reader).await {
+        Err(Error::IOError(err)) => assert_eq!(err.kind(), std::io::ErrorKind::InvalidData),
+        other => panic!("unexpected result: {:?}", other.err()),
+    }
+
+    // Same validation as `open`
+    let mut truncated = file.clone();
+    truncated.truncate(30);
+    assert!(matches!(
+        FirmwareImage::from_async_reader(&truncated[..]).await,
+        Err(Error::IOError(_))
+    ));
+    let mut cyclic = file;
+    cyclic[14 + 12 + 2] = 0x00;
+    assert!(matches!(
+        FirmwareImage::from_async_reader(&cyclic[..]).await,
+        Err(Error::ImageCycle { image_idx: 0 })
+    ));
+}
+
+#[tokio::test]
+async fn test_flash_file() {
+    let path = std::env::temp_dir().join(format!("stn_updater_flash_{}.bin", std::process::id()));
+    std::fs::write(&path, write_container(&[0x1234], &[&[0xAA; 48]])).unwrap();
+
+    let (io, device) = mock_device(bootloader(0x1234, 16));
+    let calls = std::sync::Mutex::new(vec![]);
+    let report =
+        flash_file::<AtzResetter, _, _, _>(io, SerialCodec::new(), &path, |_, _, idx, length| {
+            calls.lock().unwrap().push((idx, length));
+        })
+        .await
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(report.reset);
+    assert_eq!(calls.into_inner().unwrap(), vec![(0, 3), (1, 3), (2, 3)]);
+    let chunks = device
+        .await
+        .unwrap()
+        .iter()
+        .filter(|received| matches!(received, Received::Frame(frame) if frame.command == SendChunkRequest::COMMAND))
+        .count();
+    assert_eq!(chunks, 3);
+
+    let (io, _device) = mock_device(bootloader(0x1234, 16));
+    let result =
+        flash_file::<AtzResetter, _, _, _>(io, SerialCodec::new(), &path, |_, _, _, _| {}).await;
+    assert!(matches!(result, Err(Error::IOError(_))));
+}
+
+#[tokio::test]
+async fn test_capture_replay() {
+    let path = std::env::temp_dir().join(format!("stn_updater_capture_{}.bin", std::process::id()));
+    let data = (0..100).collect::<Vec<u8>>();
+
+    let (io, device) = mock_device(bootloader(0x1234, 32));
+    let mut updater =
+        Updater::new(io, SerialCodec::new()).recorder(FileRecorder::create(&path).unwrap());
+    let recorded = updater
+        .upload_firmware::<AtzResetter, _>(&firmware(&[0x1234], &[&data]), |_, _, _, _| {})
+        .await
+        .unwrap();
+    drop(updater);
+    let recorded_frames = device.await.unwrap();
+
+    let capture = load_capture(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(
+        capture.first(),
+        Some(&CaptureEntry::Tx(RequestFrame::new(
+            ConnectRequest::COMMAND,
+            vec![]
+        )))
+    );
+    assert!(capture
+        .iter()
+        .any(|entry| matches!(entry, CaptureEntry::Rx(frame) if frame.command == GetDevIDRequest::COMMAND)));
+
+    let replay = std::sync::Arc::new(std::sync::Mutex::new(Replay::new(capture)));
+    let handler = {
+        let replay = replay.clone();
+        move |received: &Received| match received {
+            Received::Frame(frame) => replay
+                .lock()
+                .unwrap()
+                .respond(frame)
+                .unwrap()
+                .into_iter()
+                .map(Reply::Frame)
+                .collect(),
+            Received::Raw(_) => vec![],
+        }
+    };
+    let (io, device) = mock_device(handler);
+    let mut updater = Updater::new(io, SerialCodec::new());
+    let replayed = updater
+        .upload_firmware::<AtzResetter, _>(&firmware(&[0x1234], &[&data]), |_, _, _, _| {})
+        .await
+        .unwrap();
+    drop(updater);
+
+    // Same behavior, only the timing differs
+    assert_eq!(
+        UploadReport {
+            link_rtt: None,
+            ..replayed
+        },
+        UploadReport {
+            link_rtt: None,
+            ..recorded
+        }
+    );
+    assert_eq!(device.await.unwrap(), recorded_frames);
+    assert!(replay.lock().unwrap().is_finished());
+}
+
+#[tokio::test]
+async fn test_wire_tap() {
+    // Both bytes of the device ID need stuffing on the way back
+    let (io, _device) = mock_device(bootloader(0x5504, 16));
+    let tapped = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let tap = {
+        let tapped = tapped.clone();
+        move |direction, bytes: &[u8]| tapped.lock().unwrap().push((direction, bytes.to_vec()))
+    };
+
+    let mut updater = Updater::new(WireTap::new(io, tap), SerialCodec::new());
+    assert_eq!(updater.device_id().await.unwrap(), 0x5504);
+    drop(updater);
+
+    let wire = |direction| {
+        tapped
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(seen, _)| *seen == direction)
+            .flat_map(|(_, bytes)| bytes.clone())
+            .collect::<Vec<u8>>()
+    };
+    let mut request = bytes::BytesMut::new();
+    SerialCodec::new()
+        .encode(GetDevIDRequest.frame(), &mut request)
+        .unwrap();
+    assert_eq!(wire(Direction::Tx), &request[..]);
+
+    let response = wire(Direction::Rx);
+    assert_eq!(
+        response,
+        encode_response(&ResponseFrame::new(
+            true,
+            GetDevIDRequest::COMMAND,
+            vec![0x55, 0x04]
+        ))
+    );
+    assert_eq!(&response[..2], &[SerialCodec::STX, SerialCodec::STX]);
+    assert_eq!(
+        &response[4..8],
+        &[SerialCodec::DLE, 0x55, SerialCodec::DLE, 0x04]
+    );
+    assert_eq!(response.last(), Some(&SerialCodec::ETX));
+}
+
+#[tokio::test]
+async fn test_json_trace() {
+    let path = std::env::temp_dir().join(format!("stn_updater_trace_{}.json", std::process::id()));
+    let (io, _device) = mock_device(bootloader(0x1234, 16));
+    let mut updater =
+        Updater::new(io, SerialCodec::new()).recorder(JsonRecorder::create(&path).unwrap());
+    updater.connect::<AtzResetter>().await.unwrap();
+    assert_eq!(updater.device_id().await.unwrap(), 0x1234);
+    drop(updater);
+
+    let trace = std::fs::read_to_string(&path).unwrap();
+    let entries = load_trace(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    // Everything but the timestamp
+    let lines = trace
+        .lines()
+        .map(|line| line.split_once(',').unwrap().1)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        lines,
+        vec![
+            r#""direction":"tx","command":3,"name":"Connect","ack":null,"data":""}"#,
+            r#""direction":"rx","command":3,"name":"Connect","ack":true,"data":""}"#,
+            r#""direction":"tx","command":7,"name":"GetDevID","ack":null,"data":""}"#,
+            r#""direction":"rx","command":7,"name":"GetDevID","ack":true,"data":"1234"}"#,
+        ]
+    );
+    assert!(trace.starts_with(r#"{"elapsed_us":"#));
+
+    assert_eq!(entries.len(), 4);
+    assert!(entries
+        .windows(2)
+        .all(|pair| pair[0].elapsed_us <= pair[1].elapsed_us));
+    assert_eq!(
+        entries[3],
+        TraceEntry {
+            elapsed_us: entries[3].elapsed_us,
+            direction: Direction::Rx,
+            command: GetDevIDRequest::COMMAND,
+            name: Some("GetDevID".to_string()),
+            ack: Some(true),
+            data: "1234".to_string(),
+        }
+    );
+}
+
+#[tokio::test]
+async fn test_upload_container_end_to_end() {
+    let first = (0..80).collect::<Vec<u8>>();
+    let second = (0..40).map(|byte| 0xFF - byte).collect::<Vec<u8>>();
+
+    let path = std::env::temp_dir().join(format!("stn_updater_e2e_{}.bin", std::process::id()));
+    std::fs::write(
+        &path,
+        write_container(&[0x1100, 0x1234], &[&first, &second]),
+    )
+    .unwrap();
+    let firmware = FirmwareImage::open(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let (io, device) = mock_device(bootloader(0x1234, 32));
+    let mut updater = Updater::new(io, SerialCodec::new());
+    let report = updater
+        .upload_firmware::<AtzResetter, _>(&firmware, |_, _, _, _| {})
+        .await
+        .unwrap();
+    assert_eq!(report.chunk_retries, 0);
+    drop(updater);
+
+    let chunk = |chunk_num: u16, data: &[u8]| {
+        (
+            SendChunkRequest::COMMAND,
+            [&chunk_num.to_be_bytes()[..], data].concat(),
+        )
+    };
+    let expected = vec![
+        (ConnectRequest::COMMAND, vec![]),
+        (GetDevIDRequest::COMMAND, vec![]),
+        (StartUploadRequest::COMMAND, vec![0x00, 0x00, 80, 0x01]),
+        chunk(0, &first[..32]),
+        chunk(1, &first[32..64]),
+        chunk(2, &first[64..]),
+        (StartUploadRequest::COMMAND, vec![0x00, 0x00, 40, 0x01]),
+        chunk(0, &second[..32]),
+        chunk(1, &second[32..]),
+        (ResetRequest::COMMAND, vec![]),
+    ];
+
+    let received = device
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|received| match received {
+            Received::Frame(frame) => (frame.command, frame.data),
+            Received::Raw(bytes) => panic!("unexpected raw bytes: {:?}", bytes),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(received, expected);
+}
+
+#[test]
+fn test_validate() {
+    let valid = || firmware(&[0x1234], &[&[0xAA; 16], &[0xBB; 16], &[0xCC; 16]]);
+    valid().validate().unwrap();
+
+    let mut firmware = valid();
+    firmware.device_ids.clear();
+    assert!(matches!(firmware.validate(), Err(Error::NoDeviceIds)));
+
+    let mut firmware = valid();
+    firmware.descriptors.clear();
+    assert!(matches!(firmware.validate(), Err(Error::NoImages)));
+
+    let mut firmware = valid();
+    firmware.descriptors[1].image_type = 0x02;
+    assert!(matches!(
+        firmware.validate(),
+        Err(Error::UnsupportedImageType {
+            image_idx: 1,
+            image_type: 0x02
+        })
+    ));
+
+    let mut firmware = valid();
+    firmware.descriptors[2].image_size = 17;
+    assert!(matches!(
+        firmware.validate(),
+        Err(Error::ImageOutOfBounds { image_idx: 2 })
+    ));
+
+    let mut firmware = valid();
+    firmware.descriptors[1].next_idx = 3;
+    assert!(matches!(
+        firmware.validate(),
+        Err(Error::InvalidImageIndex {
+            image_idx: 1,
+            target: 3
+        })
+    ));
+
+    // `error_idx` only counts for images tolerating errors
+    let mut firmware = valid();
+    firmware.descriptors[0].error_idx = 7;
+    firmware.validate().unwrap();
+    firmware.descriptors[0].image_type = 0x01;
+    assert!(matches!(
+        firmware.validate(),
+        Err(Error::InvalidImageIndex {
+            image_idx: 0,
+            target: 7
+        })
+    ));
+
+    let mut firmware = valid();
+    firmware.descriptors[2].next_idx = 1;
+    assert!(matches!(
+        firmware.validate(),
+        Err(Error::ImageCycle { image_idx: 1 })
+    ));
+
+    let mut firmware = valid();
+    firmware.descriptors[1].image_type = 0x01;
+    firmware.descriptors[1].error_idx = 0;
+    assert!(matches!(
+        firmware.validate(),
+        Err(Error::ImageCycle { image_idx: 0 })
+    ));
+
+    // Two chains, so no telling which one to upload
+    let mut firmware = valid();
+    firmware.descriptors[0].next_idx = 0xFF;
+    assert!(matches!(
+        firmware.validate(),
+        Err(Error::AmbiguousRoot { roots }) if roots == vec![0, 1]
+    ));
+
+    // Rejected by `open` too
+    let mut file = write_container(&[0x1234], &[&[0xAA; 16], &[0xBB; 16]]);
+    // Second descriptor's next_idx back to the first
+    file[12 + 12 + 2] = 0x00;
+    let path = std::env::temp_dir().join(format!("stn_updater_cycle_{}.bin", std::process::id()));
+    std::fs::write(&path, &file).unwrap();
+    let err = FirmwareImage::open(&path).err().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert!(matches!(
+        err.get_ref().and_then(|err| err.downcast_ref::<Error>()),
+        Some(Error::ImageCycle { image_idx: 0 })
+    ));
+}
+
+#[tokio::test]
+async fn test_tolerate_errors_follows_error_idx() {
+    let (io, device) = mock_device(nack_start_upload(bootloader(0x1234, 16), 0x7F, 1));
+    let mut firmware = firmware(&[0x1234], &[&[0xAA; 16], &[0xBB; 32], &[0xCC; 48]]);
+    firmware.descriptors[0].image_type = 0x01;
+    firmware.descriptors[0].error_idx = 2;
+
+    let mut updater = Updater::new(io, SerialCodec::new());
+    updater
+        .upload_firmware::<AtzResetter, _>(&firmware, |_, _, _, _| {})
+        .await
+        .unwrap();
+    drop(updater);
+
+    let sizes = device
+        .await
+        .unwrap()
+        .iter()
+        .filter_map(|received| match received {
+            Received::Frame(frame) if frame.command == StartUploadRequest::COMMAND => {
+                Some(frame.data[2])
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(sizes, vec![16, 48]);
+}
+
+#[tokio::test]
+async fn test_upload_starts_at_root() {
+    let (io, device) = mock_device(bootloader(0x1234, 16));
+    let mut firmware = firmware(&[0x1234], &[&[0xAA; 16], &[0xBB; 32], &[0xCC; 48]]);
+    firmware.descriptors[2].next_idx = 0;
+    firmware.descriptors[1].next_idx = 0xFF;
+    firmware.validate().unwrap();
+    assert_eq!(firmware.root_idx(), 2);
+    assert_eq!(firmware.num_images(), 3);
+    assert_eq!(
+        UploadPlan::new(&firmware, 16).unwrap().image_sizes,
+        vec![48, 16, 32]
+    );
+
+    let mut updater = Updater::new(io, SerialCodec::new());
+    updater
+        .upload_firmware::<AtzResetter, _>(&firmware, |_, _, _, _| {})
+        .await
+        .unwrap();
+    drop(updater);
+
+    assert_eq!(start_upload_sizes(&device.await.unwrap()), vec![48, 16, 32]);
+}
+
+#[tokio::test]
+async fn test_device_mismatch_sorted_ids() {
+    let ids = [0x1300, 0x1100, 0x1400, 0x1200];
+    for rotation in 0..ids.len() {
+        let mut ids = ids;
+        ids.rotate_left(rotation);
+        let firmware = firmware(&ids, &[&[0xAA; 16]]);
+        assert_eq!(
+            firmware.device_ids.iter().copied().collect::<Vec<_>>(),
+            vec![0x1100, 0x1200, 0x1300, 0x1400]
+        );
+
+        let (io, device) = mock_device(bootloader(0x1234, 16));
+        let mut updater = Updater::new(io, SerialCodec::new());
+        match updater
+            .upload_firmware::<AtzResetter, _>(&firmware, |_, _, _, _| {})
+            .await
+        {
+            Err(Error::DeviceMismatch {
+                device_id,
+                expected,
+            }) => assert_eq!(
+                (device_id, expected),
+                (0x1234, vec![0x1100, 0x1200, 0x1300, 0x1400])
+            ),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        drop(updater);
+
+        assert!(!device.await.unwrap().iter().any(|received| matches!(
+            received,
+            Received::Frame(frame) if frame.command == StartUploadRequest::COMMAND
+        )));
+    }
+}
+
+/// Never answers `ResetRequest`.
+fn ignoring_reset(
+    mut inner: impl FnMut(&Received) -> Vec<Reply> + Send,
+) -> impl FnMut(&Received) -> Vec<Reply> + Send {
+    move |received| match received {
+        Received::Frame(frame) if frame.command == ResetRequest::COMMAND => vec![],
+        _ => inner(received),
+    }
+}
+
+#[tokio::test]
+async fn test_device_mismatch_reset_fails() {
+    let (io, _device) = mock_device(ignoring_reset(bootloader(0x1234, 16)));
+    let mut updater =
+        Updater::new(io, SerialCodec::new()).request_timeout(Duration::from_millis(50));
+    let result = updater
+        .upload_firmware::<AtzResetter, _>(&firmware(&[0x1100], &[&[0xAA; 16]]), |_, _, _, _| {})
+        .await;
+    assert!(matches!(
+        result,
+        Err(Error::DeviceMismatch {
+            device_id: 0x1234,
+            ..
+        })
+    ));
+}
+
+#[tokio::test]
+async fn test_transform() {
+    let (io, device) = mock_device(bootloader(0x1234, 16));
+    let mut updater = Updater::new(io, SerialCodec::new()).transform(|image_idx, data| {
+        if image_idx == 1 {
+            data[4..6].copy_from_slice(&[0x12, 0x34]);
+        }
+    });
+    let firmware = firmware(&[0x1234], &[&[0xAA; 16], &[0xBB; 16]]);
+    updater
+        .upload_firmware::<AtzResetter, _>(&firmware, |_, _, _, _| {})
+        .await
+        .unwrap();
+    drop(updater);
+
+    let mut patched = vec![0xBB; 16];
+    patched[4..6].copy_from_slice(&[0x12, 0x34]);
+    assert_eq!(
+        sent_chunks(&device.await.unwrap()),
+        vec![vec![0xAA; 16], patched]
+    );
+    // Only the uploaded copy is patched
+    assert_eq!(firmware.image_data(1), &[0xBB; 16]);
+}
+
+#[tokio::test]
+async fn test_force() {
+    let (io, device) = mock_device(bootloader(0x1234, 16));
+    let mut updater = Updater::new(io, SerialCodec::new()).force(true);
+    updater
+        .upload_firmware::<AtzResetter, _>(&firmware(&[0x1100], &[&[0xAA; 16]]), |_, _, _, _| {})
+        .await
+        .unwrap();
+    drop(updater);
+
+    assert_eq!(sent_chunks(&device.await.unwrap()), vec![vec![0xAA; 16]]);
+}
+
+#[tokio::test]
+async fn test_device_not_allowed() {
+    let (io, device) = mock_device(bootloader(0x1234, 16));
+    let mut updater = Updater::new(io, SerialCodec::new()).allowed_device_ids([0x1100, 0x1200]);
+    let result = updater
+        .upload_firmware::<AtzResetter, _>(&firmware(&[0x1234], &[&[0xAA; 16]]), |_, _, _, _| {})
+        .await;
+    assert!(matches!(
+        result,
+        Err(Error::DeviceNotAllowed { device_id: 0x1234 })
+    ));
+    drop(updater);
+
+    assert!(start_upload_sizes(&device.await.unwrap()).is_empty());
+
+    let (io, _device) = mock_device(ignoring_reset(bootloader(0x1234, 16)));
+    let mut updater = Updater::new(io, SerialCodec::new())
+        .request_timeout(Duration::from_millis(50))
+        .allowed_device_ids([0x1100]);
+    let result = updater
+        .upload_firmware::<AtzResetter, _>(&firmware(&[0x1234], &[&[0xAA; 16]]), |_, _, _, _| {})
+        .await;
+    assert!(matches!(
+        result,
+        Err(Error::DeviceNotAllowed { device_id: 0x1234 })
+    ));
+}
+
+async fn upload_on_hw_version(supported: &[(u8, u8)]) -> (Result<UploadReport, Error>, usize) {
+    let mut inner = bootloader(0x1234, 16);
+    let handler = move |received: &Received| match received {
+        Received::Frame(frame) if frame.command == GetHWRevRequest::COMMAND => {
+            vec![ack(frame.command, vec![0x01, 0x02])]
+        }
+        _ => inner(received),
+    };
+    // A failed reset doesn't hide why the upload was refused
+    let (io, device) = mock_device(ignoring_reset(handler));
+    let mut updater = Updater::new(io, SerialCodec::new())
+        .request_timeout(Duration::from_millis(50))
+        .reset_on_complete(false)
+        .supported_hw_versions(supported.iter().copied());
+    let result = updater
+        .upload_firmware::<AtzResetter, _>(&firmware(&[0x1234], &[&[0xAA; 16]]), |_, _, _, _| {})
+        .await;
+    drop(updater);
+    (result, start_upload_sizes(&device.await.unwrap()).len())
+}
+
+#[tokio::test]
+async fn test_supported_hw_versions() {
+    let (result, start_uploads) = upload_on_hw_version(&[(1, 1), (1, 2)]).await;
+    result.unwrap();
+    assert_eq!(start_uploads, 1);
+
+    // The device ID matches, the board doesn't
+    let (result, start_uploads) = upload_on_hw_version(&[(1, 1), (2, 0)]).await;
+    assert!(matches!(
+        result,
+        Err(Error::UnsupportedHardware { found: (1, 2) })
+    ));
+    assert_eq!(start_uploads, 0);
+}
+
+async fn upload_with_failing_image(
+    continue_on_image_error: bool,
+) -> (Result<UploadReport, Error>, Vec<u8>) {
+    // Rejects the upload of the 32 byte image
+    let mut inner = bootloader(0x1234, 16);
+    let handler = move |received: &Received| match received {
+        Received::Frame(frame)
+            if frame.command == StartUploadRequest::COMMAND && frame.data[2] == 32 =>
+        {
+            vec![Reply::Frame(ResponseFrame::new(
+                false,
+                StartUploadRequest::COMMAND,
+                vec![0x7F],
+            ))]
+        }
+        _ => inner(received),
+    };
+    let (io, device) = mock_device(handler);
+    let firmware = firmware(&[0x1234], &[&[0xAA; 16], &[0xBB; 32], &[0xCC; 48]]);
+
+    let mut updater =
+        Updater::new(io, SerialCodec::new()).continue_on_image_error(continue_on_image_error);
+    let result = updater
+        .upload_firmware::<AtzResetter, _>(&firmware, |_, _, _, _| {})
+        .await;
+    drop(updater);
+
+    let sizes = device
+        .await
+        .unwrap()
+        .iter()
+        .filter_map(|received| match received {
+            Received::Frame(frame) if frame.command == StartUploadRequest::COMMAND => {
+                Some(frame.data[2])
+            }
+            _ => None,
+        })
+        .collect();
+    (result, sizes)
+}
+
+#[tokio::test]
+async fn test_continue_on_image_error() {
+    let (result, sizes) = upload_with_failing_image(true).await;
+    assert_eq!(
+        result.unwrap().failed_images,
+        BTreeMap::from([(1, "InvalidResponse".to_string())])
+    );
+    assert_eq!(sizes, vec![16, 32, 48]);
+
+    let (result, sizes) = upload_with_failing_image(false).await;
+    assert!(matches!(result, Err(Error::InvalidResponse(_))));
+    assert_eq!(sizes, vec![16, 32]);
+}
+
+#[tokio::test]
+async fn test_image_reports() {
+    let (result, _) = upload_with_failing_image(true).await;
+    let report = result.unwrap();
+    assert_eq!(
+        report.images,
+        vec![
+            ImageReport {
+                image_idx: 0,
+                bytes_written: 16,
+                chunk_retries: 0,
+                max_chunk_retries: 0,
+                chunk_size: Some(16),
+                chunk_size_dropped_from: None,
+                error: None,
+            },
+            ImageReport {
+                image_idx: 1,
+                bytes_written: 0,
+                chunk_retries: 0,
+                max_chunk_retries: 0,
+                chunk_size: None,
+                chunk_size_dropped_from: None,
+                error: Some("InvalidResponse".to_string()),
+            },
+            ImageReport {
+                image_idx: 2,
+                bytes_written: 48,
+                chunk_retries: 0,
+                max_chunk_retries: 0,
+                chunk_size: Some(16),
+                chunk_size_dropped_from: None,
+                error: None,
+            },
+        ]
+    );
+    assert_eq!(report.chunk_retries, 0);
+}
+
+#[tokio::test]
+async fn test_chunk_size_dropped() {
+    let mut inner = bootloader(0x1234, 16);
+    let mut offers = [64u16, 16, 32].into_iter();
+    let handler = move |received: &Received| match received {
+        Received::Frame(frame) if frame.command == StartUploadRequest::COMMAND => {
+            let offer = offers.next().unwrap();
+            vec![ack(frame.command, offer.to_be_bytes().to_vec())]
+        }
+        _ => inner(received),
+    };
+    let (io, _device) = mock_device(handler);
+    let firmware = firmware(&[0x1234], &[&[0xAA; 64], &[0xBB; 64], &[0xCC; 64]]);
+
+    let mut updater = Updater::new(io, SerialCodec::new());
+    let report = updater
+        .upload_firmware::<AtzResetter, _>(&firmware, |_, _, _, _| {})
+        .await
+        .unwrap();
+
+    let negotiated = report
+        .images
+        .iter()
+        .map(|image| (image.chunk_size, image.chunk_size_dropped_from))
+        .collect::<Vec<_>>();
+    // Half the earlier size is still fine
+    assert_eq!(
+        negotiated,
+        vec![(Some(64), None), (Some(16), Some(64)), (Some(32), None)]
+    );
+}
+
+#[tokio::test]
+async fn test_region_offsets() {
+    for region_offsets in [false, true] {
+        let (io, device) = mock_device(bootloader(0x1234, 16));
+        let firmware = firmware(&[0x1234], &[&[0xAA; 16], &[0xBB; 32]]);
+        let mut updater = Updater::new(io, SerialCodec::new()).region_offsets(region_offsets);
+        updater
+            .upload_firmware::<AtzResetter, _>(&firmware, |_, _, _, _| {})
+            .await
+            .unwrap();
+        drop(updater);
+
+        let offsets = device
+            .await
+            .unwrap()
+            .iter()
+            .filter_map(|received| match received {
+                Received::Frame(frame) if frame.command == StartUploadRequest::COMMAND => {
+                    Some(frame.data[4..].to_vec())
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let expected = if region_offsets {
+            firmware
+                .descriptors
+                .iter()
+                .map(|descriptor| descriptor.image_offset.to_be_bytes().to_vec())
+                .collect()
+        } else {
+            vec![vec![], vec![]]
+        };
+        assert_eq!(offsets, expected);
+    }
+}
+
+#[tokio::test]
+async fn test_zero_size_image() {
+    let mut firmware = firmware(&[0x1234], &[&[0xAA; 16], &[], &[0xCC; 48]]);
+    assert!(matches!(
+        firmware.validate(),
+        Err(Error::EmptyImage { image_idx: 1 })
+    ));
+
+    // An empty validation image is skipped without starting an upload
+    firmware.descriptors[1].image_type = 0x10;
+    let (io, device) = mock_device(bootloader(0x1234, 16));
+    let mut updater = Updater::new(io, SerialCodec::new());
+    updater
+        .upload_firmware::<AtzResetter, _>(&firmware, |_, _, _, _| {})
+        .await
+        .unwrap();
+    drop(updater);
+
+    let sizes = device
+        .await
+        .unwrap()
+        .iter()
+        .filter_map(|received| match received {
+            Received::Frame(frame) if frame.command == StartUploadRequest::COMMAND => {
+                Some(frame.data[2])
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(sizes, vec![16, 48]);
+}
+
+async fn upload_modes(program_mode: UploadMode, validation_mode: Option<UploadMode>) -> Vec<u8> {
+    let (io, device) = mock_device(bootloader(0x1234, 16));
+    let mut firmware = firmware(&[0x1234], &[&[0xAA; 20], &[0xAA; 20]]);
+    firmware.descriptors[1].image_type = 0x10;
+
+    let mut updater = Updater::new(io, SerialCodec::new()).program_mode(program_mode);
+    if let Some(validation_mode) = validation_mode {
+        updater = updater.validation_mode(validation_mode);
+    }
+    updater
+        .upload_firmware::<AtzResetter, _>(&firmware, |_, _, _, _| {})
+        .await
+        .unwrap();
+    drop(updater);
+
+    device
+        .await
+        .unwrap()
+        .iter()
+        .filter_map(|received| match received {
+            Received::Frame(frame) if frame.command == StartUploadRequest::COMMAND => {
+                frame.data.last().copied()
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+#[test_case(UploadMode::Program, None, &[0x01, 0x01] ; "default")]
+#[test_case(UploadMode::ProgramVerify, None, &[0x02, 0x01] ; "program-verify")]
+#[test_case(UploadMode::Program, Some(UploadMode::Verify), &[0x01, 0x03] ; "verify validation images")]
+#[tokio::test]
+async fn test_upload_mode_per_image_type(
+    program_mode: UploadMode,
+    validation_mode: Option<UploadMode>,
+    modes: &[u8],
+) {
+    assert_eq!(upload_modes(program_mode, validation_mode).await, modes);
+}
+
+#[tokio::test]
+async fn test_overlapping_images() {
+    let mut firmware = firmware(&[0x1234], &[&[0xAA; 32], &[0xBB; 32], &[0xCC; 32]]);
+    firmware.descriptors[2].image_offset = 16;
+    assert!(matches!(
+        firmware.validate(),
+        Err(Error::OverlappingImages { a: 0, b: 2 })
+    ));
+
+    // Validation images may re-cover programmed data
+    firmware.descriptors[2].image_type = 0x10;
+    firmware.validate().unwrap();
+
+    firmware.descriptors[2].image_type = 0x00;
+    let (io, device) = mock_device(bootloader(0x1234, 16));
+    let mut updater = Updater::new(io, SerialCodec::new());
+    let result = updater
+        .upload_firmware::<AtzResetter, _>(&firmware, |_, _, _, _| {})
+        .await;
+    assert!(matches!(
+        result,
+        Err(Error::OverlappingImages { a: 0, b: 2 })
+    ));
+    drop(updater);
+    assert!(device.await.unwrap().is_empty());
+}
+
+#[test_case(&[1000], 256, LinkParams { bytes_per_sec: 1000, round_trip: Duration::from_millis(10) }, 4, Duration::from_millis(1147); "single image")]
+#[test_case(&[65536], 1024, LinkParams::serial(115200, Duration::from_millis(20)), 64, Duration::from_nanos(7_096_267_361); "serial 64k")]
+#[test_case(&[100, 50], 64, LinkParams { bytes_per_sec: 1000, round_trip: Duration::ZERO }, 3, Duration::from_millis(249); "two images, wire only")]
+fn test_estimated_duration(
+    sizes: &[usize],
+    chunk_size: usize,
+    link: LinkParams,
+    num_chunks: usize,
+    expected: Duration,
+) {
+    let images = sizes
+        .iter()
+        .map(|&size| vec![0xAA; size])
+        .collect::<Vec<_>>();
+    let images = images.iter().map(|image| &image[..]).collect::<Vec<_>>();
+    let plan = UploadPlan::new(&firmware(&[0x1234], &images), chunk_size).unwrap();
+    assert_eq!(
+        plan.image_sizes,
+        sizes.iter().map(|&size| size as u32).collect::<Vec<_>>()
+    );
+    assert_eq!(plan.num_chunks(), num_chunks);
+    assert_eq!(plan.estimated_duration(link), expected);
+}
+
+#[test]
+fn test_upload_plan_zero_chunk_size() {
+    assert!(matches!(
+        UploadPlan::new(&firmware(&[0x1234], &[&[0xAA; 16]]), 0),
+        Err(Error::InvalidChunkSize { chunk_size: 0 })
+    ));
+}
+
+#[test_case(160, Duration::from_millis(100) ; "limited")]
+#[test_case(0, Duration::ZERO ; "zero is unlimited")]
+#[tokio::test(start_paused = true)]
+async fn test_max_throughput(rate: u32, expected_gap: Duration) {
+    let sent = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let mut inner = bootloader(0x1234, 16);
+    let handler = {
+        let sent = sent.clone();
+        move |received: &Received| {
+            if let Received::Frame(frame) = received {
+                if frame.command == SendChunkRequest::COMMAND {
+                    sent.lock().unwrap().push(tokio::time::Instant::now());
+                }
+            }
+            inner(received)
+        }
+    };
+    let (io, _device) = mock_device(handler);
+
+    // 16 byte chunks at 160 bytes per second are 100 ms apart
+    let mut updater = Updater::new(io, SerialCodec::new()).max_throughput_bytes_per_sec(rate);
+    updater
+        .upload_firmware::<AtzResetter, _>(&firmware(&[0x1234], &[&[0xAA; 64]]), |_, _, _, _| {})
+        .await
+        .unwrap();
+
+    let sent = sent.lock().unwrap();
+    assert_eq!(sent.len(), 4);
+    for pair in sent.windows(2) {
+        let gap = pair[1] - pair[0];
+        assert!(
+            gap >= expected_gap && gap < expected_gap + Duration::from_millis(1),
+            "{:?}",
+            gap
+        );
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_device_info() {
+    let mut inner = bootloader(0x1234, 16);
+    let handler = move |received: &Received| match received {
+        Received::Frame(frame) => match frame.command {
+            // Unsupported by this bootloader
+            GetHWRevRequest::COMMAND => {
+                vec![Reply::Frame(ResponseFrame::new(
+                    false,
+                    frame.command,
+                    vec![],
+                ))]
+            }
+            GetSerialNumberRequest::COMMAND => vec![ack(frame.command, b"12345678".to_vec())],
+            // Never answered, left to the budget
+            GetDeviceNameRequest::COMMAND => vec![],
+            _ => inner(received),
+        },
+        Received::Raw(_) => vec![],
+    };
+    let (io, _device) = mock_device(handler);
+    let mut updater = Updater::new(io, SerialCodec::new()).request_timeout(Duration::from_secs(1));
+
+    let start = tokio::time::Instant::now();
+    let info = updater
+        .device_info(Duration::from_millis(500))
+        .await
+        .unwrap();
+    assert_eq!(start.elapsed(), Duration::from_millis(500));
+    assert_eq!(info.device_id, 0x1234);
+    assert_eq!(info.bootloader_version, Some((2, 0)));
+    assert_eq!(info.hw_version, None);
+    assert_eq!(info.serial_number.as_deref(), Some("12345678"));
+    assert_eq!(info.device_name, None);
+    #[cfg(feature = "experimental")]
+    assert!(info.flash_info.is_none());
+
+    // The budget is lifted again
+    assert_eq!(updater.deadline(), None);
+}
+
+#[tokio::test]
+async fn test_firmware_state() {
+    let mut inner = versioned(bootloader(0x1234, 16), (1, 2));
+    let handler = move |received: &Received| match received {
+        Received::Frame(frame) if frame.command == GetFWStatusRequest::COMMAND => {
+            vec![ack(frame.command, vec![0x01])]
+        }
+        _ => inner(received),
+    };
+    let (io, _device) = mock_device(handler);
+    let mut updater = Updater::new(io, SerialCodec::new());
+
+    assert_eq!(
+        updater.firmware_state().await.unwrap(),
+        FirmwareState {
+            bootloader_version: (1, 2),
+            status: FirmwareStatus::Invalid,
+        }
+    );
+}
+
+/// Answers connects after `connect_delay` and the first chunk after `chunk_delay`.
+fn laggy_bootloader(
+    connect_delay: Duration,
+    chunk_delay: Duration,
+) -> impl FnMut(&Received) -> Vec<Reply> + Send {
+    let mut inner = bootloader(0x1234, 16);
+    let mut first_chunk = true;
+    move |received| {
+        let delay = match received {
+            Received::Frame(frame) if frame.command == ConnectRequest::COMMAND => connect_delay,
+            Received::Frame(frame) if frame.command == SendChunkRequest::COMMAND && first_chunk => {
+                first_chunk = false;
+                chunk_delay
+            }
+            _ => Duration::ZERO,
+        };
+        let mut replies = vec![Reply::Delay(delay)];
+        replies.extend(inner(received));
+        replies
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_link_rtt() {
+    let (io, _device) = mock_device(laggy_bootloader(
+        Duration::from_millis(50),
+        Duration::from_secs(6),
+    ));
+    let mut updater = Updater::new(io, SerialCodec::new()).chunk_timeout_rtt_factor(200);
+    assert_eq!(updater.link_rtt(), None);
+    let report = updater
+        .upload_firmware::<AtzResetter, _>(&firmware(&[0x1234], &[&[0xAA; 32]]), |_, _, _, _| {})
+        .await
+        .unwrap();
+
+    let link_rtt = updater.link_rtt().unwrap();
+    assert!(
+        link_rtt >= Duration::from_millis(50) && link_rtt < Duration::from_millis(55),
+        "{:?}",
+        link_rtt
+    );
+    assert_eq!(report.link_rtt, Some(link_rtt));
+    // The 6s chunk fit within the scaled 10s timeout
+    assert_eq!(report.chunk_retries, 0);
+    assert!(report.resends.is_empty());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_send_timeout() {
+    // Nobody reads the device end, so writes stall once the pipe is full
+    let (io, _device) = tokio::io::duplex(8);
+    let mut updater = Updater::new(io, SerialCodec::new())
+        .send_timeout(Duration::from_millis(50))
+        .chunk_retry(1);
+
+    let start = tokio::time::Instant::now();
+    assert!(matches!(
+        updater.send_chunk(0, &[0xAA; 64]).await,
+        Err(Error::Timeout)
+    ));
+    assert_eq!(start.elapsed(), Duration::from_millis(50));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_deadline() {
+    let mut inner = bootloader(0x1234, 16);
+    let handler = move |received: &Received| {
+        let mut replies = vec![Reply::Delay(Duration::from_millis(250))];
+        replies.extend(inner(received));
+        replies
+    };
+    let (io, device) = mock_device(handler);
+    let mut updater = Updater::new(io, SerialCodec::new()).request_timeout(Duration::from_secs(1));
+
+    let start = tokio::time::Instant::now();
+    updater.set_deadline(Some(start + Duration::from_millis(300)));
+    assert_eq!(updater.device_id().await.unwrap(), 0x1234);
+
+    // Cut short at the deadline rather than running out the request timeout and resending
+    assert!(matches!(
+        updater.device_id().await,
+        Err(Error::DeadlineExceeded)
+    ));
+    assert_eq!(start.elapsed(), Duration::from_millis(300));
+
+    // Nothing more is sent once it has passed
+    assert!(matches!(
+        updater.serial_number().await,
+        Err(Error::DeadlineExceeded)
+    ));
+    assert_eq!(start.elapsed(), Duration::from_millis(300));
+
+    updater.set_deadline(None);
+    tokio::time::sleep(Duration::from_millis(250)).await;
+    updater
+        .drain_input(Duration::from_millis(10))
+        .await
+        .unwrap();
+    assert_eq!(updater.device_id().await.unwrap(), 0x1234);
+    drop(updater);
+
+    let received = device.await.unwrap();
+    assert!(!received.iter().any(|received| {
+        matches!(received, Received::Frame(frame) if frame.command == GetSerialNumberRequest::COMMAND)
+    }));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_deadline_bounds_delays() {
+    // Never quiet for long enough to finish draining
+    let (io, mut device) = tokio::io::duplex(4096);
+    let chatter = tokio::spawn(async move {
+        while device.write_all(&[0x00]).await.is_ok() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    });
+    let mut updater = Updater::new(io, SerialCodec::new());
+    let start = tokio::time::Instant::now();
+    updater.set_deadline(Some(start + Duration::from_millis(100)));
+    assert!(matches!(
+        updater.drain_input(Duration::from_millis(50)).await,
+        Err(Error::DeadlineExceeded)
+    ));
+    assert_eq!(start.elapsed(), Duration::from_millis(100));
+    drop(updater);
+    chatter.await.unwrap();
+
+    // Cut short while waiting to retry a busy StartUpload
+    let (io, _device) = mock_device(nack_start_upload(
+        bootloader(0x1234, 16),
+        StartUploadRequest::BUSY,
+        1,
+    ));
+    let mut updater = Updater::new(io, SerialCodec::new())
+        .busy_retry(1)
+        .busy_retry_delay(Duration::from_secs(10));
+    let start = tokio::time::Instant::now();
+    updater.set_deadline(Some(start + Duration::from_secs(1)));
+    let result = updater
+        .upload_firmware::<AtzResetter, _>(&firmware(&[0x1234], &[&[0xAA; 16]]), |_, _, _, _| {})
+        .await;
+    assert!(matches!(result, Err(Error::DeadlineExceeded)));
+    assert_eq!(start.elapsed(), Duration::from_secs(1));
+}
+
+#[tokio::test]
+async fn test_recv_until() {
+    let handler = |received: &Received| match received {
+        Received::Frame(frame) if frame.command == GetDeviceNameRequest::COMMAND => vec![
+            ack(frame.command, vec![0x00, b'S', b'T']),
+            ack(frame.command, vec![0x01, b'N']),
+            ack(frame.command, vec![0xFF]),
+            ack(frame.command, vec![0x00, b'X']),
+        ],
+        _ => vec![],
+    };
+    let (io, _device) = mock_device(handler);
+    let mut updater = Updater::new(io, SerialCodec::new());
+
+    let mut request = bytes::BytesMut::new();
+    SerialCodec::new()
+        .encode(GetDeviceNameRequest.frame(), &mut request)
+        .unwrap();
+    updater.transport_mut().write_all(&request).await.unwrap();
+
+    let frames = updater
+        .recv_until(Duration::from_millis(100), |frame| frame.data[0] == 0xFF)
+        .await
+        .unwrap();
+    assert_eq!(
+        frames
+            .into_iter()
+            .map(|frame| frame.data)
+            .collect::<Vec<_>>(),
+        vec![vec![0x00, b'S', b'T'], vec![0x01, b'N'], vec![0xFF]]
+    );
+
+    // The frame after the terminator is left for the next read, but no terminator follows it
+    let frames = updater
+        .recv_until(Duration::from_millis(50), |_| false)
+        .await;
+    assert!(matches!(frames, Err(Error::Timeout)));
+}
+
+#[tokio::test]
+async fn test_transport_mut() {
+    let mut inner = bootloader(0x1234, 16);
+    let handler = move |received: &Received| match received {
+        Received::Raw(bytes) if bytes == b"ATI\r" => vec![Reply::Raw(b"STN2120\r>".to_vec())],
+        _ => inner(received),
+    };
+    let (io, device) = mock_device(handler);
+
+    let mut updater = Updater::new(io, SerialCodec::new());
+    assert_eq!(updater.device_id().await.unwrap(), 0x1234);
+
+    updater.transport_mut().write_all(b"ATI\r").await.unwrap();
+    let mut banner = [0u8; 9];
+    updater
+        .transport_mut()
+        .read_exact(&mut banner)
+        .await
+        .unwrap();
+    assert_eq!(&banner, b"STN2120\r>");
+
+    assert_eq!(updater.device_id().await.unwrap(), 0x1234);
+    drop(updater);
+
+    let received = device.await.unwrap();
+    assert_eq!(received.len(), 3);
+    assert!(matches!(&received[1], Received::Raw(bytes) if bytes == b"ATI\r"));
+}
+
+#[test]
+fn test_check_compatibility() {
+    let mut firmware = firmware(&[0x1234], &[&[0xAA; 16], &[0xBB; 16], &[0xCC; 16]]);
+    firmware.descriptors[1].image_type = 0x01;
+    firmware.descriptors[2].image_type = 0x10;
+    firmware
+        .check_compatibility(&Capabilities::default())
+        .unwrap();
+
+    let capabilities = Capabilities {
+        image_types: BTreeSet::from([0x00, 0x10]),
+        ..Capabilities::default()
+    };
+    assert!(matches!(
+        firmware.check_compatibility(&capabilities),
+        Err(Error::UnsupportedImageType {
+            image_idx: 1,
+            image_type: 0x01
+        })
+    ));
+
+    // Validation images are programmed too
+    let capabilities = Capabilities {
+        upload_modes: BTreeSet::from([UploadMode::Program]),
+        ..Capabilities::default()
+    };
+    firmware.check_compatibility(&capabilities).unwrap();
+
+    let capabilities = Capabilities {
+        upload_modes: BTreeSet::from([UploadMode::Verify]),
+        ..Capabilities::default()
+    };
+    assert!(matches!(
+        firmware.check_compatibility(&capabilities),
+        Err(Error::UnsupportedMode {
+            image_idx: 0,
+            mode: UploadMode::Program
+        })
+    ));
+
+    // Unknown to this crate
+    firmware.descriptors[0].image_type = 0x02;
+    assert!(matches!(
+        firmware.check_compatibility(&Capabilities::default()),
+        Err(Error::UnsupportedImageType {
+            image_idx: 0,
+            image_type: 0x02
+        })
+    ));
+}
+
+/// Answers `GetVersionRequest` with `version` instead of `BOOTLOADER_VERSION`.
+fn versioned(
+    mut inner: impl FnMut(&Received) -> Vec<Reply> + Send,
+    (major, minor): (u8, u8),
+) -> impl FnMut(&Received) -> Vec<Reply> + Send {
+    move |received| match received {
+        Received::Frame(frame) if frame.command == GetVersionRequest::COMMAND => {
+            vec![ack(GetVersionRequest::COMMAND, vec![major, minor])]
+        }
+        _ => inner(received),
+    }
+}
+
+/// Answers `GetChecksumRequest` with the CRC-32 of the chunks received since the last
+/// `StartUploadRequest`, flipped once flash "fails" to keep `stuck` bytes.
+#[cfg(feature = "experimental")]
+fn checksumming(
+    mut inner: impl FnMut(&Received) -> Vec<Reply> + Send,
+    stuck: usize,
+) -> impl FnMut(&Received) -> Vec<Reply> + Send {
+    let mut flash = vec![];
+    move |received| match received {
+        Received::Frame(frame) if frame.command == GetChecksumRequest::COMMAND => {
+            let offset = u32::from_be_bytes(frame.data[..4].try_into().unwrap()) as usize;
+            let size = u32::from_be_bytes(frame.data[4..8].try_into().unwrap()) as usize;
+            let mut checksum = CRC32.checksum(&flash[offset..offset + size]);
+            if size > stuck {
+                checksum = !checksum;
+            }
+            vec![ack(
+                GetChecksumRequest::COMMAND,
+                checksum.to_be_bytes().to_vec(),
+            )]
+        }
+        Received::Frame(frame) => {
+            match frame.command {
+                StartUploadRequest::COMMAND => flash.clear(),
+                SendChunkRequest::COMMAND => flash.extend_from_slice(&frame.data[2..]),
+                _ => {}
+            }
+            inner(received)
+        }
+        Received::Raw(_) => inner(received),
+    }
+}
+
+#[cfg(feature = "experimental")]
+async fn upload_verified(verify: bool, stuck: usize) -> (Result<UploadReport, Error>, Vec<u8>) {
+    let (io, device) = mock_device(checksumming(bootloader(0x1234, 16), stuck));
+    let firmware = firmware(&[0x1234], &[&[0xAA; 16], &[0xBB; 32]]);
+    assert_eq!(firmware.crc32(1), CRC32.checksum(&[0xBB; 32]));
+
+    let mut updater = Updater::new(io, SerialCodec::new()).verify(verify);
+    let result = updater
+        .upload_firmware::<AtzResetter, _>(&firmware, |_, _, _, _| {})
+        .await;
+    drop(updater);
+
+    let commands = device
+        .await
+        .unwrap()
+        .into_iter()
+        .filter_map(|received| match received {
+            Received::Frame(frame) => Some(frame.command),
+            Received::Raw(_) => None,
+        })
+        .filter(|&command| {
+            command == StartUploadRequest::COMMAND || command == GetChecksumRequest::COMMAND
+        })
+        .collect();
+    (result, commands)
+}
+
+#[cfg(feature = "experimental")]
+#[tokio::test]
+async fn test_verify_device_checksum() {
+    let (result, commands) = upload_verified(false, 16).await;
+    result.unwrap();
+    assert_eq!(commands, vec![0x30, 0x30]);
+
+    let (result, commands) = upload_verified(true, usize::MAX).await;
+    result.unwrap();
+    assert_eq!(commands, vec![0x30, 0x0D, 0x30, 0x0D]);
+
+    let (result, commands) = upload_verified(true, 16).await;
+    match result {
+        Err(Error::VerificationFailed {
+            image_idx,
+            expected,
+            actual,
+        }) => {
+            assert_eq!(image_idx, 1);
+            assert_eq!(expected, CRC32.checksum(&[0xBB; 32]));
+            assert_eq!(actual, !expected);
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+    assert_eq!(commands, vec![0x30, 0x0D, 0x30, 0x0D]);
+}
+
+#[cfg(feature = "experimental")]
+async fn upload_flaky_verify(verify_retries: usize) -> (Result<UploadReport, Error>, Vec<u8>) {
+    // Only the first checksum comes back wrong, as if that write didn't stick. The first ack of
+    // the first chunk is lost too.
+    let mut inner = checksumming(lossy_chunks(bootloader(0x1234, 16), vec![1]), usize::MAX);
+    let mut checksums = 0;
+    let handler = move |received: &Received| {
+        let mut replies = inner(received);
+        if let Received::Frame(frame) = received {
+            if frame.command == GetChecksumRequest::COMMAND {
+                checksums += 1;
+                if let (1, Some(Reply::Frame(reply))) = (checksums, replies.first_mut()) {
+                    reply.data[0] ^= 0xFF;
+                }
+            }
+        }
+        replies
+    };
+    let (io, device) = mock_device(handler);
+    let firmware = firmware(&[0x1234], &[&[0xAA; 16], &[0xBB; 32]]);
+
+    let mut updater = Updater::new(io, SerialCodec::new())
+        .resend_retry(1)
+        .chunk_timeout(Duration::from_millis(30))
+        .verify(true)
+        .verify_retries(verify_retries);
+    let result = updater
+        .upload_firmware::<AtzResetter, _>(&firmware, |_, _, _, _| {})
+        .await;
+    drop(updater);
+    let commands = device
+        .await
+        .unwrap()
+        .into_iter()
+        .filter_map(|received| match received {
+            Received::Frame(frame)
+                if frame.command == StartUploadRequest::COMMAND
+                    || frame.command == GetChecksumRequest::COMMAND =>
+            {
+                Some(frame.command)
+            }
+            _ => None,
+        })
+        .collect();
+    (result, commands)
+}
+
+#[cfg(feature = "experimental")]
+#[tokio::test]
+async fn test_verify_retries() {
+    let (result, commands) = upload_flaky_verify(0).await;
+    assert!(matches!(
+        result,
+        Err(Error::VerificationFailed { image_idx: 0, .. })
+    ));
+    assert_eq!(
+        commands,
+        vec![StartUploadRequest::COMMAND, GetChecksumRequest::COMMAND]
+    );
+
+    // Started over from the first image
+    let (result, commands) = upload_flaky_verify(2).await;
+    let report = result.unwrap();
+    assert_eq!(report.verify_retries, 1);
+    // Including the resend of the first attempt
+    assert_eq!(
+        report.resends,
+        BTreeMap::from([(SendChunkRequest::COMMAND, 1)])
+    );
+    let attempt = [StartUploadRequest::COMMAND, GetChecksumRequest::COMMAND];
+    assert_eq!(commands, [attempt, attempt, attempt].concat());
+}
+
+#[tokio::test]
+async fn test_upload_incompatible_firmware() {
+    let capabilities = Capabilities {
+        upload_modes: BTreeSet::from([UploadMode::Program, UploadMode::Verify]),
+        ..Capabilities::default()
+    };
+
+    let (io, device) = mock_device(bootloader(0x1234, 16));
+    let mut updater = Updater::new(io, SerialCodec::new())
+        .capabilities(capabilities.clone())
+        .program_mode(UploadMode::ProgramVerify);
+    let result = updater
+        .upload_firmware::<AtzResetter, _>(&firmware(&[0x1234], &[&[0xAA; 16]]), |_, _, _, _| {})
+        .await;
+    assert!(matches!(
+        result,
+        Err(Error::UnsupportedMode {
+            image_idx: 0,
+            mode: UploadMode::ProgramVerify
+        })
+    ));
+    drop(updater);
+
+    let received = device.await.unwrap();
+    assert!(start_upload_sizes(&received).is_empty());
+    assert!(received.iter().any(|received| matches!(
+        received,
+        Received::Frame(frame) if frame.command == ResetRequest::COMMAND
+    )));
+
+    let (io, _device) = mock_device(ignoring_reset(bootloader(0x1234, 16)));
+    let mut updater = Updater::new(io, SerialCodec::new())
+        .request_timeout(Duration::from_millis(50))
+        .capabilities(capabilities)
+        .program_mode(UploadMode::ProgramVerify);
+    let result = updater
+        .upload_firmware::<AtzResetter, _>(&firmware(&[0x1234], &[&[0xAA; 16]]), |_, _, _, _| {})
+        .await;
+    assert!(matches!(result, Err(Error::UnsupportedMode { .. })));
+}
+
+#[cfg(feature = "serial")]
+mod serial {
+    use stn_updater::transport::serial::{
+        usb_ports, SerialPortInfo, SerialPortType, UsbPortInfo, STN_USB_IDS,
+    };
+
+    fn usb_port(port_name: &str, vid: u16, pid: u16) -> SerialPortInfo {
+        SerialPortInfo {
+            port_name: port_name.to_string(),
+            port_type: SerialPortType::UsbPort(UsbPortInfo {
+                vid,
+                pid,
+                serial_number: None,
+                manufacturer: None,
+                product: None,
+            }),
+        }
+    }
+
+    #[test]
+    fn test_usb_ports() {
+        let ports = vec![
+            SerialPortInfo {
+                port_name: "/dev/ttyS0".to_string(),
+                port_type: SerialPortType::Unknown,
+            },
+            usb_port("/dev/ttyUSB0", 0x0403, 0x6001),
+            usb_port("/dev/ttyUSB1", 0x0403, 0x6015),
+            SerialPortInfo {
+                port_name: "/dev/rfcomm0".to_string(),
+                port_type: SerialPortType::BluetoothPort,
+            },
+            usb_port("/dev/ttyACM0", 0x1234, 0x5678),
+        ];
+
+        let names = |ports: Vec<SerialPortInfo>| {
+            ports
+                .into_iter()
+                .map(|port| port.port_name)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(usb_ports(ports.clone(), &STN_USB_IDS)),
+            vec!["/dev/ttyUSB1"]
+        );
+        assert_eq!(
+            names(usb_ports(
+                ports.clone(),
+                &[(0x1234, 0x5678), (0x0403, 0x6001)]
+            )),
+            vec!["/dev/ttyUSB0", "/dev/ttyACM0"]
+        );
+        assert!(usb_ports(ports, &[]).is_empty());
+    }
+}
+
+#[cfg(feature = "ble")]
+mod ble {
+    use super::*;
+
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+
+    use btleplug::api::{
+        BDAddr, Central, CentralEvent, CentralState, CharPropFlags, Characteristic, Descriptor,
+        Peripheral, PeripheralProperties, ScanFilter, Service, ValueNotification, WriteType,
+    };
+    use btleplug::platform::PeripheralId;
+    use futures::Stream;
+    use stn_updater::transport::ble::{
+        scan, scan_for, DiscoveredDevice, PeripheralStream, ScanOptions, UART_RX_CHAR_UUID,
+        UART_SERVICE_UUID, UART_TX_CHAR_UUID,
+    };
+
+    #[derive(Debug, Clone, Default)]
+    struct MockPeripheral {
+        name: Option<String>,
+        rssi: Option<i16>,
+        notifications: Vec<Vec<u8>>,
+        /// Characteristic each of `notifications` comes from, `UART_RX_CHAR_UUID` past the end.
+        notification_uuids: Vec<uuid::Uuid>,
+        /// Notify characteristics on top of `UART_RX_CHAR_UUID`.
+        extra_notify_chars: Vec<uuid::Uuid>,
+        hang_writes: bool,
+        /// End the notification stream after `notifications`, as a disconnect does.
+        disconnect: bool,
+        writes: Arc<Mutex<Vec<Vec<u8>>>>,
+        subscriptions: Arc<Mutex<Vec<uuid::Uuid>>>,
+    }
+
+    fn characteristic(uuid: uuid::Uuid, properties: CharPropFlags) -> Characteristic {
+        Characteristic {
+            uuid,
+            service_uuid: UART_SERVICE_UUID,
+            properties,
+            descriptors: BTreeSet::new(),
+        }
+    }
+
+    #[async_trait]
+    impl Peripheral for MockPeripheral {
+        fn id(&self) -> PeripheralId {
+            panic!("not used by these tests")
+        }
+
+        fn address(&self) -> BDAddr {
+            BDAddr::default()
+        }
+
+        async fn properties(&self) -> btleplug::Result<Option<PeripheralProperties>> {
+            Ok(self.name.as_ref().map(|name| PeripheralProperties {
+                local_name: Some(name.clone()),
+                rssi: self.rssi,
+                services: vec![UART_SERVICE_UUID],
+                ..Default::default()
+            }))
+        }
+
+        fn services(&self) -> BTreeSet<Service> {
+            let mut characteristics = BTreeSet::from([
+                characteristic(UART_RX_CHAR_UUID, CharPropFlags::NOTIFY),
+                characteristic(UART_TX_CHAR_UUID, CharPropFlags::WRITE_WITHOUT_RESPONSE),
+            ]);
+            for &uuid in &self.extra_notify_chars {
+                characteristics.insert(characteristic(uuid, CharPropFlags::NOTIFY));
+            }
+            BTreeSet::from([Service {
+                uuid: UART_SERVICE_UUID,
+                primary: true,
+                characteristics,
+            }])
+        }
+
+        async fn is_connected(&self) -> btleplug::Result<bool> {
+            Ok(true)
+        }
+
+        async fn connect(&self) -> btleplug::Result<()> {
+            Ok(())
+        }
+
+        async fn disconnect(&self) -> btleplug::Result<()> {
+            Ok(())
+        }
+
+        async fn discover_services(&self) -> btleplug::Result<()> {
+            Ok(())
+        }
+
+        async fn write(
+            &self,
+            characteristic: &Characteristic,
+            data: &[u8],
+            _write_type: WriteType,
+        ) -> btleplug::Result<()> {
+            if self.hang_writes {
+                // A wedged BLE stack, the write never completes
+                return futures::future::pending().await;
+            }
+            assert_eq!(characteristic.uuid, UART_TX_CHAR_UUID);
+            self.writes.lock().unwrap().push(data.to_vec());
+            Ok(())
+        }
+
+        async fn read(&self, _characteristic: &Characteristic) -> btleplug::Result<Vec<u8>> {
+            Err(btleplug::Error::NotSupported("read".to_string()))
+        }
+
+        async fn subscribe(&self, characteristic: &Characteristic) -> btleplug::Result<()> {
+            self.subscriptions.lock().unwrap().push(characteristic.uuid);
+            Ok(())
+        }
+
+        async fn unsubscribe(&self, _characteristic: &Characteristic) -> btleplug::Result<()> {
+            Ok(())
+        }
+
+        async fn notifications(
+            &self,
+        ) -> btleplug::Result<Pin<Box<dyn Stream<Item = ValueNotification> + Send>>> {
+            let uuids = self
+                .notification_uuids
+                .clone()
+                .into_iter()
+                .chain(std::iter::repeat(UART_RX_CHAR_UUID));
+            let notifications = self
+                .notifications
+                .clone()
+                .into_iter()
+                .zip(uuids)
+                .map(|(value, uuid)| ValueNotification { uuid, value });
+            let notifications = futures::stream::iter(notifications);
+            if self.disconnect {
+                Ok(Box::pin(notifications))
+            } else {
+                Ok(Box::pin(notifications.chain(futures::stream::pending())))
+            }
+        }
+
+        async fn write_descriptor(
+            &self,
+            _descriptor: &Descriptor,
+            _data: &[u8],
+        ) -> btleplug::Result<()> {
+            Err(btleplug::Error::NotSupported(
+                "write_descriptor".to_string(),
+            ))
+        }
+
+        async fn read_descriptor(&self, _descriptor: &Descriptor) -> btleplug::Result<Vec<u8>> {
+            Err(btleplug::Error::NotSupported("read_descriptor".to_string()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_write_timeout() {
+        let periph = MockPeripheral {
+            hang_writes: true,
+            ..Default::default()
+        };
+        let stream = PeripheralStream::new(
+            periph,
+            UART_SERVICE_UUID,
+            UART_RX_CHAR_UUID,
+            UART_TX_CHAR_UUID,
+        )
+        .await
+        .unwrap()
+        .write_timeout(Duration::from_millis(50));
+
+        let mut updater = Updater::new(stream, SerialCodec::new());
+        match updater.device_id().await {
+            Err(Error::IOError(err)) => assert_eq!(err.kind(), std::io::ErrorKind::TimedOut),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_write_owns_buffer() {
+        let periph = MockPeripheral::default();
+        let mut stream = PeripheralStream::new(
+            periph.clone(),
+            UART_SERVICE_UUID,
+            UART_RX_CHAR_UUID,
+            UART_TX_CHAR_UUID,
+        )
+        .await
+        .unwrap();
+
+        for data in [vec![0x55, 0x55, 0x03], vec![0x00, 0x00, 0x59, 0x50, 0x04]] {
+            stream.write_all(&data).await.unwrap();
+            drop(data);
+        }
+
+        assert_eq!(
+            *periph.writes.lock().unwrap(),
+            vec![vec![0x55, 0x55, 0x03], vec![0x00, 0x00, 0x59, 0x50, 0x04]]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_coalesces_notifications() {
+        let periph = MockPeripheral {
+            notifications: vec![vec![0x55, 0x55], vec![0x47, 0x02, 0x12], vec![0x34, 0x00]],
+            ..Default::default()
+        };
+        let mut stream = PeripheralStream::new(
+            periph,
+            UART_SERVICE_UUID,
+            UART_RX_CHAR_UUID,
+            UART_TX_CHAR_UUID,
+        )
+        .await
+        .unwrap();
+
+        let mut buf = [0u8; 1024];
+        let count = stream.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..count], &[0x55, 0x55, 0x47, 0x02, 0x12, 0x34, 0x00]);
+    }
+
+    #[tokio::test]
+    async fn test_multiple_rx_chars() {
+        let status = uuid::Uuid::from_u128(0x0000FFF3_0000_1000_8000_00805F9B34FB);
+        let unrelated = uuid::Uuid::from_u128(0x0000FFF4_0000_1000_8000_00805F9B34FB);
+        let periph = MockPeripheral {
+            notifications: vec![
+                vec![0x55, 0x55],
+                vec![0x47, 0x02],
+                vec![0xFF, 0xFF],
+                vec![0x12, 0x34, 0x00],
+            ],
+            notification_uuids: vec![UART_RX_CHAR_UUID, status, unrelated],
+            extra_notify_chars: vec![status, unrelated],
+            ..Default::default()
+        };
+        let mut stream = PeripheralStream::with_rx_chars(
+            periph.clone(),
+            UART_SERVICE_UUID,
+            &[status, UART_RX_CHAR_UUID],
+            UART_TX_CHAR_UUID,
+        )
+        .await
+        .unwrap();
+
+        let subscribed = stream
+            .rx_characteristics()
+            .iter()
+            .map(|characteristic| characteristic.uuid)
+            .collect::<Vec<_>>();
+        assert_eq!(subscribed, vec![status, UART_RX_CHAR_UUID]);
+        assert_eq!(stream.rx_characteristic().uuid, status);
+        let mut subscriptions = periph.subscriptions.lock().unwrap().clone();
+        subscriptions.sort();
+        assert_eq!(subscriptions, vec![UART_RX_CHAR_UUID, status]);
+
+        // Merged in arrival order, without the characteristic that wasn't asked for
+        let mut buf = [0u8; 1024];
+        let count = stream.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..count], &[0x55, 0x55, 0x47, 0x02, 0x12, 0x34, 0x00]);
+
+        let missing = uuid::Uuid::from_u128(0x0000FFF5_0000_1000_8000_00805F9B34FB);
+        assert!(PeripheralStream::with_rx_chars(
+            periph,
+            UART_SERVICE_UUID,
+            &[UART_RX_CHAR_UUID, missing],
+            UART_TX_CHAR_UUID,
+        )
+        .await
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_is_eof() {
+        let periph = MockPeripheral {
+            disconnect: true,
+            ..Default::default()
+        };
+        let stream = PeripheralStream::new(
+            periph,
+            UART_SERVICE_UUID,
+            UART_RX_CHAR_UUID,
+            UART_TX_CHAR_UUID,
+        )
+        .await
+        .unwrap();
+
+        let mut updater =
+            Updater::new(stream, SerialCodec::new()).request_timeout(Duration::from_secs(60));
+        let started = tokio::time::Instant::now();
+        assert!(matches!(
+            updater.device_id().await,
+            Err(Error::Disconnected)
+        ));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_read_small_buffer() {
+        let periph = MockPeripheral {
+            notifications: vec![vec![0x55, 0x55, 0x47], vec![0x02, 0x12]],
+            ..Default::default()
+        };
+        let mut stream = PeripheralStream::new(
+            periph,
+            UART_SERVICE_UUID,
+            UART_RX_CHAR_UUID,
+            UART_TX_CHAR_UUID,
+        )
+        .await
+        .unwrap();
+
+        let mut buf = [0u8; 2];
+        let mut received = vec![];
+        while received.len() < 5 {
+            let count = stream.read(&mut buf).await.unwrap();
+            assert!(count <= 2);
+            received.extend_from_slice(&buf[..count]);
+        }
+        assert_eq!(received, vec![0x55, 0x55, 0x47, 0x02, 0x12]);
+    }
+
+    #[derive(Debug, Clone, Default)]
+    struct MockAdapter {
+        scanning: Arc<Mutex<bool>>,
+        peripherals: Arc<Mutex<Vec<MockPeripheral>>>,
+    }
+
+    impl MockAdapter {
+        fn advertise(&self, name: &str) {
+            self.advertise_with_rssi(name, None);
+        }
+
+        fn advertise_with_rssi(&self, name: &str, rssi: Option<i16>) {
+            self.peripherals.lock().unwrap().push(MockPeripheral {
+                name: Some(name.to_string()),
+                rssi,
+                ..Default::default()
+            });
+        }
+    }
+
+    #[async_trait]
+    impl Central for MockAdapter {
+        type Peripheral = MockPeripheral;
+
+        async fn events(
+            &self,
+        ) -> btleplug::Result<Pin<Box<dyn Stream<Item = CentralEvent> + Send>>> {
+            Ok(Box::pin(futures::stream::pending()))
+        }
+
+        async fn start_scan(&self, _filter: ScanFilter) -> btleplug::Result<()> {
+            *self.scanning.lock().unwrap() = true;
+            Ok(())
+        }
+
+        async fn stop_scan(&self) -> btleplug::Result<()> {
+            *self.scanning.lock().unwrap() = false;
+            Ok(())
+        }
+
+        async fn peripherals(&self) -> btleplug::Result<Vec<MockPeripheral>> {
+            Ok(self.peripherals.lock().unwrap().clone())
+        }
+
+        async fn peripheral(&self, _id: &PeripheralId) -> btleplug::Result<MockPeripheral> {
+            Err(btleplug::Error::DeviceNotFound)
+        }
+
+        async fn add_peripheral(&self, _id: &PeripheralId) -> btleplug::Result<MockPeripheral> {
+            Err(btleplug::Error::DeviceNotFound)
+        }
+
+        async fn adapter_info(&self) -> btleplug::Result<String> {
+            Ok("mock".to_string())
+        }
+
+        async fn adapter_state(&self) -> btleplug::Result<CentralState> {
+            Ok(CentralState::PoweredOn)
+        }
+    }
+
+    fn named(name: &'static str) -> impl Fn(&PeripheralProperties) -> bool {
+        move |properties| properties.local_name.as_deref() == Some(name)
+    }
+
+    #[tokio::test]
+    async fn test_scan_for_returns_early() {
+        let adapter = MockAdapter::default();
+        adapter.advertise("OBDLink CX");
+
+        let advertiser = adapter.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            advertiser.advertise("OBDLink MX+");
+        });
+
+        let start = std::time::Instant::now();
+        let found = scan_for(&adapter, named("OBDLink MX+"), Duration::from_secs(10))
+            .await
+            .unwrap();
+        assert_eq!(
+            found.and_then(|peripheral| peripheral.name),
+            Some("OBDLink MX+".to_string())
+        );
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(!*adapter.scanning.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_scan_for_max_duration() {
+        let adapter = MockAdapter::default();
+        adapter.advertise("OBDLink CX");
+
+        let start = std::time::Instant::now();
+        let found = scan_for(&adapter, named("OBDLink MX+"), Duration::from_millis(250))
+            .await
+            .unwrap();
+        assert!(found.is_none());
+        assert!(start.elapsed() >= Duration::from_millis(250));
+        assert!(!*adapter.scanning.lock().unwrap());
+    }
+
+    fn names(devices: Vec<DiscoveredDevice<MockPeripheral>>) -> Vec<String> {
+        devices
+            .into_iter()
+            .map(|device| device.name.unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_scan_rssi() {
+        let adapter = MockAdapter::default();
+        for (name, rssi) in [
+            ("far", Some(-80)),
+            ("bench", Some(-40)),
+            ("unknown", None),
+            ("next door", Some(-95)),
+            ("desk", Some(-60)),
+        ] {
+            adapter.advertise_with_rssi(name, rssi);
+        }
+
+        let devices = scan(&adapter, ScanOptions::new(Duration::from_millis(10)))
+            .await
+            .unwrap();
+        assert_eq!(
+            names(devices),
+            vec!["far", "bench", "unknown", "next door", "desk"]
+        );
+
+        let devices = scan(
+            &adapter,
+            ScanOptions::new(Duration::from_millis(10))
+                .services(vec![UART_SERVICE_UUID])
+                .sort_by_rssi(true),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            names(devices),
+            vec!["bench", "desk", "far", "next door", "unknown"]
+        );
+
+        let devices = scan(
+            &adapter,
+            ScanOptions::new(Duration::from_millis(10))
+                .min_rssi(-90)
+                .sort_by_rssi(true),
+        )
+        .await
+        .unwrap();
+        assert_eq!(names(devices), vec!["bench", "desk", "far"]);
+        assert!(!*adapter.scanning.lock().unwrap());
+    }
+}
+
+#[cfg(feature = "tcp")]
+mod tcp {
+    use super::*;
+    use stn_updater::transport::tcp::{self, TcpResetter};
+
+    #[tokio::test]
+    async fn test_tcp_connect() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let device = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            mock_device_on(stream, application_mode(0x1234))
+                .await
+                .unwrap()
+        });
+
+        let stream = tcp::connect(addr).await.unwrap();
+        assert!(stream.nodelay().unwrap());
+        let mut updater = Updater::new(stream, SerialCodec::new());
+        updater.connect::<TcpResetter>().await.unwrap();
+        assert_eq!(updater.device_id().await.unwrap(), 0x1234);
+        drop(updater);
+
+        let received = device.await.unwrap();
+        assert!(received
+            .iter()
+            .any(|received| matches!(received, Received::Raw(raw) if raw == b"ATZ\r")));
+    }
+}
+
+/// Serves `ReadFlashRequest`s from what was uploaded, with the byte at `corrupt` flipped.
+#[cfg(feature = "experimental")]
+fn reading_back(
+    mut inner: impl FnMut(&Received) -> Vec<Reply> + Send,
+    corrupt: Option<usize>,
+) -> impl FnMut(&Received) -> Vec<Reply> + Send {
+    let mut flash = vec![];
+    move |received| match received {
+        Received::Frame(frame) if frame.command == ReadFlashRequest::COMMAND => {
+            let offset = u32::from_be_bytes(frame.data[..4].try_into().unwrap()) as usize;
+            let size = frame.data[4] as usize;
+            let mut data = flash[offset..offset + size].to_vec();
+            if let Some(corrupt) = corrupt.filter(|idx| (offset..offset + size).contains(idx)) {
+                data[corrupt - offset] ^= 0xFF;
+            }
+            vec![ack(ReadFlashRequest::COMMAND, data)]
+        }
+        Received::Frame(frame) => {
+            match frame.command {
+                StartUploadRequest::COMMAND => flash.clear(),
+                SendChunkRequest::COMMAND => flash.extend_from_slice(&frame.data[2..]),
+                _ => {}
+            }
+            inner(received)
+        }
+        Received::Raw(_) => inner(received),
+    }
+}
+
+#[cfg(feature = "experimental")]
+async fn upload_read_back(corrupt: Option<usize>) -> (Result<UploadReport, Error>, Vec<usize>) {
+    let (io, device) = mock_device(reading_back(bootloader(0x1234, 16), corrupt));
+    let data = (0..100).collect::<Vec<u8>>();
+    let mut updater = Updater::new(io, SerialCodec::new())
+        .verify(true)
+        .verify_readback(true)
+        .verify_chunk_size(32);
+    let result = updater
+        .upload_firmware::<AtzResetter, _>(&firmware(&[0x1234], &[&data]), |_, _, _, _| {})
+        .await;
+    drop(updater);
+
+    let read_sizes = device
+        .await
+        .unwrap()
+        .into_iter()
+        .filter_map(|received| match received {
+            Received::Frame(frame) if frame.command == ReadFlashRequest::COMMAND => {
+                Some(frame.data[4] as usize)
+            }
+            _ => None,
+        })
+        .collect();
+    (result, read_sizes)
+}
+
+#[cfg(feature = "experimental")]
+#[tokio::test]
+async fn test_verify_readback() {
+    let (result, read_sizes) = upload_read_back(None).await;
+    result.unwrap();
+    assert_eq!(read_sizes, vec![32, 32, 32, 4]);
+
+    // Stops at the chunk holding the bad byte
+    let (result, read_sizes) = upload_read_back(Some(70)).await;
+    assert!(matches!(
+        result,
+        Err(Error::ReadbackMismatch {
+            image_idx: 0,
+            offset: 70
+        })
+    ));
+    assert_eq!(read_sizes, vec![32, 32, 32]);
+}
+
+#[cfg(feature = "compression")]
+mod compression {
+    use super::*;
+    use flate2::write::ZlibEncoder;
+    use std::io::Write;
+
+    #[test]
+    fn test_compressed_container() {
+        let first = (0..200).map(|byte| (byte % 7) as u8).collect::<Vec<u8>>();
+        let second = vec![0xBB; 300];
+        let plain = write_container(&[0x1100, 0x1234], &[&first, &second]);
+
+        let mut encoder = ZlibEncoder::new(vec![], flate2::Compression::best());
+        encoder.write_all(&plain[8..]).unwrap();
+        let mut compressed = b"STNFWz05".to_vec();
+        compressed.extend_from_slice(&encoder.finish().unwrap());
+        assert!(compressed.len() < plain.len());
+
+        let firmware = FirmwareImage::from_bytes(compressed).unwrap();
+        assert_eq!(firmware.data, plain);
+        assert_eq!(firmware.device_ids, BTreeSet::from([0x1100, 0x1234]));
+        assert_eq!(firmware.descriptors.len(), 2);
+        let image = |idx: usize| {
+            let descriptor = &firmware.descriptors[idx];
+            let offset = descriptor.image_offset as usize;
+            &firmware.data[offset..offset + descriptor.image_size as usize]
+        };
+        assert_eq!(image(0), &first[..]);
+        assert_eq!(image(1), &second[..]);
+
+        // A corrupt stream is rejected rather than parsed
+        let mut corrupt = b"STNFWz05".to_vec();
+        corrupt.extend_from_slice(&[0xDE, 0xAD, 0xBE, 0xEF]);
+        assert!(matches!(
+            FirmwareImage::from_bytes(corrupt),
+            Err(Error::IOError(_))
+        ));
+    }
+}